@@ -0,0 +1,76 @@
+use crate::content_hash::HashAlgorithm;
+use crate::error::ForkliftResult;
+use crate::filesystem::{File, FileSystem, ProtocolContext, Stat};
+
+use nix::fcntl::OFlag;
+use nix::sys::stat::Mode;
+use serde_derive::*;
+use std::path::Path;
+
+/// amount of a file copied between saving checkpoints
+pub const CHECKPOINT_INTERVAL: u64 = 256 * 1024 * 1024;
+/// amount of a checkpoint file read at once
+const READ_SIZE: u64 = 4096;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// how far a copy into a temporary file got, saved next to the temporary file so that
+/// a rerun can continue the copy from there instead of from the start
+pub struct Checkpoint {
+    /// size of the source file
+    size: i64,
+    /// modification time of the source file, in seconds and nanoseconds
+    mtime: (i64, i64),
+    /// name of the algorithm of prefix_hash
+    algorithm: String,
+    /// number of bytes at the start of the temporary file that match the source
+    offset: u64,
+    /// checksum of those bytes
+    prefix_hash: Vec<u8>,
+}
+
+impl Checkpoint {
+    pub fn new(src: &Stat, algorithm: HashAlgorithm, offset: u64, prefix_hash: Vec<u8>) -> Self {
+        Checkpoint {
+            size: src.size(),
+            mtime: (src.mtime().tv_sec(), src.mtime().tv_nsec()),
+            algorithm: algorithm.name().to_string(),
+            offset,
+            prefix_hash,
+        }
+    }
+    /// read the checkpoint saved at path, or None if there is none (or it is unreadable,
+    /// since the copy is then started over)
+    pub fn load(path: &Path, context: &ProtocolContext) -> Option<Self> {
+        let file = context.open(path, OFlag::O_RDONLY, Mode::S_IRWXU).ok()?;
+        let mut contents = vec![];
+        loop {
+            let buf = file.read(READ_SIZE, contents.len() as u64).ok()?;
+            if buf.is_empty() {
+                break;
+            }
+            contents.extend_from_slice(&buf);
+        }
+        serde_json::from_slice(&contents).ok()
+    }
+    /// save the checkpoint to path, replacing the one there
+    pub fn save(&self, path: &Path, context: &ProtocolContext) -> ForkliftResult<()> {
+        let contents = serde_json::to_vec(self)?;
+        let file = context.create(path, OFlag::O_CREAT, Mode::S_IRUSR | Mode::S_IWUSR)?;
+        file.write(&contents, 0)?;
+        file.truncate(contents.len() as u64)
+    }
+    /// true if the checkpoint was saved while copying src, as it is now, with algorithm
+    pub fn is_for(&self, src: &Stat, algorithm: HashAlgorithm) -> bool {
+        self.size == src.size()
+            && self.mtime == (src.mtime().tv_sec(), src.mtime().tv_nsec())
+            && self.algorithm == algorithm.name()
+    }
+    /// number of bytes at the start of the temporary file that match the source
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+    /// checksum of the first offset bytes
+    pub fn prefix_hash(&self) -> &[u8] {
+        &self.prefix_hash
+    }
+}
@@ -0,0 +1,340 @@
+use crate::content_hash::HashAlgorithm;
+use crate::error::{ForkliftError, ForkliftResult};
+use crate::filesystem::*;
+use crate::filesystem_entry::Entry;
+use crate::filesystem_ops::*;
+use crate::postgres_logger::{send_mess, LogMessage};
+use crate::progress_message::ProgressMessage;
+use crate::tables::current_time;
+
+use crossbeam::channel::{self, Receiver, Sender};
+use crossbeam::select;
+use log::*;
+use nix::fcntl::OFlag;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// size of the ranges files are split into
+pub const RANGE_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// a range of a file to copy, which any RsyncWorker of the run can take
+pub struct RangeJob {
+    /// source file
+    src: PathBuf,
+    /// destination file
+    dest: PathBuf,
+    /// offset of the range
+    start: u64,
+    /// offset just past the end of the range
+    end: u64,
+    /// hash used for the checksums
+    algorithm: HashAlgorithm,
+    /// size of the whole file
+    size: u64,
+    /// bytes of the whole file copied so far
+    done: Arc<AtomicU64>,
+    /// set once copying a range failed, so the rest of the file is not copied
+    failed: Arc<AtomicBool>,
+    /// channel back to the worker copying the file
+    results: Sender<ForkliftResult<RangeCopied>>,
+}
+
+/// the result of copying a range
+pub struct RangeCopied {
+    /// offset of the range
+    start: u64,
+    /// checksum of the range of the source
+    src_check: Vec<u8>,
+    /// checksum of the range of the destination, after the copy
+    dest_check: Vec<u8>,
+    /// bytes that were already the same
+    matched: u64,
+    /// bytes written
+    transferred: u64,
+}
+
+#[derive(Clone)]
+/// queue of the ranges of files being copied in parallel, shared by the RsyncWorkers
+/// of a run
+pub struct RangeQueue {
+    send: Sender<RangeJob>,
+    recv: Receiver<RangeJob>,
+    /// size of the ranges files are split into
+    range_size: u64,
+}
+
+impl RangeQueue {
+    pub fn new(range_size: u64) -> Self {
+        let (send, recv) = channel::unbounded();
+        RangeQueue { send, recv, range_size }
+    }
+    /// the receiving end, to take ranges from
+    pub fn receiver(&self) -> &Receiver<RangeJob> {
+        &self.recv
+    }
+    /// stop queueing ranges, only taking them; once every worker has done so,
+    /// the receiver is disconnected when all the ranges are taken
+    pub fn into_receiver(self) -> Receiver<RangeJob> {
+        self.recv
+    }
+}
+
+/// copy the range of a job taken from the queue using this worker's contexts, and send
+/// the result back to the worker copying the file
+pub fn run_range(
+    job: RangeJob,
+    (src_context, dest_context): (&ProtocolContext, &ProtocolContext),
+    progress_send: &Sender<ProgressMessage>,
+) {
+    let result = if job.failed.load(Ordering::SeqCst) {
+        Err(ForkliftError::FSError(format!("Stopped copying {:?}", job.src)))
+    } else {
+        copy_range(&job, (src_context, dest_context), progress_send)
+    };
+    if result.is_err() {
+        job.failed.store(true, Ordering::SeqCst);
+    }
+    if job.results.send(result).is_err() {
+        debug!("Worker copying {:?} stopped before range at {}", job.src, job.start);
+    }
+}
+
+/// helper for run_range; like checksum_copy, but only from job.start to job.end
+fn copy_range(
+    job: &RangeJob,
+    (src_context, dest_context): (&ProtocolContext, &ProtocolContext),
+    progress_send: &Sender<ProgressMessage>,
+) -> ForkliftResult<RangeCopied> {
+    let (src_path, dest_path) = (job.src.as_path(), job.dest.as_path());
+    let src_err = format!("Could not open {:?} for reading", src_path);
+    let dest_err = format!("could not open {:?} for writing", dest_path);
+    let src_file = open_file(src_path, src_context, OFlag::O_RDONLY, &src_err)?;
+    let dest_file = open_file(dest_path, dest_context, OFlag::O_RDWR, &dest_err)?;
+    let (mut src_hasher, mut dest_hasher) = (job.algorithm.hasher(), job.algorithm.hasher());
+    let mut hasher = job.algorithm.hasher();
+    let (mut offset, mut matched, mut transferred) = (job.start, 0, 0);
+    while offset < job.end {
+        let mut src_buf = read_chunk(src_path, &src_file, offset)?;
+        src_buf.truncate((job.end - offset).min(src_buf.len() as u64) as usize);
+        if src_buf.is_empty() {
+            let err = format!("{:?} ended at {}, before {}", src_path, offset, job.end);
+            return Err(ForkliftError::FSError(err));
+        }
+        let mut dest_buf = read_chunk(dest_path, &dest_file, offset)?;
+        dest_buf.truncate(src_buf.len());
+        if hash(&src_buf, hasher.as_mut()) != hash(&dest_buf, hasher.as_mut()) {
+            let num_written = write_file(dest_path, &dest_file, &src_buf, offset)?;
+            if num_written == 0 {
+                let err = format!("Could not write to {:?} at offset {}", dest_path, offset);
+                return Err(ForkliftError::FSError(err));
+            }
+            transferred += num_written;
+            src_buf.truncate(num_written as usize);
+            dest_buf = read_chunk(dest_path, &dest_file, offset)?;
+            dest_buf.truncate(src_buf.len());
+        } else {
+            matched += src_buf.len() as u64;
+        }
+        src_hasher.update(&src_buf);
+        dest_hasher.update(&dest_buf);
+        offset += src_buf.len() as u64;
+        let done = job.done.fetch_add(src_buf.len() as u64, Ordering::SeqCst);
+        let progress = ProgressMessage::CheckSyncing {
+            description: src_path.to_string_lossy().to_string(),
+            size: job.size as usize,
+            done: (done + src_buf.len() as u64) as usize,
+        };
+        if progress_send.send(progress).is_err() {
+            return Err(ForkliftError::CrossbeamChannelError("Unable to send progress".to_string()));
+        }
+    }
+    Ok(RangeCopied {
+        start: job.start,
+        src_check: src_hasher.finish_reset(),
+        dest_check: dest_hasher.finish_reset(),
+        matched,
+        transferred,
+    })
+}
+
+/// name of the checksums of the lists of range checksums of files split into ranges of
+/// range_size, such as "sha256-ranges-1GiB"
+pub fn ranges_name(algorithm: HashAlgorithm, range_size: u64) -> String {
+    let size = match range_size {
+        s if s % (1 << 30) == 0 => format!("{}GiB", s >> 30),
+        s if s % (1 << 20) == 0 => format!("{}MiB", s >> 20),
+        s if s % (1 << 10) == 0 => format!("{}KiB", s >> 10),
+        s => format!("{}B", s),
+    };
+    format!("{}-ranges-{}", algorithm.name(), size)
+}
+
+/// copies the src file to dest like checksum_copy, but split into ranges that are queued
+/// in ranges, so that every worker of the run can copy some of them.
+/// The file checksums are the checksums of the list of range checksums, recorded under the
+/// ranges_name of the algorithm
+///
+/// @note   the worker calling this copies ranges too (of this file or others) until
+///         all the ranges of this file are done
+pub fn chunked_copy(
+    src: &Entry,
+    dest: &Entry,
+    (src_context, dest_context): (&ProtocolContext, &ProtocolContext),
+    (is_copy, algorithm): (bool, HashAlgorithm),
+    ranges: &RangeQueue,
+    progress_send: &Sender<ProgressMessage>,
+    logs_send: &Sender<LogMessage>,
+) -> ForkliftResult<SyncOutcome> {
+    let (src_path, dest_path) = (src.path(), dest.path());
+    let size = match src.metadata() {
+        Some(m) => m.size() as u64,
+        None => {
+            return Err(ForkliftError::FSError(format!("Source file {:?} should exist!", src_path)));
+        }
+    };
+    let dest_err = format!("could not open {:?} for writing", dest_path);
+    let dest_file = file_create(dest_path, dest_context, &dest_err)?;
+    // set the size first, so every range can be written on its own
+    let resized = dest_file.fstat()?.size() as u64 != size;
+    if resized {
+        dest_file.truncate(size)?;
+    }
+    let (results_send, results) = channel::unbounded();
+    let (done, failed) = (Arc::new(AtomicU64::new(0)), Arc::new(AtomicBool::new(false)));
+    let (mut num_ranges, mut start) = (0, 0);
+    while start < size {
+        let job = RangeJob {
+            src: src_path.to_path_buf(),
+            dest: dest_path.to_path_buf(),
+            start,
+            end: (start + ranges.range_size).min(size),
+            algorithm,
+            size,
+            done: done.clone(),
+            failed: failed.clone(),
+            results: results_send.clone(),
+        };
+        if ranges.send.send(job).is_err() {
+            return Err(ForkliftError::CrossbeamChannelError("Unable to queue range".to_string()));
+        }
+        num_ranges += 1;
+        start += ranges.range_size;
+    }
+    debug!("Copying {:?} in {} ranges", src_path, num_ranges);
+    // every range has to come back before returning, even after one failed,
+    // so nothing is still writing to dest afterwards
+    let (mut copied, mut error, mut received) = (vec![], None, 0);
+    while received < num_ranges {
+        select! {
+            recv(results) -> result => match result {
+                Ok(Ok(range)) => {
+                    copied.push(range);
+                    received += 1;
+                }
+                Ok(Err(e)) => {
+                    failed.store(true, Ordering::SeqCst);
+                    error = error.or(Some(e));
+                    received += 1;
+                }
+                Err(_) => {
+                    let err = "Unable to receive copied range".to_string();
+                    return Err(ForkliftError::CrossbeamChannelError(err));
+                }
+            },
+            recv(ranges.recv) -> job => if let Ok(job) = job {
+                run_range(job, (src_context, dest_context), progress_send);
+            },
+        }
+    }
+    if let Some(e) = error {
+        return Err(e);
+    }
+    copied.sort_by_key(|range| range.start);
+    let (mut src_hasher, mut dest_hasher) = (algorithm.hasher(), algorithm.hasher());
+    let (mut matched, mut transferred) = (0, 0);
+    for range in &copied {
+        src_hasher.update(&range.src_check);
+        dest_hasher.update(&range.dest_check);
+        matched += range.matched;
+        transferred += range.transferred;
+    }
+    let (src_check, dest_check) = (src_hasher.finish_reset(), dest_hasher.finish_reset());
+    let path = src_path.to_string_lossy().to_string();
+    if src_check != dest_check {
+        let mess = LogMessage::Error(ForkliftError::ChecksumError(format!(
+            "{} has source checksum {:?}, destination checksum {:?}",
+            path, src_check, dest_check
+        )));
+        send_mess(mess, logs_send)?
+    }
+    if transferred == 0 && !resized {
+        return Ok(SyncOutcome::UpToDate);
+    }
+    // the checksums are not those of the whole files, so they are recorded under another name
+    let name = ranges_name(algorithm, ranges.range_size);
+    if is_copy {
+        Ok(SyncOutcome::FileCopied(path, src_check, dest_check, name, size as i64, current_time()))
+    } else {
+        Ok(SyncOutcome::ChecksumUpdated(
+            path,
+            src_check,
+            dest_check,
+            name,
+            size as i64,
+            current_time(),
+            matched,
+            transferred,
+        ))
+    }
+}
+
+#[test]
+fn test_chunked_copy_with_helpers() {
+    use crate::memory_filesystem::{MemoryContext, MemoryData};
+    use std::path::Path;
+    use std::thread;
+    let (src, dest) = (MemoryContext::new(1), MemoryContext::new(2));
+    let path = Path::new("/file");
+    let contents: Vec<u8> = (0..3_000_000u32).map(|i| (i * 31 % 251) as u8).collect();
+    let mut old = contents[..2_000_000].to_vec();
+    old.extend(vec![7; 1_500_000]);
+    src.add_file(path, &contents, 0o644).unwrap();
+    dest.add_file(path, &old, 0o644).unwrap();
+    let (src_context, dest_context) = (ProtocolContext::Memory(src), ProtocolContext::Memory(dest));
+    let (send_prog, _rec_prog) = channel::unbounded();
+    let (send_log, _rec_log) = channel::unbounded();
+    let ranges = RangeQueue::new(500_000);
+    let helpers: Vec<_> = (0..2)
+        .map(|_| {
+            let receiver = ranges.receiver().clone();
+            let contexts = (src_context.clone(), dest_context.clone());
+            let send_prog = send_prog.clone();
+            thread::spawn(move || {
+                for job in receiver.iter() {
+                    run_range(job, (&contexts.0, &contexts.1), &send_prog);
+                }
+            })
+        })
+        .collect();
+    let (src_entry, dest_entry) = (Entry::new(path, &src_context), Entry::new(path, &dest_context));
+    let contexts = (&src_context, &dest_context);
+    let copy = (false, HashAlgorithm::default());
+    let outcome =
+        chunked_copy(&src_entry, &dest_entry, contexts, copy, &ranges, &send_prog, &send_log);
+    drop(ranges);
+    for helper in helpers {
+        helper.join().unwrap();
+    }
+    match outcome {
+        Ok(SyncOutcome::ChecksumUpdated(_, src, dest, name, .., matched, transferred)) => {
+            assert_eq!(src, dest);
+            assert_eq!(name, "meow-ranges-500000B");
+            assert_eq!((matched, transferred), (2_000_000, 1_000_000));
+        }
+        outcome => panic!("expected ChecksumUpdated, got {:?}", outcome),
+    }
+    if let ProtocolContext::Memory(dest) = &dest_context {
+        assert_eq!(dest.data(path), Some(MemoryData::File(contents)));
+    }
+}
@@ -71,12 +71,32 @@ impl ProgressInfo for ConsoleProgressOutput {
             stats.copied, stats.symlink_created, stats.symlink_updated, stats.symlink_skipped
         );
         println!(
-            "{} directories created, {} directories updated",
-            stats.directory_created, stats.directory_updated
+            "{} directories created, {} directories updated, {} hard links created",
+            stats.directory_created, stats.directory_updated, stats.hard_link_created
         );
         println!(
-            "{} permissions updated, {} checksum updated",
-            stats.permissions_update, stats.checksum_updated
+            "{} special files created, {} special files skipped",
+            stats.special_created, stats.special_skipped
+        );
+        println!(
+            "{} permissions updated, {} checksum updated, {} bytes left as holes",
+            stats.permissions_update, stats.checksum_updated, stats.hole_bytes_skipped
+        );
+        println!(
+            "{} bytes matched, {} bytes transferred in updated files",
+            stats.bytes_matched, stats.bytes_transferred
+        );
+        println!(
+            "{} files verified, {} files failed verification",
+            stats.files_verified, stats.verify_failed
+        );
+        println!(
+            "{} entries removed, {} entries quarantined",
+            stats.removed, stats.quarantined
+        );
+        println!(
+            "{} files filtered out by attributes, {} entries failed",
+            stats.filtered_out, stats.failed
         );
     }
 }
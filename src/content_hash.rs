@@ -0,0 +1,128 @@
+use digest::Digest;
+use meowhash::MeowHasher;
+use serde_derive::*;
+use sha2::{Sha256, Sha512};
+use xxhash_rust::xxh3::Xxh3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+/// algorithm used to checksum file contents
+pub enum HashAlgorithm {
+    /// MeowHash, fast but not cryptographic
+    Meow,
+    /// 128 bit xxHash3, fast but not cryptographic
+    Xxh3,
+    /// BLAKE3, cryptographic and fast
+    Blake3,
+    /// SHA-256
+    Sha256,
+    /// SHA-512
+    Sha512,
+}
+
+impl Default for HashAlgorithm {
+    fn default() -> Self {
+        HashAlgorithm::Meow
+    }
+}
+
+impl HashAlgorithm {
+    /// create a new hasher for this algorithm
+    pub fn hasher(self) -> Box<dyn ContentHasher> {
+        match self {
+            HashAlgorithm::Meow => Box::new(DigestHasher(MeowHasher::new())),
+            HashAlgorithm::Xxh3 => Box::new(Xxh3::new()),
+            HashAlgorithm::Blake3 => Box::new(blake3::Hasher::new()),
+            HashAlgorithm::Sha256 => Box::new(DigestHasher(Sha256::new())),
+            HashAlgorithm::Sha512 => Box::new(DigestHasher(Sha512::new())),
+        }
+    }
+    /// the name of the algorithm, as recorded in the Files table
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Meow => "meow",
+            HashAlgorithm::Xxh3 => "xxh3-128",
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Sha512 => "sha512",
+        }
+    }
+}
+
+/// a hash of file contents that is fed data incrementally
+pub trait ContentHasher: Send {
+    /// add buf to the data being hashed
+    fn update(&mut self, buf: &[u8]);
+    /// get the hash of all the data added so far, and reset the hasher
+    fn finish_reset(&mut self) -> Vec<u8>;
+    /// copy the hasher with the data added so far, to get the hash of a prefix of the data
+    fn box_clone(&self) -> Box<dyn ContentHasher>;
+}
+
+/// ContentHasher for the algorithms implementing Digest
+struct DigestHasher<D>(D);
+
+impl<D: Digest + Clone + Send + 'static> ContentHasher for DigestHasher<D> {
+    fn update(&mut self, buf: &[u8]) {
+        self.0.input(buf);
+    }
+    fn finish_reset(&mut self) -> Vec<u8> {
+        self.0.result_reset().as_slice().to_vec()
+    }
+    fn box_clone(&self) -> Box<dyn ContentHasher> {
+        Box::new(DigestHasher(self.0.clone()))
+    }
+}
+
+impl ContentHasher for Xxh3 {
+    fn update(&mut self, buf: &[u8]) {
+        Xxh3::update(self, buf);
+    }
+    fn finish_reset(&mut self) -> Vec<u8> {
+        let hash = self.digest128().to_be_bytes().to_vec();
+        self.reset();
+        hash
+    }
+    fn box_clone(&self) -> Box<dyn ContentHasher> {
+        Box::new(self.clone())
+    }
+}
+
+impl ContentHasher for blake3::Hasher {
+    fn update(&mut self, buf: &[u8]) {
+        blake3::Hasher::update(self, buf);
+    }
+    fn finish_reset(&mut self) -> Vec<u8> {
+        let hash = self.finalize().as_bytes().to_vec();
+        self.reset();
+        hash
+    }
+    fn box_clone(&self) -> Box<dyn ContentHasher> {
+        Box::new(self.clone())
+    }
+}
+
+#[test]
+fn test_hash_algorithms() {
+    let algorithms = [
+        HashAlgorithm::Meow,
+        HashAlgorithm::Xxh3,
+        HashAlgorithm::Blake3,
+        HashAlgorithm::Sha256,
+        HashAlgorithm::Sha512,
+    ];
+    for algorithm in &algorithms {
+        let mut hasher = algorithm.hasher();
+        hasher.update(b"ab");
+        let prefix = hasher.box_clone().finish_reset();
+        hasher.update(b"c");
+        let hash = hasher.finish_reset();
+        hasher.update(b"ab");
+        assert_eq!(hasher.finish_reset(), prefix, "{} clone is not a prefix", algorithm.name());
+        hasher.update(b"abc");
+        assert_eq!(hasher.finish_reset(), hash, "{} is not incremental", algorithm.name());
+    }
+    let mut sha256 = HashAlgorithm::Sha256.hasher();
+    sha256.update(b"abc");
+    let hex: String = sha256.finish_reset().iter().map(|b| format!("{:02x}", b)).collect();
+    assert_eq!(hex, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+}
@@ -0,0 +1,505 @@
+use crate::content_hash::{ContentHasher, HashAlgorithm};
+use crate::error::{ForkliftError, ForkliftResult};
+use crate::filesystem::{File, FileType};
+use crate::filesystem_ops::hash;
+
+use std::collections::HashMap;
+
+/// smallest block size used to find matching data
+const MIN_BLOCK_SIZE: u64 = 4 * 1024;
+/// largest block size used to find matching data, unless the file has too many blocks
+const MAX_BLOCK_SIZE: u64 = 128 * 1024;
+/// most blocks in a signature, the block size grows past MAX_BLOCK_SIZE to keep to it
+const MAX_BLOCKS: u64 = 256 * 1024;
+/// most data held in memory at once to break cycles of moves; past it, the data of a
+/// match is transferred from the source again instead
+const MAX_HELD: u64 = 32 * 1024 * 1024;
+/// amount of data read from a file at once
+const READ_SIZE: u64 = 1024 * 1024;
+
+/// pick the block size for a destination file of size bytes
+///
+/// @note   like rsync, this is about the square root of the size, so that both the
+///         number of blocks and the amount of data resent for a changed block
+///         grow slowly with the size of the file.  The signature keeps a checksum of
+///         every block in memory, so files of more than MAX_BLOCKS blocks get larger
+///         blocks instead
+pub fn block_size(size: u64) -> u64 {
+    let root = (size as f64).sqrt() as u64;
+    let block = (root.div_ceil(1024) * 1024).clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE);
+    block.max(size.div_ceil(MAX_BLOCKS).div_ceil(1024) * 1024)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// rsync's weak checksum of a window of bytes, which can be moved forward one byte
+/// at a time without rereading the whole window
+struct Rolling {
+    /// sum of the bytes
+    a: u32,
+    /// sum of the bytes weighted by their distance from the end of the window
+    b: u32,
+    /// length of the window
+    len: u32,
+}
+
+impl Rolling {
+    fn new(buf: &[u8]) -> Self {
+        let (mut a, mut b) = (0u32, 0u32);
+        for (i, &x) in buf.iter().enumerate() {
+            a = a.wrapping_add(u32::from(x));
+            b = b.wrapping_add(((buf.len() - i) as u32).wrapping_mul(u32::from(x)));
+        }
+        Rolling { a, b, len: buf.len() as u32 }
+    }
+    /// the 32 bit checksum of the window
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+    /// move the window forward one byte, dropping out and adding next
+    fn roll(&mut self, out: u8, next: u8) {
+        self.a = self.a.wrapping_sub(u32::from(out)).wrapping_add(u32::from(next));
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(u32::from(out))).wrapping_add(self.a);
+    }
+}
+
+/// a block of the destination file
+struct Block {
+    /// offset of the block in the destination file
+    offset: u64,
+    /// length of the block, only the last block can be shorter than the block size
+    len: u64,
+    /// strong checksum of the block
+    strong: Vec<u8>,
+}
+
+/// the checksums of every block of the destination file
+pub struct Signature {
+    /// size of the blocks
+    block_size: u64,
+    /// blocks of the file, by weak checksum
+    blocks: HashMap<u32, Vec<Block>>,
+    /// size of the file
+    size: u64,
+}
+
+impl Signature {
+    /// read the whole destination file, checksumming each of its blocks
+    pub fn new(dest: &FileType, algorithm: HashAlgorithm) -> ForkliftResult<Self> {
+        let size = dest.fstat()?.size() as u64;
+        let block_size = block_size(size);
+        let mut signature = Signature { block_size, blocks: HashMap::new(), size: 0 };
+        let mut hasher = algorithm.hasher();
+        let mut buf: Vec<u8> = vec![];
+        loop {
+            let chunk = dest.read(READ_SIZE / block_size * block_size, signature.size)?;
+            if chunk.is_empty() {
+                break;
+            }
+            signature.size += chunk.len() as u64;
+            buf.extend_from_slice(&chunk);
+            let whole = buf.len() - buf.len() % block_size as usize;
+            let offset = signature.size - buf.len() as u64;
+            signature.add_blocks(&buf[..whole], offset, hasher.as_mut());
+            buf.drain(..whole);
+        }
+        let offset = signature.size - buf.len() as u64;
+        signature.add_blocks(&buf, offset, hasher.as_mut());
+        Ok(signature)
+    }
+    /// add the blocks of buf, which starts at offset in the file
+    fn add_blocks(&mut self, buf: &[u8], offset: u64, hasher: &mut dyn ContentHasher) {
+        for (i, block) in buf.chunks(self.block_size as usize).enumerate() {
+            let weak = Rolling::new(block).digest();
+            self.blocks.entry(weak).or_default().push(Block {
+                offset: offset + i as u64 * self.block_size,
+                len: block.len() as u64,
+                strong: hash(block, hasher),
+            });
+        }
+    }
+    /// size of the destination file
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+    /// find a block with the same contents as window, which starts at offset in the source.
+    /// A block that is already at offset is preferred, since it does not need to be moved
+    fn find(
+        &self,
+        weak: u32,
+        window: &[u8],
+        offset: u64,
+        hasher: &mut dyn ContentHasher,
+    ) -> Option<u64> {
+        let blocks = self.blocks.get(&weak)?;
+        let strong = hash(window, hasher);
+        blocks
+            .iter()
+            .filter(|block| block.len == window.len() as u64 && block.strong == strong)
+            .min_by_key(|block| block.offset != offset)
+            .map(|block| block.offset)
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+/// a step in rebuilding the source file from the destination file
+pub enum DeltaOp {
+    /// the len bytes at dest_offset in the destination are the ones at src_offset in the source
+    Match { src_offset: u64, dest_offset: u64, len: u64 },
+    /// the len bytes at src_offset in the source are not in the destination
+    Literal { src_offset: u64, len: u64 },
+}
+
+impl DeltaOp {
+    /// true if the op is a match that is already where it belongs
+    pub fn in_place(&self) -> bool {
+        match self {
+            DeltaOp::Match { src_offset, dest_offset, .. } => src_offset == dest_offset,
+            DeltaOp::Literal { .. } => false,
+        }
+    }
+    /// the range of the destination file written by the op
+    fn target(&self) -> (u64, u64) {
+        match *self {
+            DeltaOp::Match { src_offset, len, .. } => (src_offset, src_offset + len),
+            DeltaOp::Literal { src_offset, len } => (src_offset, src_offset + len),
+        }
+    }
+}
+
+/// scan the source file for blocks of the destination file at any offset, calling
+/// progress with the number of bytes read so far.  Returns the ops to rebuild the
+/// source from the destination and the strong checksum of the whole source file
+///
+/// @note   literal data is not kept in memory; apply reads it from the source again
+pub fn delta<P>(
+    src: &FileType,
+    signature: &Signature,
+    algorithm: HashAlgorithm,
+    mut progress: P,
+) -> ForkliftResult<(Vec<DeltaOp>, Vec<u8>)>
+where
+    P: FnMut(u64) -> ForkliftResult<()>,
+{
+    let block_size = signature.block_size as usize;
+    let (mut hasher, mut file_hasher) = (algorithm.hasher(), algorithm.hasher());
+    let mut ops = vec![];
+    // buf holds the source from buf_start, the window starts at pos in buf
+    let (mut buf, mut buf_start, mut pos): (Vec<u8>, u64, usize) = (vec![], 0, 0);
+    let (mut literal_start, mut eof, mut rolling) = (0, false, None);
+    loop {
+        if !eof && buf.len() - pos <= block_size {
+            buf.drain(..pos);
+            buf_start += pos as u64;
+            pos = 0;
+            let chunk = src.read(READ_SIZE, buf_start + buf.len() as u64)?;
+            if chunk.is_empty() {
+                eof = true;
+            } else {
+                file_hasher.update(&chunk);
+                buf.extend_from_slice(&chunk);
+                progress(buf_start + buf.len() as u64)?;
+            }
+            continue;
+        }
+        let len = (buf.len() - pos).min(block_size);
+        if len == 0 {
+            break;
+        }
+        let window = &buf[pos..pos + len];
+        let mut weak = match rolling {
+            Some(weak) => weak,
+            None => Rolling::new(window),
+        };
+        let offset = buf_start + pos as u64;
+        if let Some(dest_offset) = signature.find(weak.digest(), window, offset, hasher.as_mut()) {
+            if offset > literal_start {
+                let len = offset - literal_start;
+                ops.push(DeltaOp::Literal { src_offset: literal_start, len });
+            }
+            ops.push(DeltaOp::Match { src_offset: offset, dest_offset, len: len as u64 });
+            pos += len;
+            literal_start = offset + len as u64;
+            rolling = None;
+        } else if len < block_size {
+            // the end of the source is shorter than a block and does not match
+            break;
+        } else {
+            rolling = match buf.get(pos + len) {
+                Some(&next) => {
+                    weak.roll(buf[pos], next);
+                    Some(weak)
+                }
+                None => None,
+            };
+            pos += 1;
+        }
+    }
+    let end = buf_start + buf.len() as u64;
+    if end > literal_start {
+        ops.push(DeltaOp::Literal { src_offset: literal_start, len: end - literal_start });
+    }
+    Ok((ops, file_hasher.finish_reset()))
+}
+
+/// read exactly len bytes at offset from the file
+fn read_exact(file: &FileType, len: u64, offset: u64) -> ForkliftResult<Vec<u8>> {
+    let mut buf = vec![];
+    while (buf.len() as u64) < len {
+        let mut chunk = file.read(len - buf.len() as u64, offset + buf.len() as u64)?;
+        if chunk.is_empty() {
+            return Err(ForkliftError::FSError(format!(
+                "File ended before reading {} bytes at offset {}",
+                len, offset
+            )));
+        }
+        buf.append(&mut chunk);
+    }
+    Ok(buf)
+}
+
+/// write all of buf at offset to the file
+fn write_all(file: &FileType, buf: &[u8], offset: u64) -> ForkliftResult<()> {
+    let mut written = 0;
+    while written < buf.len() {
+        let num_written = file.write(&buf[written..], offset + written as u64)?;
+        if num_written == 0 {
+            return Err(ForkliftError::FSError(format!("Could not write at offset {}", offset)));
+        }
+        written += num_written as usize;
+    }
+    Ok(())
+}
+
+/// copy the len bytes at start in src to the same offset in target
+fn copy_literal(src: &FileType, target: &FileType, start: u64, len: u64) -> ForkliftResult<()> {
+    let mut offset = start;
+    while offset < start + len {
+        let count = READ_SIZE.min(start + len - offset);
+        let buf = read_exact(src, count, offset)?;
+        write_all(target, &buf, offset)?;
+        offset += count;
+    }
+    Ok(())
+}
+
+/// rebuild the source file in target, an empty file, from the blocks of the destination
+/// file and the literal data of the source.
+/// Returns the number of bytes matched and the number of bytes transferred
+pub fn apply_to(
+    ops: &[DeltaOp],
+    src: &FileType,
+    dest: &FileType,
+    target: &FileType,
+) -> ForkliftResult<(u64, u64)> {
+    let (mut matched, mut transferred) = (0, 0);
+    for op in ops {
+        match *op {
+            DeltaOp::Match { src_offset, dest_offset, len } => {
+                write_all(target, &read_exact(dest, len, dest_offset)?, src_offset)?;
+                matched += len;
+            }
+            DeltaOp::Literal { src_offset, len } => {
+                copy_literal(src, target, src_offset, len)?;
+                transferred += len;
+            }
+        }
+    }
+    Ok((matched, transferred))
+}
+
+/// rebuild the source file in place in the destination file, by moving matching blocks
+/// inside the destination and copying literal data from the source.
+/// Returns the number of bytes matched and the number of bytes transferred
+///
+/// @note   an op cannot overwrite data another match still has to read, so the ops are
+///         run in dependency order.  When the dependencies form a cycle (for example two
+///         blocks that swap places), the data of one match is held in memory first, or,
+///         once MAX_HELD bytes are held, transferred from the source again
+pub fn apply(
+    ops: &[DeltaOp],
+    src: &FileType,
+    dest: &FileType,
+    size: u64,
+) -> ForkliftResult<(u64, u64)> {
+    apply_holding(ops, src, dest, (size, MAX_HELD))
+}
+
+/// helper for apply, holding at most max_held bytes in memory
+fn apply_holding(
+    ops: &[DeltaOp],
+    src: &FileType,
+    dest: &FileType,
+    (size, max_held): (u64, u64),
+) -> ForkliftResult<(u64, u64)> {
+    let (mut matched, mut transferred) = (0, 0);
+    let mut moves = vec![];
+    for op in ops {
+        match op {
+            DeltaOp::Match { len, .. } if op.in_place() => matched += len,
+            _ => moves.push(op),
+        }
+    }
+    // reads of the matches as (offset, len, op), sorted by offset
+    let mut reads: Vec<(u64, u64, usize)> = moves
+        .iter()
+        .enumerate()
+        .filter_map(|(i, op)| match op {
+            DeltaOp::Match { dest_offset, len, .. } => Some((*dest_offset, *len, i)),
+            DeltaOp::Literal { .. } => None,
+        })
+        .collect();
+    reads.sort();
+    let longest = reads.iter().map(|read| read.1).max().unwrap_or(0);
+    // after[i] are the ops that overwrite the data op i reads
+    let mut after: Vec<Vec<usize>> = vec![vec![]; moves.len()];
+    let mut waiting = vec![0; moves.len()];
+    for (j, op) in moves.iter().enumerate() {
+        let (start, end) = op.target();
+        let first = match reads.binary_search(&(start.saturating_sub(longest), 0, 0)) {
+            Ok(i) | Err(i) => i,
+        };
+        for &(offset, len, i) in reads[first..].iter().take_while(|read| read.0 < end) {
+            if i != j && offset + len > start {
+                after[i].push(j);
+                waiting[j] += 1;
+            }
+        }
+    }
+    let mut ready: Vec<usize> = (0..moves.len()).filter(|&i| waiting[i] == 0).collect();
+    let (mut held, mut held_size): (HashMap<usize, Vec<u8>>, u64) = (HashMap::new(), 0);
+    let (mut done, mut released) = (vec![false; moves.len()], vec![false; moves.len()]);
+    let (mut remaining, mut next) = (moves.len(), 0);
+    while remaining > 0 {
+        let i = match ready.pop() {
+            Some(i) => i,
+            None => {
+                // every op left waits for another, hold a match's data to break the cycle
+                // (or, if too much is held, let it be overwritten and resend it)
+                let (offset, len) = loop {
+                    match *moves[next] {
+                        DeltaOp::Match { dest_offset, len, .. } if !released[next] => {
+                            break (dest_offset, len);
+                        }
+                        _ => next += 1,
+                    }
+                };
+                if held_size + len <= max_held {
+                    held.insert(next, read_exact(dest, len, offset)?);
+                    held_size += len;
+                }
+                released[next] = true;
+                for &j in &after[next] {
+                    waiting[j] -= 1;
+                    if waiting[j] == 0 {
+                        ready.push(j);
+                    }
+                }
+                continue;
+            }
+        };
+        match *moves[i] {
+            DeltaOp::Match { src_offset, dest_offset, len } => {
+                if let Some(buf) = held.remove(&i) {
+                    held_size -= len;
+                    write_all(dest, &buf, src_offset)?;
+                    matched += len;
+                } else if released[i] {
+                    // released to break a cycle without being held, so its data may be gone
+                    copy_literal(src, dest, src_offset, len)?;
+                    transferred += len;
+                } else {
+                    write_all(dest, &read_exact(dest, len, dest_offset)?, src_offset)?;
+                    matched += len;
+                }
+            }
+            DeltaOp::Literal { src_offset, len } => {
+                copy_literal(src, dest, src_offset, len)?;
+                transferred += len;
+            }
+        }
+        done[i] = true;
+        remaining -= 1;
+        if !released[i] {
+            released[i] = true;
+            for &j in &after[i] {
+                waiting[j] -= 1;
+                if waiting[j] == 0 {
+                    ready.push(j);
+                }
+            }
+        }
+    }
+    if dest.fstat()?.size() as u64 != size {
+        dest.truncate(size)?;
+    }
+    Ok((matched, transferred))
+}
+
+/// the strong checksum of the whole file
+pub fn checksum(file: &FileType, algorithm: HashAlgorithm) -> ForkliftResult<Vec<u8>> {
+    let mut hasher = algorithm.hasher();
+    let mut offset = 0;
+    loop {
+        let chunk = file.read(READ_SIZE, offset)?;
+        if chunk.is_empty() {
+            break;
+        }
+        hasher.update(&chunk);
+        offset += chunk.len() as u64;
+    }
+    Ok(hasher.finish_reset())
+}
+
+#[test]
+fn test_delta_moves_blocks() {
+    use crate::filesystem::{FileSystem, ProtocolContext};
+    use crate::memory_filesystem::{MemoryContext, MemoryData};
+    use nix::fcntl::OFlag;
+    use nix::sys::stat::Mode;
+    use std::path::Path;
+
+    let data: Vec<u8> = (0..40_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    let mut weak = Rolling::new(&data[..4096]);
+    weak.roll(data[0], data[4096]);
+    assert_eq!(weak, Rolling::new(&data[1..4097]));
+
+    // swap the first two blocks, insert data after them, and drop the last few bytes
+    let size = block_size(data.len() as u64) as usize;
+    let mut new = data[size..2 * size].to_vec();
+    new.extend_from_slice(&data[..size]);
+    new.extend_from_slice(b"inserted");
+    new.extend_from_slice(&data[2 * size..data.len() - 10]);
+    let (src, dest) = (MemoryContext::new(1), MemoryContext::new(2));
+    let path = Path::new("/file");
+    src.add_file(path, &new, 0o644).unwrap();
+    dest.add_file(path, &data, 0o644).unwrap();
+    let (src, dest) = (ProtocolContext::Memory(src), ProtocolContext::Memory(dest));
+    let src_file = src.open(path, OFlag::O_RDONLY, Mode::S_IRWXU).unwrap();
+    let dest_file = dest.open(path, OFlag::O_RDWR, Mode::S_IRWXU).unwrap();
+
+    let algorithm = HashAlgorithm::Sha256;
+    let signature = Signature::new(&dest_file, algorithm).unwrap();
+    let (ops, check) = delta(&src_file, &signature, algorithm, |_| Ok(())).unwrap();
+    let size = size as u64;
+    assert_eq!(ops[0], DeltaOp::Match { src_offset: 0, dest_offset: size, len: size });
+    let (matched, transferred) = apply(&ops, &src_file, &dest_file, new.len() as u64).unwrap();
+    assert_eq!(matched + transferred, new.len() as u64);
+    assert!(transferred < size);
+    assert_eq!(checksum(&dest_file, algorithm).unwrap(), check);
+    if let ProtocolContext::Memory(dest) = &dest {
+        assert_eq!(dest.data(path), Some(MemoryData::File(new.clone())));
+    }
+
+    // holding nothing, the cycle of the swapped blocks is broken by resending one of them
+    dest_file.write(&data, 0).unwrap();
+    dest_file.truncate(data.len() as u64).unwrap();
+    let (matched, transferred) =
+        apply_holding(&ops, &src_file, &dest_file, (new.len() as u64, 0)).unwrap();
+    assert_eq!(matched + transferred, new.len() as u64);
+    assert!(transferred >= size);
+    assert_eq!(checksum(&dest_file, algorithm).unwrap(), check);
+
+    // huge files get larger blocks rather than more of them
+    assert_eq!(block_size(1 << 30), 32 * 1024);
+    assert_eq!(block_size(1 << 40) * MAX_BLOCKS, 1 << 40);
+}
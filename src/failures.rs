@@ -0,0 +1,163 @@
+use crate::error::{ForkliftError, ForkliftResult};
+use crate::socket_node::SocketNode;
+
+use log::*;
+use serde_derive::*;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+/// what failed on an entry
+pub enum Operation {
+    /// syncing the entry
+    Sync,
+    /// planning the sync of the entry in a dry run
+    Plan,
+    /// opening the source directory, to walk it
+    OpenSourceDir,
+    /// opening the destination directory, to find the entries that are not in the source
+    OpenDestinationDir,
+    /// reading the synced file back, to compare it with the source
+    Verify,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// an entry that failed for good in a run
+pub struct Failure {
+    /// path of the entry, relative to src_path and dest_path
+    pub path: PathBuf,
+    /// what failed on it
+    pub operation: Operation,
+    /// errno of the last error, if it has one (for Samba errors, the errno the Samba
+    /// library mapped its status to)
+    pub errno: Option<i32>,
+    /// the last error
+    pub error: String,
+    /// number of times the entry was tried
+    pub attempts: u32,
+    /// address of the node that tried it
+    pub node: String,
+}
+
+impl Failure {
+    /// create a Failure from the last error of the entry at path
+    pub fn new(
+        (path, operation): (&Path, Operation),
+        error: &ForkliftError,
+        attempts: u32,
+        node: &SocketNode,
+    ) -> Self {
+        Failure {
+            path: path.to_path_buf(),
+            operation,
+            errno: errno(error),
+            error: format!("{:?}", error),
+            attempts,
+            node: SocketAddr::new(node.get_ip(), node.get_port()).to_string(),
+        }
+    }
+}
+
+/// get the errno of an error, if it has one.  Samba errors keep the errno libsmbclient set
+/// from the SMB status in the I/O or nix error they wrap
+pub fn errno(error: &ForkliftError) -> Option<i32> {
+    match error {
+        ForkliftError::IoError(e) => e.raw_os_error(),
+        ForkliftError::SmbcError(e) => {
+            let mut cause: Option<&(dyn Error + 'static)> = Some(e);
+            while let Some(e) = cause {
+                if let Some(e) = e.downcast_ref::<io::Error>() {
+                    return e.raw_os_error();
+                }
+                if let Some(nix::Error::Sys(errno)) = e.downcast_ref::<nix::Error>() {
+                    return Some(*errno as i32);
+                }
+                cause = e.source();
+            }
+            None
+        }
+        _ => None,
+    }
+}
+
+/// write the failures of a run to a local Json file, sorted by path
+pub fn write_failures(path: &Path, failures: &mut Vec<Failure>) -> ForkliftResult<()> {
+    failures.sort_by(|a, b| a.path.cmp(&b.path));
+    let mut out = BufWriter::new(File::create(path)?);
+    serde_json::to_writer_pretty(&mut out, failures)?;
+    writeln!(out)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// read the failures written by an earlier run
+pub fn read_failures(path: &Path) -> ForkliftResult<Vec<Failure>> {
+    match fs::read_to_string(path) {
+        Ok(failures) => {
+            debug!("loaded failures {:?}", path);
+            match serde_json::from_str(&failures) {
+                Ok(failures) => Ok(failures),
+                Err(e) => Err(ForkliftError::InvalidConfigError(format!(
+                    "Error {:?}, unable to parse failures file {:?}",
+                    e, path
+                ))),
+            }
+        }
+        Err(e) => Err(ForkliftError::InvalidConfigError(format!(
+            "Error {:?}, unable to read failures file {:?}",
+            e, path
+        ))),
+    }
+}
+
+/// get the entries to sync again, and the directories to walk again, to retry failures
+pub fn retry_paths(failures: &[Failure]) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let (mut entries, mut dirs) = (vec![], vec![]);
+    for failure in failures {
+        match failure.operation {
+            Operation::Sync | Operation::Plan | Operation::Verify => {
+                entries.push(failure.path.clone())
+            }
+            Operation::OpenSourceDir | Operation::OpenDestinationDir => {
+                dirs.push(failure.path.clone())
+            }
+        }
+    }
+    dirs.sort();
+    dirs.dedup();
+    // the entries in a directory walked again are synced by the walk
+    entries.retain(|path| !dirs.iter().any(|dir| path != dir && path.starts_with(dir)));
+    entries.sort();
+    entries.dedup();
+    (entries, dirs)
+}
+
+#[test]
+fn test_failures_file() {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    let node = SocketNode::new(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 7000));
+    let eio = ForkliftError::IoError(io::Error::from_raw_os_error(5));
+    let timeout = ForkliftError::TimeoutError("no reply".to_string());
+    let mut failures = vec![
+        Failure::new((Path::new("b/file"), Operation::Sync), &eio, 4, &node),
+        Failure::new((Path::new("a"), Operation::OpenSourceDir), &timeout, 4, &node),
+        Failure::new((Path::new("a"), Operation::OpenDestinationDir), &eio, 1, &node),
+        Failure::new((Path::new("a/file"), Operation::Sync), &eio, 4, &node),
+        Failure::new((Path::new("c/file"), Operation::Verify), &eio, 3, &node),
+    ];
+    assert_eq!((failures[0].errno, failures[1].errno), (Some(5), None));
+    assert_eq!(failures[0].node, "127.0.0.1:7000");
+
+    let path = std::env::temp_dir().join(format!("forklift-failures-{}", std::process::id()));
+    write_failures(&path, &mut failures).unwrap();
+    let read = read_failures(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(read, failures);
+    assert_eq!(read[3].path, PathBuf::from("b/file"));
+    let entries = vec![PathBuf::from("b/file"), PathBuf::from("c/file")];
+    assert_eq!(retry_paths(&read), (entries, vec![PathBuf::from("a")]));
+}
@@ -1,15 +1,21 @@
 use crate::error::*;
+use crate::local_filesystem::*;
+use crate::memory_filesystem::*;
+use crate::mf_symlink;
 
 use ::rust_smb::*;
 use chrono::*;
 use libnfs::*;
 use log::*;
 use nix::fcntl::OFlag;
-use nix::sys::stat::Mode;
+use nix::libc::{suseconds_t, time_t, timeval};
+use nix::sys::stat::{Mode, SFlag};
 use rand::*;
 use rayon::*;
 use serde_derive::*;
 
+use std::convert::TryFrom;
+use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
 
 /// initialize the Samba context
@@ -54,12 +60,35 @@ pub fn create_nfs_context(
     Ok(ProtocolContext::Nfs(nfs))
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// create a new local Protocol context rooted at the mount point root
+pub fn create_local_context(root: &str) -> ForkliftResult<ProtocolContext> {
+    let local = LocalContext::new(Path::new(root))?;
+    Ok(ProtocolContext::Local(local))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 /// an enum to represent the filesystem type
 pub enum FileSystemType {
     Samba,
     Nfs,
+    Local,
+}
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+/// how symlinks are stored on Samba shares
+pub enum SambaSymlinks {
+    /// symlinks are not synced to or from Samba
+    Skip,
+    /// symlinks are stored as Minshall-French symlink files, the format of the
+    /// Linux cifs client's mfsymlinks mount option
+    MfSymlinks,
+}
+
+impl Default for SambaSymlinks {
+    fn default() -> Self {
+        SambaSymlinks::Skip
+    }
 }
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 /// Debug Level of a Context;
 /// Note, it is not recommended to set the log level above 3 for Samba, as it will cause
@@ -84,8 +113,11 @@ pub enum DebugLevel {
 #[derive(Clone)]
 /// a generic wrapper for filesystem contexts
 pub enum ProtocolContext {
-    Samba(Box<Smbc>),
+    Samba(Box<Smbc>, SambaSymlinks),
     Nfs(Nfs),
+    Local(LocalContext),
+    /// in-memory filesystem, used for testing
+    Memory(MemoryContext),
 }
 
 impl FileSystem for ProtocolContext {
@@ -95,10 +127,18 @@ impl FileSystem for ProtocolContext {
                 let file = nfs.create(path, flags, mode)?;
                 Ok(FileType::Nfs(file))
             }
-            ProtocolContext::Samba(smbc) => {
+            ProtocolContext::Samba(smbc, _) => {
                 let file = smbc.create(path, mode)?;
                 Ok(FileType::Samba(file))
             }
+            ProtocolContext::Local(local) => {
+                let file = local.create(path, flags, mode)?;
+                Ok(FileType::Local(file))
+            }
+            ProtocolContext::Memory(memory) => {
+                let file = memory.create(path, flags, mode)?;
+                Ok(FileType::Memory(file))
+            }
         }
     }
     /// Please note, that Samba's chmod is very peculiar, and may conditionally work
@@ -109,9 +149,49 @@ impl FileSystem for ProtocolContext {
             ProtocolContext::Nfs(nfs) => {
                 nfs.lchmod(path, mode)?;
             }
-            ProtocolContext::Samba(smbc) => {
+            ProtocolContext::Samba(smbc, _) => {
                 smbc.chmod(path, mode)?;
             }
+            ProtocolContext::Local(local) => {
+                local.chmod(path, mode)?;
+            }
+            ProtocolContext::Memory(memory) => {
+                memory.chmod(path, mode)?;
+            }
+        }
+        Ok(())
+    }
+    fn chown(&self, path: &Path, uid: u32, gid: u32) -> ForkliftResult<()> {
+        match self {
+            ProtocolContext::Nfs(nfs) => {
+                nfs.chown(path, uid as i32, gid as i32)?;
+            }
+            ProtocolContext::Samba(..) => {
+                return Err(ForkliftError::FSError("Samba does not support chown".to_string()));
+            }
+            ProtocolContext::Local(local) => {
+                local.chown(path, uid, gid)?;
+            }
+            ProtocolContext::Memory(memory) => {
+                memory.chown(path, uid, gid)?;
+            }
+        }
+        Ok(())
+    }
+    fn lchown(&self, path: &Path, uid: u32, gid: u32) -> ForkliftResult<()> {
+        match self {
+            ProtocolContext::Nfs(nfs) => {
+                nfs.lchown(path, uid as i32, gid as i32)?;
+            }
+            ProtocolContext::Samba(..) => {
+                return Err(ForkliftError::FSError("Samba does not support chown".to_string()));
+            }
+            ProtocolContext::Local(local) => {
+                local.lchown(path, uid, gid)?;
+            }
+            ProtocolContext::Memory(memory) => {
+                memory.chown(path, uid, gid)?;
+            }
         }
         Ok(())
     }
@@ -136,35 +216,104 @@ impl FileSystem for ProtocolContext {
                 );
                 Ok(Stat::new(s, atime, mtime, ctime))
             }
-            ProtocolContext::Samba(sfile) => {
+            ProtocolContext::Samba(sfile, symlinks) => {
                 let stat = sfile.stat(path)?;
                 let atime = Timespec::new(stat.st_atim.tv_sec as i64, stat.st_atim.tv_nsec as i64);
                 let ctime = Timespec::new(stat.st_ctim.tv_sec as i64, stat.st_ctim.tv_nsec as i64);
                 let mtime = Timespec::new(stat.st_mtim.tv_sec as i64, stat.st_mtim.tv_nsec as i64);
+                let (mut mode, mut size) = (stat.st_mode as u32, stat.st_size as i64);
+                // Minshall-French symlink files are reported as symlinks
+                let is_file = mode & SFlag::S_IFMT.bits() == SFlag::S_IFREG.bits();
+                if *symlinks == SambaSymlinks::MfSymlinks
+                    && is_file
+                    && size == mf_symlink::MF_SYMLINK_SIZE
+                {
+                    if let Some(target) = mf_symlink::read_symlink(sfile, path)? {
+                        mode = SFlag::S_IFLNK.bits() | 0o777;
+                        size = target.as_os_str().len() as i64;
+                    }
+                }
                 let s = (
                     stat.st_dev as u64,
                     stat.st_ino as u64,
-                    stat.st_mode as u32,
+                    mode,
                     stat.st_nlink as u64,
                     stat.st_uid as u32,
                     stat.st_gid as u32,
                     stat.st_rdev as u64,
-                    stat.st_size as i64,
+                    size,
                     stat.st_blksize as i64,
                     stat.st_blocks as i64,
                 );
                 Ok(Stat::new(s, atime, mtime, ctime))
             }
+            ProtocolContext::Local(local) => local.lstat(path),
+            ProtocolContext::Memory(memory) => memory.lstat(path),
         }
     }
+    fn link(&self, oldpath: &Path, newpath: &Path) -> ForkliftResult<()> {
+        match self {
+            ProtocolContext::Nfs(nfs) => {
+                nfs.link(oldpath, newpath)?;
+            }
+            ProtocolContext::Samba(..) => {
+                return Err(ForkliftError::FSError("Samba does not support hard links".to_string()));
+            }
+            ProtocolContext::Local(local) => {
+                local.link(oldpath, newpath)?;
+            }
+            ProtocolContext::Memory(memory) => {
+                memory.link(oldpath, newpath)?;
+            }
+        }
+        Ok(())
+    }
+    fn mkfifo(&self, path: &Path, mode: Mode) -> ForkliftResult<()> {
+        self.mknod(path, SFlag::S_IFIFO, mode, 0)
+    }
+    fn mknod(&self, path: &Path, kind: SFlag, mode: Mode, dev: u64) -> ForkliftResult<()> {
+        match self {
+            ProtocolContext::Nfs(nfs) => {
+                // libnfs takes the device number as an int, so larger ones can not be made
+                let dev = match i32::try_from(dev) {
+                    Ok(dev) => dev,
+                    Err(_) => {
+                        return Err(ForkliftError::FSError(format!(
+                            "Device number {:#x} of {:?} is too large for NFS",
+                            dev, path
+                        )));
+                    }
+                };
+                nfs.mknod(path, (kind.bits() | mode.bits()) as i32, dev)?;
+            }
+            ProtocolContext::Samba(..) => {
+                return Err(ForkliftError::FSError(
+                    "Samba does not support special files".to_string(),
+                ));
+            }
+            ProtocolContext::Local(local) => {
+                local.mknod(path, kind, mode, dev)?;
+            }
+            ProtocolContext::Memory(memory) => {
+                memory.mknod(path, kind, mode, dev)?;
+            }
+        }
+        Ok(())
+    }
     fn mkdir(&self, path: &Path) -> ForkliftResult<()> {
         match self {
             ProtocolContext::Nfs(nfs) => {
                 nfs.mkdir(path)?;
             }
-            ProtocolContext::Samba(smbc) => {
+            ProtocolContext::Samba(smbc, _) => {
                 smbc.mkdir(path, Mode::S_IRWXU | Mode::S_IRWXO | Mode::S_IRWXG)?;
             }
+            ProtocolContext::Local(local) => {
+                local.mkdir(path)?;
+            }
+            ProtocolContext::Memory(memory) => {
+                memory.mkdir(path)?;
+            }
         }
         Ok(())
     }
@@ -177,10 +326,18 @@ impl FileSystem for ProtocolContext {
                 let file = nfs.open(path, flags)?;
                 Ok(FileType::Nfs(file))
             }
-            ProtocolContext::Samba(smbc) => {
+            ProtocolContext::Samba(smbc, _) => {
                 let file = smbc.open(path, flags, mode)?;
                 Ok(FileType::Samba(file))
             }
+            ProtocolContext::Local(local) => {
+                let file = local.open(path, flags, mode)?;
+                Ok(FileType::Local(file))
+            }
+            ProtocolContext::Memory(memory) => {
+                let file = memory.open(path, flags, mode)?;
+                Ok(FileType::Memory(file))
+            }
         }
     }
     fn opendir(&self, path: &Path) -> ForkliftResult<DirectoryType> {
@@ -189,20 +346,67 @@ impl FileSystem for ProtocolContext {
                 let dir = nfs.opendir(path)?;
                 Ok(DirectoryType::Nfs(dir))
             }
-            ProtocolContext::Samba(smbc) => {
+            ProtocolContext::Samba(smbc, _) => {
                 let dir = smbc.opendir(path)?;
                 Ok(DirectoryType::Samba(dir))
             }
+            ProtocolContext::Local(local) => {
+                let dir = local.opendir(path)?;
+                Ok(DirectoryType::Local(dir))
+            }
+            ProtocolContext::Memory(memory) => {
+                let dir = memory.opendir(path)?;
+                Ok(DirectoryType::Memory(dir))
+            }
         }
     }
+    /// Please note that Samba only supports symlinks as Minshall-French symlink files
+    fn readlink(&self, path: &Path, buf: &mut [u8]) -> ForkliftResult<()> {
+        match self {
+            ProtocolContext::Nfs(nfs) => {
+                nfs.readlink(path, buf)?;
+            }
+            ProtocolContext::Samba(smbc, SambaSymlinks::MfSymlinks) => {
+                let target = match mf_symlink::read_symlink(smbc, path)? {
+                    Some(target) => target,
+                    None => {
+                        let err = format!("{:?} is not a Minshall-French symlink", path);
+                        return Err(ForkliftError::FSError(err));
+                    }
+                };
+                let bytes = target.as_os_str().as_bytes();
+                if bytes.len() > buf.len() {
+                    let err = format!("Buffer too small for the target of {:?}", path);
+                    return Err(ForkliftError::FSError(err));
+                }
+                buf[..bytes.len()].copy_from_slice(bytes);
+            }
+            ProtocolContext::Samba(_, SambaSymlinks::Skip) => {
+                return Err(ForkliftError::FSError("Samba symlinks are disabled".to_string()));
+            }
+            ProtocolContext::Local(local) => {
+                local.readlink(path, buf)?;
+            }
+            ProtocolContext::Memory(memory) => {
+                memory.readlink(path, buf)?;
+            }
+        }
+        Ok(())
+    }
     fn rename(&self, oldpath: &Path, newpath: &Path) -> ForkliftResult<()> {
         match self {
             ProtocolContext::Nfs(nfs) => {
                 nfs.rename(oldpath, newpath)?;
             }
-            ProtocolContext::Samba(smbc) => {
+            ProtocolContext::Samba(smbc, _) => {
                 smbc.rename(oldpath, newpath)?;
             }
+            ProtocolContext::Local(local) => {
+                local.rename(oldpath, newpath)?;
+            }
+            ProtocolContext::Memory(memory) => {
+                memory.rename(oldpath, newpath)?;
+            }
         }
         Ok(())
     }
@@ -212,9 +416,37 @@ impl FileSystem for ProtocolContext {
             ProtocolContext::Nfs(nfs) => {
                 nfs.rmdir(path)?;
             }
-            ProtocolContext::Samba(smbc) => {
+            ProtocolContext::Samba(smbc, _) => {
                 smbc.rmdir(path)?;
             }
+            ProtocolContext::Local(local) => {
+                local.rmdir(path)?;
+            }
+            ProtocolContext::Memory(memory) => {
+                memory.rmdir(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Please note that Samba does not support symlinks
+    fn symlink(&self, oldpath: &Path, newpath: &Path) -> ForkliftResult<()> {
+        match self {
+            ProtocolContext::Nfs(nfs) => {
+                nfs.symlink(oldpath, newpath)?;
+            }
+            ProtocolContext::Samba(smbc, SambaSymlinks::MfSymlinks) => {
+                mf_symlink::write_symlink(smbc, oldpath, newpath)?;
+            }
+            ProtocolContext::Samba(_, SambaSymlinks::Skip) => {
+                return Err(ForkliftError::FSError("Samba symlinks are disabled".to_string()));
+            }
+            ProtocolContext::Local(local) => {
+                local.symlink(oldpath, newpath)?;
+            }
+            ProtocolContext::Memory(memory) => {
+                memory.symlink(oldpath, newpath)?;
+            }
         }
         Ok(())
     }
@@ -224,9 +456,34 @@ impl FileSystem for ProtocolContext {
             ProtocolContext::Nfs(nfs) => {
                 nfs.unlink(path)?;
             }
-            ProtocolContext::Samba(smbc) => {
+            ProtocolContext::Samba(smbc, _) => {
                 smbc.unlink(path)?;
             }
+            ProtocolContext::Local(local) => {
+                local.unlink(path)?;
+            }
+            ProtocolContext::Memory(memory) => {
+                memory.unlink(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn utimes(&self, path: &Path, atime: Timespec, mtime: Timespec) -> ForkliftResult<()> {
+        let mut times = [atime.to_timeval(), mtime.to_timeval()];
+        match self {
+            ProtocolContext::Nfs(nfs) => {
+                nfs.utimes(path, &mut times)?;
+            }
+            ProtocolContext::Samba(smbc, _) => {
+                smbc.utimes(path, &mut times)?;
+            }
+            ProtocolContext::Local(local) => {
+                local.utimes(path, atime, mtime)?;
+            }
+            ProtocolContext::Memory(memory) => {
+                memory.utimes(path, atime, mtime)?;
+            }
         }
         Ok(())
     }
@@ -237,6 +494,8 @@ impl FileSystem for ProtocolContext {
 pub enum FileType {
     Samba(SmbcFile),
     Nfs(NfsFile),
+    Local(LocalFile),
+    Memory(MemoryFile),
 }
 
 impl File for FileType {
@@ -251,6 +510,8 @@ impl File for FileType {
                 let buf = sfile.fread(count)?;
                 Ok(buf)
             }
+            FileType::Local(lfile) => lfile.pread(count, offset),
+            FileType::Memory(mfile) => mfile.pread(count, offset),
         }
     }
     /// @note: we can return a u64 when the actual write calls return i32 because
@@ -266,6 +527,8 @@ impl File for FileType {
                 let bytes = sfile.fwrite(buf)?;
                 Ok(bytes as u64)
             }
+            FileType::Local(lfile) => lfile.pwrite(buf, offset),
+            FileType::Memory(mfile) => mfile.pwrite(buf, offset),
         }
     }
     /// Please NOTE: Samba stat function's attributes only have certain attributes that are
@@ -312,6 +575,8 @@ impl File for FileType {
                 );
                 Ok(Stat::new(s, atime, mtime, ctime))
             }
+            FileType::Local(lfile) => lfile.fstat(),
+            FileType::Memory(mfile) => mfile.fstat(),
         }
     }
     fn truncate(&self, size: u64) -> ForkliftResult<()> {
@@ -322,9 +587,41 @@ impl File for FileType {
             FileType::Samba(sfile) => {
                 sfile.ftruncate(size as i64)?;
             }
+            FileType::Local(lfile) => {
+                lfile.ftruncate(size)?;
+            }
+            FileType::Memory(mfile) => {
+                mfile.ftruncate(size)?;
+            }
         }
         Ok(())
     }
+    /// NFS and Samba can not find holes, so their files are all data
+    /// (the same as lseek on a Unix filesystem without hole support)
+    fn seek_data(&self, offset: u64) -> ForkliftResult<Option<u64>> {
+        match self {
+            FileType::Nfs(_) | FileType::Samba(_) => {
+                let size = self.fstat()?.size() as u64;
+                if offset < size {
+                    Ok(Some(offset))
+                } else {
+                    Ok(None)
+                }
+            }
+            FileType::Local(lfile) => lfile.seek_data(offset),
+            FileType::Memory(mfile) => mfile.seek_data(offset),
+        }
+    }
+    fn seek_hole(&self, offset: u64) -> ForkliftResult<u64> {
+        match self {
+            FileType::Nfs(_) | FileType::Samba(_) => {
+                let size = self.fstat()?.size() as u64;
+                Ok(offset.max(size))
+            }
+            FileType::Local(lfile) => lfile.seek_hole(offset),
+            FileType::Memory(mfile) => mfile.seek_hole(offset),
+        }
+    }
 }
 
 /// general trait describing a File
@@ -337,22 +634,60 @@ pub trait File {
     fn fstat(&self) -> ForkliftResult<Stat>;
     /// truncate the file to size
     fn truncate(&self, size: u64) -> ForkliftResult<()>;
+    /// get the start of the first data at or after offset,
+    /// or None if there are only holes from offset to the end of the file
+    fn seek_data(&self, offset: u64) -> ForkliftResult<Option<u64>>;
+    /// get the start of the first hole at or after offset
+    /// (the end of the file counts as a hole)
+    fn seek_hole(&self, offset: u64) -> ForkliftResult<u64>;
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 /// a generic enum to represent to different filetypes not specific to a filesystem
 pub enum GenericFileType {
     Directory,
     File,
     Link,
+    Fifo,
+    CharDevice,
+    BlockDevice,
+    Socket,
     Other,
 }
 
+impl GenericFileType {
+    /// get the filetype of a stat mode
+    pub fn from_mode(mode: u32) -> GenericFileType {
+        match SFlag::from_bits_truncate(mode & SFlag::S_IFMT.bits()) {
+            SFlag::S_IFDIR => GenericFileType::Directory,
+            SFlag::S_IFREG => GenericFileType::File,
+            SFlag::S_IFLNK => GenericFileType::Link,
+            SFlag::S_IFIFO => GenericFileType::Fifo,
+            SFlag::S_IFCHR => GenericFileType::CharDevice,
+            SFlag::S_IFBLK => GenericFileType::BlockDevice,
+            SFlag::S_IFSOCK => GenericFileType::Socket,
+            _ => GenericFileType::Other,
+        }
+    }
+    /// check if the filetype is a fifo, device, or socket
+    pub fn is_special(&self) -> bool {
+        match self {
+            GenericFileType::Fifo
+            | GenericFileType::CharDevice
+            | GenericFileType::BlockDevice
+            | GenericFileType::Socket => true,
+            _ => false,
+        }
+    }
+}
+
 #[derive(Clone)]
 /// a generic enum to hold the DirEntry of a filesystem
 pub enum DirEntryType {
     Samba(SmbcDirEntry),
     Nfs(DirEntry),
+    Local(LocalDirEntry),
+    Memory(MemoryDirEntry),
 }
 
 impl DirEntryType {
@@ -361,6 +696,8 @@ impl DirEntryType {
         match self {
             DirEntryType::Samba(smbentry) => smbentry.path.as_path(),
             DirEntryType::Nfs(nfsentry) => nfsentry.path.as_path(),
+            DirEntryType::Local(localentry) => localentry.path.as_path(),
+            DirEntryType::Memory(memoryentry) => memoryentry.path.as_path(),
         }
     }
     /// get the general filetype of the directory entry
@@ -376,8 +713,13 @@ impl DirEntryType {
                 EntryType::Directory => GenericFileType::Directory,
                 EntryType::File => GenericFileType::File,
                 EntryType::Symlink => GenericFileType::Link,
-                _ => GenericFileType::Other,
+                EntryType::NamedPipe => GenericFileType::Fifo,
+                EntryType::Character => GenericFileType::CharDevice,
+                EntryType::Block => GenericFileType::BlockDevice,
+                EntryType::Socket => GenericFileType::Socket,
             },
+            DirEntryType::Local(localentry) => localentry.d_type.clone(),
+            DirEntryType::Memory(memoryentry) => memoryentry.d_type.clone(),
         }
     }
 }
@@ -387,6 +729,8 @@ impl DirEntryType {
 pub enum DirectoryType {
     Samba(SmbcDirectory),
     Nfs(NfsDirectory),
+    Local(LocalDirectory),
+    Memory(MemoryDirectory),
 }
 
 /// a generic iterator for DirectoryType
@@ -404,6 +748,8 @@ impl Iterator for DirectoryType {
                 Some(Err(e)) => Some(Err(ForkliftError::IoError(e))),
                 None => None,
             },
+            DirectoryType::Local(dir) => dir.next().map(|entry| Ok(DirEntryType::Local(entry))),
+            DirectoryType::Memory(dir) => dir.next().map(|entry| Ok(DirEntryType::Memory(entry))),
         }
     }
 }
@@ -422,6 +768,18 @@ impl Timespec {
     pub fn new(sec: i64, nsec: i64) -> Self {
         Timespec { tv_sec: sec, tv_nsec: nsec }
     }
+    /// get the seconds field
+    pub fn tv_sec(&self) -> i64 {
+        self.tv_sec
+    }
+    /// get the nanoseconds field
+    pub fn tv_nsec(&self) -> i64 {
+        self.tv_nsec
+    }
+    /// convert to a timeval (as used by utimes), truncating to microseconds
+    pub fn to_timeval(self) -> timeval {
+        timeval { tv_sec: self.tv_sec as time_t, tv_usec: (self.tv_nsec / 1000) as suseconds_t }
+    }
     /// get the number of hours since the system's EPOCH
     pub fn num_hours(&self) -> i64 {
         self.num_seconds() / 3600
@@ -451,9 +809,9 @@ impl Timespec {
     /// a helper function for getting the number of microseconds represented
     fn micros_mod_sec(&self) -> i64 {
         if self.tv_sec < 0 && self.tv_nsec > 0 {
-            self.tv_sec - 1_000_000
+            (self.tv_nsec - 1_000_000_000) / 1000
         } else {
-            self.tv_nsec
+            self.tv_nsec / 1000
         }
     }
     /// print the time formatted
@@ -580,18 +938,35 @@ pub trait FileSystem {
     fn create(&self, path: &Path, flags: OFlag, mode: Mode) -> ForkliftResult<FileType>;
     /// change the permissions on a file/directory to mode
     fn chmod(&self, path: &Path, mode: Mode) -> ForkliftResult<()>;
+    /// change the owner and group of a file, following symlinks
+    fn chown(&self, path: &Path, uid: u32, gid: u32) -> ForkliftResult<()>;
+    /// change the owner and group of a file, without following symlinks
+    fn lchown(&self, path: &Path, uid: u32, gid: u32) -> ForkliftResult<()>;
     /// get the metadata of a file
     fn stat(&self, path: &Path) -> ForkliftResult<Stat>;
+    /// create a hard link at newpath to the file at oldpath
+    fn link(&self, oldpath: &Path, newpath: &Path) -> ForkliftResult<()>;
+    /// create a named pipe at path
+    fn mkfifo(&self, path: &Path, mode: Mode) -> ForkliftResult<()>;
+    /// create a special file (kind is S_IFIFO, S_IFCHR, S_IFBLK, or S_IFSOCK) at path,
+    /// with device number dev for character and block devices
+    fn mknod(&self, path: &Path, kind: SFlag, mode: Mode, dev: u64) -> ForkliftResult<()>;
     /// make a new directory at path
     fn mkdir(&self, path: &Path) -> ForkliftResult<()>;
     /// open a file at path
     fn open(&self, path: &Path, flags: OFlag, mode: Mode) -> ForkliftResult<FileType>;
     /// open a directory at path
     fn opendir(&self, path: &Path) -> ForkliftResult<DirectoryType>;
+    /// read the target of the symlink at path into buf
+    fn readlink(&self, path: &Path, buf: &mut [u8]) -> ForkliftResult<()>;
     /// rename a file/directory
     fn rename(&self, oldpath: &Path, newpath: &Path) -> ForkliftResult<()>;
     /// remove a directory
     fn rmdir(&self, path: &Path) -> ForkliftResult<()>;
+    /// create a symlink at newpath pointing to oldpath
+    fn symlink(&self, oldpath: &Path, newpath: &Path) -> ForkliftResult<()>;
     /// unlink (remove) a file
     fn unlink(&self, path: &Path) -> ForkliftResult<()>;
+    /// set the access and modification times of a file/directory
+    fn utimes(&self, path: &Path, atime: Timespec, mtime: Timespec) -> ForkliftResult<()>;
 }
@@ -1,30 +1,57 @@
 use ::rust_smb::*;
 use chrono::NaiveDateTime;
 use crossbeam::channel::Sender;
-use digest::Digest;
 use lazy_static::lazy_static;
-use libnfs::*;
 use log::*;
-use meowhash::*;
 use nix::fcntl::OFlag;
-use nix::sys::stat::Mode;
+use nix::sys::stat::{Mode, SFlag};
 use nom::types::CompleteByteSlice;
 use pathdiff::*;
+use serde_derive::*;
 
 use std::collections::hash_map::Entry as E;
 use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::path::{Path, PathBuf};
 use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::checkpoint::{Checkpoint, CHECKPOINT_INTERVAL};
+use crate::chunked_copy::{chunked_copy, RangeQueue};
+use crate::content_hash::{ContentHasher, HashAlgorithm};
+use crate::delta::{apply, apply_to, checksum, delta, DeltaOp, Signature};
 use crate::error::{ForkliftError, ForkliftResult};
 use crate::filesystem::*;
 use crate::filesystem_entry::Entry;
+use crate::id_map::IdMap;
 use crate::postgres_logger::{send_mess, LogMessage};
 use crate::progress_message::ProgressMessage;
+use crate::retry::RetryPolicy;
 use crate::tables::{current_time, ErrorType};
 
 /// default buffer size
 const BUFF_SIZE: u64 = 1024 * 1000;
+/// suffix of the hidden temporary files new file contents are written to
+const TEMP_SUFFIX: &str = ".forklift-tmp";
+/// suffix of the hidden files the checkpoints of copies into temporary files are saved to
+const CHECKPOINT_SUFFIX: &str = ".forklift-ckpt";
+/// temporary files not written to for this many seconds are left from an earlier run
+const STALE_TEMP_SECS: u64 = 60 * 60;
+/// smallest run of zeros left as a hole when copying a sparse file
+const HOLE_SIZE: usize = 4096;
+/// prefix of the SIDs Samba maps unix uids to
+const UNIX_USER_SID: &str = "S-1-22-1";
+/// prefix of the SIDs Samba maps unix gids to
+const UNIX_GROUP_SID: &str = "S-1-22-2";
+/// NT access mask equivalent of unix read permission
+const READ_MASK: u32 = 0x0012_0089;
+/// NT access mask equivalent of unix write permission
+const WRITE_MASK: u32 = 0x0012_0116;
+/// NT access mask equivalent of unix execute permission
+const EXECUTE_MASK: u32 = 0x0012_00a0;
+/// NT access mask equivalent of unix rwx permission
+const FULL_MASK: u32 = 0x001f_01ff;
 
 lazy_static! {
     /// singleton containing a map of named ID's to Sid's
@@ -42,22 +69,116 @@ lazy_static! {
 pub enum SyncOutcome {
     /// file/directory is up-to-date
     UpToDate,
-    /// copied a file,  send path, src, dest checksum, size
-    FileCopied(String, Vec<u8>, Vec<u8>, i64, NaiveDateTime),
+    /// copied a file,  send path, src, dest checksum, name of the checksum algorithm, size
+    FileCopied(String, Vec<u8>, Vec<u8>, String, i64, NaiveDateTime),
     /// updated a symlink
     SymlinkUpdated,
     /// created a symlink
     SymlinkCreated,
     /// symlink destination does not exist/is not in share
     SymlinkSkipped,
+    /// linked another name of an already synced hard linked file
+    HardLinkCreated,
+    /// created a fifo, device, or socket
+    SpecialFileCreated,
+    /// fifo, device, or socket could not be created in the destination
+    SpecialFileSkipped,
     /// updated the permissions (ACL or otherwise)
     PermissionsUpdated,
     /// copied a directory
     DirectoryCreated,
     /// updated a directory internal bytes
     DirectoryUpdated,
-    /// updated a file internal bytes, send path, src, dest checksum, size
-    ChecksumUpdated(String, Vec<u8>, Vec<u8>, i64, NaiveDateTime),
+    /// updated a file internal bytes, send path, src, dest checksum, name of the checksum
+    /// algorithm, size, bytes matched, bytes transferred
+    ChecksumUpdated(String, Vec<u8>, Vec<u8>, String, i64, NaiveDateTime, u64, u64),
+    /// removed a destination entry that is not in the source
+    Removed,
+    /// moved a destination entry that is not in the source into the quarantine directory
+    Quarantined,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+/// how sync_entry decides whether an existing destination file needs its contents compared
+pub enum ComparePolicy {
+    /// files with the same size and mtime are up to date
+    #[serde(rename = "size+mtime")]
+    SizeMtime,
+    /// always compare the contents of the files
+    #[serde(rename = "checksum")]
+    Checksum,
+    /// files with the same size are up to date
+    #[serde(rename = "size-only")]
+    SizeOnly,
+    /// files that already exist in the destination are never updated
+    #[serde(rename = "ignore-existing")]
+    IgnoreExisting,
+}
+
+impl Default for ComparePolicy {
+    fn default() -> Self {
+        ComparePolicy::SizeMtime
+    }
+}
+
+impl ComparePolicy {
+    /// true if an existing destination file is up to date without comparing its contents
+    pub fn is_unchanged(self, src: &Entry, dest: &Entry) -> ForkliftResult<bool> {
+        match self {
+            ComparePolicy::SizeMtime => {
+                Ok(!has_different_size(src, dest)? && !has_different_mtime(src, dest)?)
+            }
+            ComparePolicy::Checksum => Ok(false),
+            ComparePolicy::SizeOnly => Ok(!has_different_size(src, dest)?),
+            ComparePolicy::IgnoreExisting => Ok(true),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+/// what is done with destination entries that are not in the source
+pub enum DeletePolicy {
+    /// never remove them
+    #[serde(rename = "never")]
+    Never,
+    /// remove them while walking
+    #[serde(rename = "during")]
+    During,
+    /// remove them once the run synced every entry without errors
+    #[serde(rename = "after")]
+    After,
+    /// move them into a dated directory under QUARANTINE_DIR in the destination root
+    #[serde(rename = "quarantine")]
+    Quarantine,
+}
+
+impl Default for DeletePolicy {
+    fn default() -> Self {
+        DeletePolicy::During
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+/// settings of the sync that change how each entry is synced
+pub struct SyncOptions {
+    /// how existing destination files are checked for changes
+    pub compare: ComparePolicy,
+    /// algorithm used to checksum file contents
+    pub hash: HashAlgorithm,
+    /// write new file contents to a temporary file, then rename it over the destination
+    pub temp_files: bool,
+    /// files of at least this size are copied in ranges by all the workers of the run
+    pub chunked_copy_size: Option<u64>,
+    /// read the files written in a run again at its end, to check they match the source
+    pub verify: bool,
+    /// only plan what the sync would do, leaving the destination untouched
+    pub dry_run: bool,
+    /// what is done with destination entries that are not in the source
+    pub delete: DeletePolicy,
+    /// excluded destination entries not in the source are deleted like any other
+    pub delete_excluded: bool,
+    /// how entries are tried again after transient failures
+    pub retry: RetryPolicy,
 }
 
 #[derive(Clone)]
@@ -77,6 +198,53 @@ pub fn exist(path: &Path, context: &ProtocolContext) -> bool {
     context.stat(path).is_ok()
 }
 
+/// the hidden temporary file in the same directory that the new contents of path
+/// are written to
+pub fn temp_path(path: &Path) -> PathBuf {
+    hidden_path(path, TEMP_SUFFIX)
+}
+
+/// the hidden file in the same directory that the checkpoints of copying path into
+/// its temp_path are saved to
+pub fn checkpoint_path(path: &Path) -> PathBuf {
+    hidden_path(path, CHECKPOINT_SUFFIX)
+}
+
+/// helper for temp_path and checkpoint_path
+fn hidden_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// if path is a temp_path or checkpoint_path, the path of the file it belongs to
+pub fn temp_target(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?.as_bytes();
+    for suffix in &[TEMP_SUFFIX, CHECKPOINT_SUFFIX] {
+        let suffix = suffix.as_bytes();
+        if name.len() > suffix.len() + 1 && name.starts_with(b".") && name.ends_with(suffix) {
+            let target = OsStr::from_bytes(&name[1..name.len() - suffix.len()]);
+            return Some(path.with_file_name(target));
+        }
+    }
+    None
+}
+
+/// true if path is a temporary file (or checkpoint) that has not been written to for
+/// STALE_TEMP_SECS, so it was left by an earlier run that died, and not one being
+/// written right now
+pub fn is_stale_temp(path: &Path, context: &ProtocolContext) -> bool {
+    if temp_target(path).is_none() {
+        return false;
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    match context.stat(path) {
+        Ok(stat) => (stat.mtime().num_seconds() as u64) + STALE_TEMP_SECS < now,
+        Err(_) => false,
+    }
+}
+
 /// gets the relative path (the parts of the path in common) between base and comp
 pub fn get_rel_path(base_path: &Path, comp_path: &Path) -> ForkliftResult<PathBuf> {
     match diff_paths(&base_path, &comp_path) {
@@ -152,6 +320,7 @@ pub fn make_dir(
     dest_path: &Path,
     src_context: &ProtocolContext,
     dest_context: &ProtocolContext,
+    id_map: &IdMap,
     logs_send: &Sender<LogMessage>,
 ) -> ForkliftResult<SyncOutcome> {
     let outcome: SyncOutcome;
@@ -171,8 +340,14 @@ pub fn make_dir(
     let (src_entry, dest_entry) =
         (Entry::new(&src_path, src_context), Entry::new(&dest_path, dest_context));
     // make sure permissions match
-    let copy_outcome =
-        copy_permissions(&src_entry, &dest_entry, &src_context, &dest_context, logs_send)?;
+    let copy_outcome = copy_permissions(
+        &src_entry,
+        &dest_entry,
+        &src_context,
+        &dest_context,
+        id_map,
+        logs_send,
+    )?;
     debug!("Copy permissions successful");
     match (exists, copy_outcome) {
         (false, _) => outcome = SyncOutcome::DirectoryCreated,
@@ -192,6 +367,7 @@ pub fn make_dir_all(
     root_file_path: &Path,
     src_context: &ProtocolContext,
     dest_context: &ProtocolContext,
+    id_map: &IdMap,
     logs_send: &Sender<LogMessage>,
 ) -> ForkliftResult<()> {
     let (mut stack, mut src_stack) = (vec![], vec![]);
@@ -229,7 +405,7 @@ pub fn make_dir_all(
             }
         };
         if !exist(&path, dest_context) {
-            make_dir(srcpath, &path, src_context, dest_context, logs_send)?;
+            make_dir(srcpath, &path, src_context, dest_context, id_map, logs_send)?;
             debug!("made dir {:?}", path);
         }
     }
@@ -271,18 +447,72 @@ pub fn is_more_recent(src: &Entry, dest: &Entry) -> ForkliftResult<bool> {
     }
 }
 
+/// Check if the source and dest entries have different mtimes, to the second
+pub fn has_different_mtime(src: &Entry, dest: &Entry) -> ForkliftResult<bool> {
+    match (src.metadata(), dest.metadata()) {
+        (Some(src_stat), Some(dest_stat)) => {
+            Ok(src_stat.mtime().num_seconds() != dest_stat.mtime().num_seconds())
+        }
+        (None, _) => {
+            let err = format!("Source File {:?} does not exist", src.path());
+            Err(ForkliftError::FSError(err))
+        }
+        (_, None) => Ok(true),
+    }
+}
+
+/// set the access and modification times of the destination to those of the source,
+/// if they differ at the microsecond precision utimes can set.
+/// Returns true if the destination times were changed
+///
+/// @note symlinks are skipped, since utimes follows them
+pub fn copy_times(
+    src: &Entry,
+    dest_path: &Path,
+    dest_context: &ProtocolContext,
+) -> ForkliftResult<bool> {
+    let src_stat = match (src.is_link(), src.metadata()) {
+        (Some(false), Some(stat)) => stat,
+        (Some(true), _) => return Ok(false),
+        (..) => {
+            return Err(ForkliftError::FSError(format!("src {:?} does not exist", src.path())));
+        }
+    };
+    let dest_stat = match dest_context.stat(dest_path) {
+        Ok(stat) => stat,
+        Err(e) => {
+            let err = format!("Error {}, unable to stat {:?}", e, dest_path);
+            return Err(ForkliftError::FSError(err));
+        }
+    };
+    let (src_atime, src_mtime) = (src_stat.atime(), src_stat.mtime());
+    if src_atime.num_microseconds() == dest_stat.atime().num_microseconds()
+        && src_mtime.num_microseconds() == dest_stat.mtime().num_microseconds()
+    {
+        return Ok(false);
+    }
+    if let Err(e) = dest_context.utimes(dest_path, src_atime, src_mtime) {
+        let err = format!("Error {}, unable to set the times of {:?}", e, dest_path);
+        return Err(ForkliftError::FSError(err));
+    }
+    debug!("Set times of {:?}", dest_path);
+    Ok(true)
+}
+
 /// this functions checks whether or not the destination file has the same
 /// permission settings as the source file.
-/// @note this function ONLY checks the mode attribute (DOS or LINUX) this function
+/// @note this function ONLY checks the mode attribute (DOS or LINUX), and the
+/// owner and group (as mapped by id_map) between unix filesystems. this function
 /// DOES NOT check the external attributes (xattr)  of a file.  
 pub fn has_different_permissions(
     src: &Entry,
     dest: &Entry,
     src_context: &ProtocolContext,
     dest_context: &ProtocolContext,
+    id_map: &IdMap,
 ) -> ForkliftResult<bool> {
     //check file existence
-    let (src_mode, dest_mode) = match (src.metadata(), dest.metadata()) {
+    let (src_stat, dest_stat) = match (src.metadata(), dest.metadata()) {
         (None, _) => {
             return Err(ForkliftError::FSError("Source File does not exist".to_string()));
         }
@@ -290,15 +520,12 @@ pub fn has_different_permissions(
             debug!("Dest File does not exist");
             return Ok(true);
         }
-        (Some(src_stat), Some(dest_stat)) => (src_stat.mode(), dest_stat.mode()),
+        (Some(src_stat), Some(dest_stat)) => (src_stat, dest_stat),
     };
+    let (src_mode, dest_mode) = (src_stat.mode(), dest_stat.mode());
 
     match (src_context, dest_context) {
-        (ProtocolContext::Nfs(_), ProtocolContext::Nfs(_)) => {
-            trace!("src mode {:?}, dest mode {:?}", src_mode, dest_mode);
-            Ok(src_mode != dest_mode)
-        }
-        (ProtocolContext::Samba(ctx), ProtocolContext::Samba(dctx)) => {
+        (ProtocolContext::Samba(ctx, _), ProtocolContext::Samba(dctx, _)) => {
             let xattr = SmbcXAttr::DosAttr(SmbcDosAttr::Mode);
             let (err, suc) = ("get the dos mode failed", "dos mode retreived");
             let src_mod_values = get_xattr(src.path(), ctx, &xattr, err, suc)?;
@@ -306,8 +533,75 @@ pub fn has_different_permissions(
             trace!("src dos mode {:?}, dest dos mode {:?}", src_mod_values, dest_mod_values);
             Ok(src_mod_values != dest_mod_values)
         }
-        (..) => Err(ForkliftError::FSError("Filesystems do not match!".to_string())),
+        // Samba -> unix, compare against the translated mode
+        (ProtocolContext::Samba(..), _) => {
+            let translated = dos_to_unix_mode(src_mode);
+            trace!("translated src mode {:?}, dest mode {:?}", translated, dest_mode);
+            Ok(translated & 0o7777 != dest_mode & 0o7777)
+        }
+        // unix -> Samba, only the read-only DOS attribute can be compared
+        (_, ProtocolContext::Samba(..)) => {
+            Ok(is_dos_readonly(src_mode) != is_dos_readonly(dest_mode))
+        }
+        // Nfs, Local and Memory all carry unix stat modes and owners
+        (..) => {
+            trace!("src mode {:?}, dest mode {:?}", src_mode, dest_mode);
+            let owner = mapped_owner(&src_stat, id_map)?;
+            Ok(src_mode != dest_mode || has_different_owner(owner, dest))
+        }
+    }
+}
+
+/// get the destination (uid, gid) of a source stat, as given by the id map.
+/// None means the id is unmapped, and the destination id is left as it is
+pub fn mapped_owner(src_stat: &Stat, id_map: &IdMap) -> ForkliftResult<(Option<u32>, Option<u32>)> {
+    Ok((id_map.map_uid(src_stat.uid())?, id_map.map_gid(src_stat.gid())?))
+}
+
+/// check whether the destination has a different owner or group than the mapped
+/// source owner. A destination that does not exist has a different owner
+pub fn has_different_owner((uid, gid): (Option<u32>, Option<u32>), dest: &Entry) -> bool {
+    match dest.metadata() {
+        Some(dest_stat) => {
+            trace!(
+                "src owner {:?}:{:?}, dest owner {}:{}",
+                uid,
+                gid,
+                dest_stat.uid(),
+                dest_stat.gid()
+            );
+            (uid.is_some() && uid != Some(dest_stat.uid()))
+                || (gid.is_some() && gid != Some(dest_stat.gid()))
+        }
+        None => uid.is_some() || gid.is_some(),
+    }
+}
+
+/// change the owner and group of path to the mapped source owner (lchown if is_link).
+/// Only privileged users may give files away, so a refused change (EPERM) is returned
+/// like any other error, failing the entry
+fn change_owner(
+    path: &Path,
+    context: &ProtocolContext,
+    (uid, gid): (Option<u32>, Option<u32>),
+    is_link: bool,
+) -> ForkliftResult<()> {
+    // unmapped ids keep their current destination value
+    let (uid, gid) = match (uid, gid) {
+        (Some(uid), Some(gid)) => (uid, gid),
+        (None, None) => return Ok(()),
+        (..) => {
+            let stat = context.stat(path)?;
+            (uid.unwrap_or(stat.uid()), gid.unwrap_or(stat.gid()))
+        }
+    };
+    if is_link {
+        context.lchown(path, uid, gid)?;
+    } else {
+        context.chown(path, uid, gid)?;
     }
+    debug!("Chown of file {:?} to {}:{} ran", path, uid, gid);
+    Ok(())
 }
 
 /// create an empty vector to store the name of a symlink
@@ -325,21 +619,22 @@ fn make_target(size: i64, readmax: u64) -> ForkliftResult<Vec<u8>> {
     Ok(src_target)
 }
 
-/// read a symlink into a String
-fn read_link(path: &Path, nfs_context: &Nfs, size: i64) -> ForkliftResult<String> {
+/// read the target of a symlink, which does not have to be valid UTF-8
+pub fn read_link(path: &Path, context: &ProtocolContext, size: i64) -> ForkliftResult<PathBuf> {
     let mut src_target: Vec<u8> = make_target(size, BUFF_SIZE)?;
-    if let Err(e) = nfs_context.readlink(path, &mut src_target) {
+    if let Err(e) = context.readlink(path, &mut src_target) {
         let err = format!("Unable to read link at {:?}, {:?}", path, e);
         return Err(ForkliftError::FSError(err));
     }
-    let mut link = String::from_utf8(src_target)?;
-    link.pop();
-    Ok(link)
+    // the target is null terminated (or padded) within the buffer
+    let end = src_target.iter().position(|b| *b == 0).unwrap_or_else(|| src_target.len());
+    src_target.truncate(end);
+    Ok(PathBuf::from(OsString::from_vec(src_target)))
 }
 
 /// helper for copy_link; unlink a symlink in order to update it
-pub fn unlink_outdated_link(path: &Path, nfs_context: &Nfs) -> ForkliftResult<()> {
-    if let Err(e) = nfs_context.unlink(path) {
+pub fn unlink_outdated_link(path: &Path, context: &ProtocolContext) -> ForkliftResult<()> {
+    if let Err(e) = context.unlink(path) {
         return Err(ForkliftError::FSError(format!(
             "Could not remove {:?} while updating link, {}",
             path, e
@@ -350,21 +645,31 @@ pub fn unlink_outdated_link(path: &Path, nfs_context: &Nfs) -> ForkliftResult<()
 
 /// check if the destination symlink links to the same file as the source, copy if not
 ///
-/// @note Samba does not support symlinks, so copy_link will immediately return
-///  with an error
+/// @note Samba only supports symlinks as Minshall-French symlink files, so if they
+///  are disabled (SambaSymlinks::Skip) copy_link will immediately return with an
+///  error if the source is Samba, and skip the link if the destination is Samba
 pub fn copy_link(
     src: &Entry,
     dest: &Entry,
     src_context: &ProtocolContext,
     dest_context: &ProtocolContext,
+    id_map: &IdMap,
     logs_send: &Sender<LogMessage>,
 ) -> ForkliftResult<SyncOutcome> {
     //Check if correct Filesytem
-    let (src_nfs_context, dest_nfs_context) = match (src_context.clone(), dest_context.clone()) {
-        (ProtocolContext::Nfs(ctx), ProtocolContext::Nfs(dctx)) => (ctx, dctx),
-        (..) => {
-            return Err(ForkliftError::FSError("Samba does not support symlinks".to_string()));
+    match (src_context, dest_context) {
+        (ProtocolContext::Samba(_, SambaSymlinks::Skip), _) => {
+            return Err(ForkliftError::FSError("Samba symlinks are disabled".to_string()));
+        }
+        (_, ProtocolContext::Samba(_, SambaSymlinks::Skip)) => {
+            let mess = LogMessage::ErrorType(
+                ErrorType::FSError,
+                format!("Samba symlinks are disabled, skipping {:?}", dest.path()),
+            );
+            send_mess(mess, logs_send)?;
+            return Ok(SyncOutcome::SymlinkSkipped);
         }
+        (..) => (),
     };
     //Check if files exist and get size
     let (size, dest_size) = match (src.metadata(), dest.metadata()) {
@@ -375,14 +680,25 @@ pub fn copy_link(
         (Some(src_stat), Some(dest_stat)) => (src_stat.size() + 1, dest_stat.size() + 1),
     };
     let (src_path, dest_path) = (src.path(), dest.path());
-    let src_target = read_link(src_path, &src_nfs_context, size)?;
+    let src_target = read_link(src_path, src_context, size)?;
+    let owner = match (src_context, dest_context, src.metadata()) {
+        (_, _, None) => {
+            return Err(ForkliftError::FSError("Source File does not exist!".to_string()));
+        }
+        // Samba symlink files have no unix owner to copy
+        (ProtocolContext::Samba(..), _, _) | (_, ProtocolContext::Samba(..), _) => (None, None),
+        (_, _, Some(stat)) => mapped_owner(&stat, id_map)?,
+    };
     let mut outcome: SyncOutcome;
     match dest.is_link() {
         Some(true) => {
-            let dest_target = read_link(dest_path, &dest_nfs_context, dest_size)?;
+            let dest_target = read_link(dest_path, dest_context, dest_size)?;
             if dest_target != src_target {
-                unlink_outdated_link(dest_path, &dest_nfs_context)?;
+                unlink_outdated_link(dest_path, dest_context)?;
                 outcome = SyncOutcome::SymlinkUpdated;
+            } else if has_different_owner(owner, dest) {
+                change_owner(dest_path, dest_context, owner, true)?;
+                return Ok(SyncOutcome::PermissionsUpdated);
             } else {
                 return Ok(SyncOutcome::UpToDate);
             }
@@ -399,17 +715,127 @@ pub fn copy_link(
         }
     }
     //create/update symlink, if creation fails, skip
-    if let Err(e) = dest_nfs_context.symlink(Path::new(&src_target), dest_path) {
+    if let Err(e) = dest_context.symlink(&src_target, dest_path) {
         let mess = LogMessage::ErrorType(
             ErrorType::FSError,
             format!("Error {}, could not create link from {:?} to {:?}", e, dest_path, src_target),
         );
         send_mess(mess, logs_send)?;
         outcome = SyncOutcome::SymlinkSkipped;
+    } else {
+        change_owner(dest_path, dest_context, owner, true)?;
     }
     Ok(outcome)
 }
 
+/// helper for copy_special; log that the special file at path was skipped and why
+fn skip_special(
+    path: &Path,
+    reason: &str,
+    logs_send: &Sender<LogMessage>,
+) -> ForkliftResult<SyncOutcome> {
+    let reason = format!("{}, skipping special file {:?}", reason, path);
+    warn!("{}", reason);
+    send_mess(LogMessage::ErrorType(ErrorType::FSError, reason), logs_send)?;
+    Ok(SyncOutcome::SpecialFileSkipped)
+}
+
+/// check if the destination is the same kind of special file (fifo, device, or socket)
+/// as the source, (re)creating it if not
+///
+/// @note Samba does not support special files, so they are skipped if either side is
+///  Samba.  They are also skipped if the destination can not create them (ex. device
+///  files without root).  Every skipped file is logged
+pub fn copy_special(
+    src: &Entry,
+    dest: &Entry,
+    src_context: &ProtocolContext,
+    dest_context: &ProtocolContext,
+    logs_send: &Sender<LogMessage>,
+) -> ForkliftResult<SyncOutcome> {
+    let dest_path = dest.path();
+    let src_stat = match src.metadata() {
+        Some(stat) => stat,
+        None => {
+            return Err(ForkliftError::FSError("Source File does not exist!".to_string()));
+        }
+    };
+    match (src_context, dest_context) {
+        (ProtocolContext::Samba(..), _) | (_, ProtocolContext::Samba(..)) => {
+            return skip_special(dest_path, "Samba does not support special files", logs_send);
+        }
+        (..) => (),
+    };
+    let kind = SFlag::from_bits_truncate(src_stat.mode() & SFlag::S_IFMT.bits());
+    if let Some(dest_stat) = dest.metadata() {
+        let dest_kind = dest_stat.mode() & SFlag::S_IFMT.bits();
+        if dest_kind == kind.bits() && dest_stat.rdev() == src_stat.rdev() {
+            return Ok(SyncOutcome::UpToDate);
+        }
+        if let Some(true) = dest.is_dir() {
+            return Err(ForkliftError::FSError(format!(
+                "Refusing to replace existing directory {:?} by special file",
+                dest_path
+            )));
+        }
+        if let Err(e) = dest_context.unlink(dest_path) {
+            let err = format!("Error {}, could not unlink outdated {:?}", e, dest_path);
+            return Err(ForkliftError::FSError(err));
+        }
+    }
+    let mode = Mode::from_bits_truncate(src_stat.mode() & 0o7777);
+    let created = if kind == SFlag::S_IFIFO {
+        dest_context.mkfifo(dest_path, mode)
+    } else {
+        dest_context.mknod(dest_path, kind, mode, src_stat.rdev())
+    };
+    if let Err(e) = created {
+        return skip_special(dest_path, &format!("Error {}, could not create it", e), logs_send);
+    }
+    debug!("Created special file {:?}", dest_path);
+    Ok(SyncOutcome::SpecialFileCreated)
+}
+
+/// get the (device, inode) of a regular file with more than one name (hard link),
+/// or None if the stat is not one of a hard linked file
+pub fn hard_link_key(stat: &Stat) -> Option<(u64, u64)> {
+    let is_file = stat.mode() & SFlag::S_IFMT.bits() == SFlag::S_IFREG.bits();
+    if is_file && stat.nlink() > 1 {
+        Some((stat.dev(), stat.ino()))
+    } else {
+        None
+    }
+}
+
+/// make dest_path a hard link to target, the destination of another name of the same
+/// source file, replacing any other file at dest_path
+pub fn make_hard_link(
+    target: &Path,
+    dest_path: &Path,
+    dest_context: &ProtocolContext,
+) -> ForkliftResult<SyncOutcome> {
+    let target_stat = dest_context.stat(target)?;
+    if let Ok(dest_stat) = dest_context.stat(dest_path) {
+        if dest_stat.mode() & SFlag::S_IFMT.bits() == SFlag::S_IFDIR.bits() {
+            //Not safe to delete
+            return Err(ForkliftError::FSError(format!(
+                "Refusing to replace existing directory {:?} by hard link",
+                dest_path
+            )));
+        }
+        if (dest_stat.dev(), dest_stat.ino()) == (target_stat.dev(), target_stat.ino()) {
+            return Ok(SyncOutcome::UpToDate);
+        }
+        dest_context.unlink(dest_path)?;
+    }
+    if let Err(e) = dest_context.link(target, dest_path) {
+        let err = format!("Error {}, could not link {:?} to {:?}", e, dest_path, target);
+        return Err(ForkliftError::FSError(err));
+    }
+    debug!("Linked {:?} to {:?}", dest_path, target);
+    Ok(SyncOutcome::HardLinkCreated)
+}
+
 /// read as much data from a file from the offset as possible in one pass
 ///
 /// @note           while this function will attempt to read BUFF_SIZE
@@ -417,7 +843,7 @@ pub fn copy_link(
 ///                 still possible that the actual number of bytes read is
 ///                 smaller.  Be sure to check the length of the returned
 ///                 vector for the true number of bytes read
-fn read_chunk(path: &Path, file: &FileType, offset: u64) -> ForkliftResult<Vec<u8>> {
+pub fn read_chunk(path: &Path, file: &FileType, offset: u64) -> ForkliftResult<Vec<u8>> {
     match file.read(BUFF_SIZE, offset) {
         Ok(buf) => Ok(buf),
         Err(e) => {
@@ -430,7 +856,7 @@ fn read_chunk(path: &Path, file: &FileType, offset: u64) -> ForkliftResult<Vec<u
 /// open the file at path in context.
 ///
 /// @note since mode is never used, we can set mode to be anything
-fn open_file(
+pub fn open_file(
     path: &Path,
     context: &ProtocolContext,
     flags: OFlag,
@@ -451,7 +877,7 @@ fn open_file(
 ///                 big to write, so we can check the num written
 ///                 against the size of the buffer to see if we
 ///                 need to write again
-fn write_file(path: &Path, file: &FileType, buffer: &[u8], offset: u64) -> ForkliftResult<u64> {
+pub fn write_file(path: &Path, file: &FileType, buffer: &[u8], offset: u64) -> ForkliftResult<u64> {
     match file.write(buffer, offset) {
         Ok(n) => Ok(n),
         Err(e) => {
@@ -461,7 +887,7 @@ fn write_file(path: &Path, file: &FileType, buffer: &[u8], offset: u64) -> Forkl
     }
 }
 /// helper for checksum copy; creates a new file at path
-fn file_create(path: &Path, context: &ProtocolContext, err: &str) -> ForkliftResult<FileType> {
+pub fn file_create(path: &Path, context: &ProtocolContext, err: &str) -> ForkliftResult<FileType> {
     match context.create(&path, OFlag::O_CREAT, Mode::S_IRWXU | Mode::S_IRWXO | Mode::S_IRWXG) {
         Ok(f) => Ok(f),
         Err(e) => Err(ForkliftError::FSError(format!("Error {}, {}", e, err))),
@@ -469,18 +895,141 @@ fn file_create(path: &Path, context: &ProtocolContext, err: &str) -> ForkliftRes
 }
 
 /// helper for checksum copy; hash a buffer of bytes to a checksum vec of bytes
-fn hash(buf: &[u8], hasher: &mut MeowHasher) -> Vec<u8> {
-    hasher.input(buf);
-    hasher.result_reset().as_slice().to_vec()
+pub fn hash(buf: &[u8], hasher: &mut dyn ContentHasher) -> Vec<u8> {
+    hasher.update(buf);
+    hasher.finish_reset()
+}
+
+/// helper for checksum copy; true if buf is all zeros
+fn is_zero(buf: &[u8]) -> bool {
+    buf.iter().all(|&b| b == 0)
+}
+
+/// helper for checksum copy; true if the stat's allocated blocks do not cover its size,
+/// so the file probably has holes
+pub fn is_sparse(stat: &Stat) -> bool {
+    stat.blocks() * 512 < stat.size()
+}
+
+/// helper for checksum copy; write src_buf to the file at offset, leaving out the
+/// HOLE_SIZE blocks that are zero in both src_buf and dest_buf (the current contents
+/// of the file), so that they stay holes.  Returns the number of bytes left out
+fn write_sparse(
+    path: &Path,
+    file: &FileType,
+    src_buf: &[u8],
+    dest_buf: &[u8],
+    offset: u64,
+) -> ForkliftResult<u64> {
+    let (mut holes, mut start) = (0, 0);
+    for (i, block) in src_buf.chunks(HOLE_SIZE).enumerate() {
+        let begin = i * HOLE_SIZE;
+        let end = (begin + block.len()).min(dest_buf.len());
+        if is_zero(block) && is_zero(dest_buf.get(begin..end).unwrap_or(&[])) {
+            if begin > start {
+                write_file(path, file, &src_buf[start..begin], offset + start as u64)?;
+            }
+            holes += block.len() as u64;
+            start = begin + block.len();
+        }
+    }
+    if start < src_buf.len() {
+        write_file(path, file, &src_buf[start..], offset + start as u64)?;
+    }
+    Ok(holes)
+}
+
+/// helper for checksum copy; make sure the file is all zeros from start to end
+/// without writing to the holes it already has.  Returns true if the file was changed
+fn zero_range(path: &Path, file: &FileType, start: u64, end: u64) -> ForkliftResult<bool> {
+    let mut changed = false;
+    let mut offset = match file.seek_data(start)? {
+        Some(data) => data,
+        None => return Ok(false),
+    };
+    while offset < end {
+        let hole = file.seek_hole(offset)?.min(end);
+        while offset < hole {
+            let mut buf = read_chunk(path, file, offset)?;
+            buf.truncate((hole - offset) as usize);
+            if buf.is_empty() {
+                return Ok(changed);
+            }
+            if !is_zero(&buf) {
+                write_file(path, file, &vec![0; buf.len()], offset)?;
+                changed = true;
+            }
+            offset += buf.len() as u64;
+        }
+        offset = match file.seek_data(offset)? {
+            Some(data) => data,
+            None => break,
+        };
+    }
+    Ok(changed)
+}
+
+/// helper for checksum copy; add len zeros to the hasher, for holes that are never read
+fn hash_zeros(hasher: &mut dyn ContentHasher, len: u64) {
+    let zeros = vec![0; len.min(BUFF_SIZE) as usize];
+    let mut left = len;
+    while left > 0 {
+        let num = left.min(BUFF_SIZE);
+        hasher.update(&zeros[..num as usize]);
+        left -= num;
+    }
+}
+/// helper for checksum copy; if the checkpoint at checkpoint_path was saved while copying
+/// the current version of the source into dest, hash the part of dest that it covers.
+/// Returns the offset to continue from and a hasher that has hashed everything before it,
+/// or None if there is no checkpoint or dest does not match it
+fn resume_copy(
+    checkpoint_path: &Path,
+    src_stat: &Stat,
+    (dest_path, dest_file, dest_context): (&Path, &FileType, &ProtocolContext),
+    algorithm: HashAlgorithm,
+) -> ForkliftResult<Option<(u64, Box<dyn ContentHasher>)>> {
+    let checkpoint = match Checkpoint::load(checkpoint_path, dest_context) {
+        Some(checkpoint) if checkpoint.is_for(src_stat, algorithm) => checkpoint,
+        _ => return Ok(None),
+    };
+    let (mut prefix, mut offset) = (algorithm.hasher(), 0);
+    while offset < checkpoint.offset() {
+        let mut buf = read_chunk(dest_path, dest_file, offset)?;
+        if buf.is_empty() {
+            return Ok(None);
+        }
+        buf.truncate((checkpoint.offset() - offset).min(buf.len() as u64) as usize);
+        prefix.update(&buf);
+        offset += buf.len() as u64;
+    }
+    if prefix.box_clone().finish_reset() != checkpoint.prefix_hash() {
+        return Ok(None);
+    }
+    Ok(Some((offset, prefix)))
 }
 
-/// helper for checksum copy; trucate a buffer to the number written and append to total
-fn update_buffer(buf: &mut Vec<u8>, total_buf: &mut Vec<u8>, num_written: u64) {
-    buf.truncate(num_written as usize);
-    total_buf.append(buf);
+/// helper for checksum copy; save a checkpoint of the first offset bytes of the copy,
+/// if the source and destination checksums of them match
+fn save_checkpoint(
+    (checkpoint_path, dest_context): (&Path, &ProtocolContext),
+    (src_stat, algorithm): (&Stat, HashAlgorithm),
+    (src_hasher, dest_hasher): (&dyn ContentHasher, &dyn ContentHasher),
+    offset: u64,
+) -> ForkliftResult<()> {
+    let (src_check, dest_check) =
+        (src_hasher.box_clone().finish_reset(), dest_hasher.box_clone().finish_reset());
+    if src_check != dest_check {
+        // the copy will fail its checksum, so there is nothing to continue from
+        return Ok(());
+    }
+    trace!("Checkpoint of {:?} at offset {}", checkpoint_path, offset);
+    Checkpoint::new(src_stat, algorithm, offset, src_check)
+        .save(checkpoint_path, dest_context)
 }
+
 /// Send progress and log errors
-fn send_progress(
+pub fn send_progress(
     progress: ProgressMessage,
     progress_send: &Sender<ProgressMessage>,
     logs_send: &Sender<LogMessage>,
@@ -495,59 +1044,112 @@ fn send_progress(
     Ok(())
 }
 /// checksums a destination file, copying over the data from the src file in the chunks
-/// where checksum fails. is_copy is used to determine if file copy or checksum copy
+/// where checksum fails. is_copy is used to determine if file copy or checksum copy,
+/// algorithm is the hash used for the checksums.  With a checkpoint path, the copy is
+/// continued from the checkpoint saved there, and saves one every CHECKPOINT_INTERVAL
 pub fn checksum_copy(
     src: &Entry,
     dest: &Entry,
     src_context: &ProtocolContext,
     dest_context: &ProtocolContext,
-    is_copy: bool,
+    (is_copy, algorithm, checkpoint): (bool, HashAlgorithm, Option<&Path>),
     progress_send: &Sender<ProgressMessage>,
     logs_send: &Sender<LogMessage>,
 ) -> ForkliftResult<SyncOutcome> {
     let (src_path, dest_path) = (src.path(), dest.path());
-    let size = match src.metadata() {
-        Some(m) => m.size(),
+    let stat = match src.metadata() {
+        Some(m) => m,
         None => {
             return Err(ForkliftError::FSError(format!("Source file {:?} should exist!", src_path)));
         }
     };
+    let (size, sparse) = (stat.size(), is_sparse(&stat));
     // open src and dest files
     let src_err = format!("Could not open {:?} for reading", src_path);
     let dest_err = format!("could not open {:?} for writing", dest_path);
     let src_file = open_file(src_path, src_context, OFlag::O_RDONLY, &src_err)?;
-    if is_copy {
+    // creating the file truncates it on Samba, which would lose what a checkpointed copy
+    // wrote; opening it with O_CREAT also creates it, and resume_copy truncates it if the
+    // checkpoint does not hold
+    if is_copy && checkpoint.is_none() {
         file_create(&dest_path, dest_context, &dest_err)?;
     }
     let dest_file = open_file(dest_path, dest_context, OFlag::O_CREAT | OFlag::O_RDWR, &dest_err)?;
 
     //loop until end, count the number of times we needed to update the file
-    let (mut src_total, mut dest_total): (Vec<u8>, Vec<u8>) = (vec![], vec![]);
-    let (mut offset, mut counter) = (0, 0);
-    let mut hasher = MeowHasher::new();
+    //the whole file checksums are kept as they go, so no more than a chunk is in memory
+    let (mut src_hasher, mut dest_hasher) = (algorithm.hasher(), algorithm.hasher());
+    let (mut offset, mut counter, mut holes) = (0, 0, 0);
+    let (mut matched, mut transferred) = (0, 0);
+    let mut hasher = algorithm.hasher();
     let path = src_path.to_string_lossy().to_string();
+    if let Some(checkpoint) = checkpoint {
+        match resume_copy(checkpoint, &stat, (dest_path, &dest_file, dest_context), algorithm)? {
+            Some((resume_offset, prefix)) => {
+                debug!("Continuing copy of {:?} from offset {}", src_path, resume_offset);
+                src_hasher = prefix.box_clone();
+                dest_hasher = prefix;
+                offset = resume_offset;
+                counter += 1;
+            }
+            // without a checkpoint, what is there is not known to match, so start over
+            None => dest_file.truncate(0)?,
+        }
+    }
+    let mut saved = offset;
+    if sparse && dest_file.fstat()?.size() != size {
+        // set the size first, so that everything not written stays a hole
+        dest_file.truncate(size as u64)?;
+        counter += 1;
+    }
     loop {
-        let mut num_written = 0;
+        if sparse {
+            // skip over the holes of the source, only zeroing the destination where needed
+            let data = src_file.seek_data(offset)?.unwrap_or(size as u64).max(offset);
+            if data > offset {
+                if zero_range(dest_path, &dest_file, offset, data)? {
+                    counter += 1;
+                }
+                hash_zeros(src_hasher.as_mut(), data - offset);
+                hash_zeros(dest_hasher.as_mut(), data - offset);
+                holes += data - offset;
+                offset = data;
+            }
+        }
         let mut src_buf = read_chunk(src_path, &src_file, offset)?;
+        if src_buf.is_empty() {
+            break;
+        }
         let mut dest_buf = read_chunk(dest_path, &dest_file, offset)?;
-        let (hash_src, hash_dest) = (hash(&src_buf, &mut hasher), hash(&dest_buf, &mut hasher));
-        if hash_src != hash_dest {
-            if src_buf.len() < dest_buf.len() {
-                dest_file.truncate(src_buf.len() as u64)?;
-            }
+        if hash(&src_buf, hasher.as_mut()) != hash(&dest_buf, hasher.as_mut()) {
             //write src_buf -> dest
-            num_written = write_file(dest_path, &dest_file, &src_buf, offset)?;
+            let num_written = if sparse {
+                holes += write_sparse(dest_path, &dest_file, &src_buf, &dest_buf, offset)?;
+                src_buf.len() as u64
+            } else {
+                write_file(dest_path, &dest_file, &src_buf, offset)?
+            };
+            if num_written == 0 {
+                let err = format!("Could not write to {:?} at offset {}", dest_path, offset);
+                return Err(ForkliftError::FSError(err));
+            }
             counter += 1;
+            transferred += num_written;
+            src_buf.truncate(num_written as usize);
             dest_buf = read_chunk(dest_path, &dest_file, offset)?;
-        }
-        //update offset, add bytes to file_bufs and check if num_written > 0
-        if num_written > 0 {
-            update_buffer(&mut src_buf, &mut src_total, num_written);
-            update_buffer(&mut dest_buf, &mut dest_total, num_written);
-            offset += num_written - 1;
         } else {
-            update_buffer(&mut src_buf, &mut src_total, num_written);
-            offset += src_buf.len() as u64;
+            matched += src_buf.len() as u64;
+        }
+        dest_buf.truncate(src_buf.len());
+        src_hasher.update(&src_buf);
+        dest_hasher.update(&dest_buf);
+        offset += src_buf.len() as u64;
+        if let Some(checkpoint) = checkpoint {
+            if offset >= saved + CHECKPOINT_INTERVAL {
+                let hashers = (src_hasher.as_ref(), dest_hasher.as_ref());
+                save_checkpoint((checkpoint, dest_context), (&stat, algorithm), hashers, offset)?;
+                saved = offset;
+            }
         }
         //send progress
         let progress = ProgressMessage::CheckSyncing {
@@ -556,11 +1158,13 @@ pub fn checksum_copy(
             done: offset as usize,
         };
         send_progress(progress, progress_send, logs_send)?;
-        if src_buf.is_empty() {
-            break;
-        }
     }
-    let (src_check, dest_check) = (hash(&src_total, &mut hasher), hash(&dest_total, &mut hasher));
+    // the destination may still have data past the end of the source
+    if dest_file.fstat()?.size() as u64 != offset {
+        dest_file.truncate(offset)?;
+        counter += 1;
+    }
+    let (src_check, dest_check) = (src_hasher.finish_reset(), dest_hasher.finish_reset());
     if src_check != dest_check {
         let mess = LogMessage::Error(ForkliftError::ChecksumError(format!(
             "{} has source checksum {:?}, destination checksum {:?}",
@@ -571,24 +1175,110 @@ pub fn checksum_copy(
     if counter == 0 {
         return Ok(SyncOutcome::UpToDate);
     }
+    if holes > 0 {
+        send_progress(ProgressMessage::HolesSkipped(holes), progress_send, logs_send)?;
+    }
+    let name = algorithm.name().to_string();
     if is_copy {
-        Ok(SyncOutcome::FileCopied(path, src_check, dest_check, size, current_time()))
+        Ok(SyncOutcome::FileCopied(path, src_check, dest_check, name, size, current_time()))
+    } else {
+        Ok(SyncOutcome::ChecksumUpdated(
+            path,
+            src_check,
+            dest_check,
+            name,
+            size,
+            current_time(),
+            matched,
+            transferred,
+        ))
+    }
+}
+
+/// updates an existing destination file from the src file, finding the blocks of the
+/// destination file anywhere in the source so that only the data that is new gets written.
+/// With options.temp_files, the new file is written to the temp_path of dest instead
+pub fn delta_copy(
+    src: &Entry,
+    dest: &Entry,
+    src_context: &ProtocolContext,
+    dest_context: &ProtocolContext,
+    options: SyncOptions,
+    progress_send: &Sender<ProgressMessage>,
+    logs_send: &Sender<LogMessage>,
+) -> ForkliftResult<SyncOutcome> {
+    let (src_path, dest_path) = (src.path(), dest.path());
+    let size = match src.metadata() {
+        Some(m) => m.size(),
+        None => {
+            return Err(ForkliftError::FSError(format!("Source file {:?} should exist!", src_path)));
+        }
+    };
+    let src_err = format!("Could not open {:?} for reading", src_path);
+    let dest_err = format!("could not open {:?} for writing", dest_path);
+    let src_file = open_file(src_path, src_context, OFlag::O_RDONLY, &src_err)?;
+    let dest_file = open_file(dest_path, dest_context, OFlag::O_RDWR, &dest_err)?;
+    let (path, algorithm) = (src_path.to_string_lossy().to_string(), options.hash);
+    let err = |e: ForkliftError| {
+        let err = format!("Error {}, Could not update {:?} from {:?}", e, dest_path, src_path);
+        ForkliftError::FSError(err)
+    };
+    let signature = Signature::new(&dest_file, algorithm).map_err(err)?;
+    let (ops, src_check) = delta(&src_file, &signature, algorithm, |done| {
+        let progress = ProgressMessage::CheckSyncing {
+            description: path.clone(),
+            size: size as usize,
+            done: done as usize,
+        };
+        send_progress(progress, progress_send, logs_send)
+    })
+    .map_err(err)?;
+    if signature.size() == size as u64 && ops.iter().all(DeltaOp::in_place) {
+        return Ok(SyncOutcome::UpToDate);
+    }
+    let (bytes, dest_check) = if options.temp_files {
+        let temp = temp_path(dest_path);
+        let temp_file = file_create(&temp, dest_context, &dest_err)?;
+        let bytes = apply_to(&ops, &src_file, &dest_file, &temp_file).map_err(err)?;
+        (bytes, checksum(&temp_file, algorithm).map_err(err)?)
     } else {
-        Ok(SyncOutcome::ChecksumUpdated(path, src_check, dest_check, size, current_time()))
+        let bytes = apply(&ops, &src_file, &dest_file, size as u64).map_err(err)?;
+        (bytes, checksum(&dest_file, algorithm).map_err(err)?)
+    };
+    let (matched, transferred) = bytes;
+    if src_check != dest_check {
+        let mess = LogMessage::Error(ForkliftError::ChecksumError(format!(
+            "{} has source checksum {:?}, destination checksum {:?}",
+            path, src_check, dest_check
+        )));
+        send_mess(mess, logs_send)?
     }
+    Ok(SyncOutcome::ChecksumUpdated(
+        path,
+        src_check,
+        dest_check,
+        algorithm.name().to_string(),
+        size,
+        current_time(),
+        matched,
+        transferred,
+    ))
 }
 
 /// syncs the src and dest files.  It also sends the current progress
 /// of the rsync of the entry.
 ///
-/// @note                   no destination => FileCopied, diff size => File Copied
-///                         diff srctime more recent => File Copied
-///                         Otherwise, checksum copy
+/// @note                   no destination => FileCopied, unchanged according to
+///                         compare => UpToDate, without opening the files
+///                         diff size => File Copied, diff srctime more recent => File Copied
+///                         Otherwise, checksum copy.  Destination files of at least
+///                         BUFF_SIZE bytes are updated with a delta copy instead
 pub fn sync_entry(
     src: &Entry,
     dest: &Entry,
     src_context: &ProtocolContext,
     dest_context: &ProtocolContext,
+    (id_map, options, ranges): (&IdMap, SyncOptions, Option<&RangeQueue>),
     progress_send: &Sender<ProgressMessage>,
     logs_send: &Sender<LogMessage>,
 ) -> ForkliftResult<SyncOutcome> {
@@ -597,7 +1287,7 @@ pub fn sync_entry(
     match src.is_link() {
         Some(true) => {
             trace!("Is link!");
-            return copy_link(src, dest, src_context, dest_context, logs_send);
+            return copy_link(src, dest, src_context, dest_context, id_map, logs_send);
         }
         Some(false) => (),
         None => {
@@ -608,7 +1298,8 @@ pub fn sync_entry(
     match src.is_dir() {
         Some(true) => {
             trace!("Is directory!");
-            return make_dir(src.path(), dest.path(), src_context, dest_context, logs_send);
+            let (src_path, dest_path) = (src.path(), dest.path());
+            return make_dir(src_path, dest_path, src_context, dest_context, id_map, logs_send);
         }
         Some(false) => (),
         None => {
@@ -616,23 +1307,144 @@ pub fn sync_entry(
             return Err(ForkliftError::FSError(err));
         }
     }
+    if let Some(stat) = src.metadata() {
+        if GenericFileType::from_mode(stat.mode()).is_special() {
+            trace!("Is special file!");
+            return copy_special(src, dest, src_context, dest_context, logs_send);
+        }
+    }
     //check if size different or if src more recent than dest
-    match (dest.metadata(), has_different_size(src, dest), is_more_recent(src, dest)) {
-        (None, _, _) => {
-            debug!("Destination does not exist yet!");
-            checksum_copy(src, dest, src_context, dest_context, true, progress_send, logs_send)
-        }
-        (Some(_), Ok(size_dif), Ok(recent)) => {
-            debug!("Is different!!! size {}  recent {}", size_dif, recent);
-            if size_dif || recent {
-                checksum_copy(src, dest, src_context, dest_context, true, progress_send, logs_send)
-            } else {
-                checksum_copy(src, dest, src_context, dest_context, false, progress_send, logs_send)
+    let (size_dif, recent) =
+        match (dest.metadata(), has_different_size(src, dest), is_more_recent(src, dest)) {
+            (None, _, _) => {
+                debug!("Destination does not exist yet!");
+                (true, true)
+            }
+            (Some(_), Ok(_), Ok(_)) if options.compare.is_unchanged(src, dest)? => {
+                trace!("Unchanged according to {:?}", options.compare);
+                return Ok(SyncOutcome::UpToDate);
+            }
+            (Some(_), Ok(size_dif), Ok(recent)) => {
+                debug!("Is different!!! size {}  recent {}", size_dif, recent);
+                (size_dif, recent)
+            }
+            (_, Err(e), _) => return Err(e),
+            (_, _, Err(e)) => return Err(e),
+        };
+    let (src_ctx, dest_ctx) = (src_context, dest_context);
+    let copy = (size_dif || recent, options.hash, None);
+    let sparse = src.metadata().map_or(false, |m| is_sparse(&m));
+    let large = dest.metadata().map_or(false, |m| m.size() as u64 >= BUFF_SIZE);
+    // files of at least chunked_copy_size are split into ranges that every worker copies
+    let ranges = match (src.metadata(), options.chunked_copy_size) {
+        (Some(stat), Some(min)) if !sparse && stat.size() as u64 >= min => ranges,
+        (..) => None,
+    };
+    if !options.temp_files {
+        return match ranges {
+            Some(ranges) => {
+                let (contexts, copy) = ((src_ctx, dest_ctx), (copy.0, options.hash));
+                chunked_copy(src, dest, contexts, copy, ranges, progress_send, logs_send)
+            }
+            None if large && !sparse => {
+                delta_copy(src, dest, src_ctx, dest_ctx, options, progress_send, logs_send)
+            }
+            None => checksum_copy(src, dest, src_ctx, dest_ctx, copy, progress_send, logs_send),
+        };
+    }
+    let temps = [temp_path(dest.path()), checkpoint_path(dest.path())];
+    let temp_entry = Entry::new(&temps[0], dest_context);
+    let outcome = match ranges {
+        Some(ranges) => {
+            let (contexts, copy) = ((src_ctx, dest_ctx), (true, options.hash));
+            chunked_copy(src, &temp_entry, contexts, copy, ranges, progress_send, logs_send)?
+        }
+        None if large && !sparse => {
+            // the delta transfer is not checkpointed, so an interrupted one starts over
+            remove_temps(&temps, dest_context)?;
+            delta_copy(src, dest, src_ctx, dest_ctx, options, progress_send, logs_send)?
+        }
+        None if dest.metadata().is_some()
+            && !copy.0
+            && same_contents(src.path(), dest.path(), src_ctx, dest_ctx, options.hash)? =>
+        {
+            remove_temps(&temps, dest_context)?;
+            SyncOutcome::UpToDate
+        }
+        None => {
+            // a copy into the temporary file left by an earlier run continues from its
+            // checkpoint
+            let copy = (true, options.hash, Some(temps[1].as_path()));
+            checksum_copy(src, &temp_entry, src_ctx, dest_ctx, copy, progress_send, logs_send)?
+        }
+    };
+    remove_temps(&temps[1..], dest_context)?;
+    replace_with_temp(outcome, &temps[0], dest.path(), dest_context)
+}
+
+/// helper for sync_entry; remove the temporary files that exist, left by an earlier run
+/// that died while writing them
+fn remove_temps(temps: &[PathBuf], dest_context: &ProtocolContext) -> ForkliftResult<()> {
+    for temp in temps {
+        if exist(temp, dest_context) {
+            dest_context.unlink(temp)?;
+        }
+    }
+    Ok(())
+}
+
+/// helper for sync_entry; true if the src and dest files have the same checksum
+pub fn same_contents(
+    src_path: &Path,
+    dest_path: &Path,
+    src_context: &ProtocolContext,
+    dest_context: &ProtocolContext,
+    algorithm: HashAlgorithm,
+) -> ForkliftResult<bool> {
+    let src_err = format!("Could not open {:?} for reading", src_path);
+    let dest_err = format!("Could not open {:?} for reading", dest_path);
+    let src_file = open_file(src_path, src_context, OFlag::O_RDONLY, &src_err)?;
+    let dest_file = open_file(dest_path, dest_context, OFlag::O_RDONLY, &dest_err)?;
+    Ok(checksum(&src_file, algorithm)? == checksum(&dest_file, algorithm)?)
+}
+
+/// helper for sync_entry; once the new contents of dest_path were written to temp,
+/// rename temp over dest_path if its checksum matches the source.  Otherwise temp is
+/// removed, leaving dest_path as it was
+///
+/// @note   Samba servers may refuse to rename over an existing file, in which case
+///         dest_path is removed first, so the replacement is not atomic there
+fn replace_with_temp(
+    outcome: SyncOutcome,
+    temp: &Path,
+    dest_path: &Path,
+    dest_context: &ProtocolContext,
+) -> ForkliftResult<SyncOutcome> {
+    match &outcome {
+        SyncOutcome::FileCopied(path, src_check, dest_check, ..)
+        | SyncOutcome::ChecksumUpdated(path, src_check, dest_check, ..) => {
+            if src_check != dest_check {
+                dest_context.unlink(temp)?;
+                return Err(ForkliftError::ChecksumError(format!(
+                    "{} has source checksum {:?}, destination checksum {:?}, not replacing {:?}",
+                    path, src_check, dest_check, dest_path
+                )));
             }
         }
-        (_, Err(e), _) => Err(e),
-        (_, _, Err(e)) => Err(e),
+        // an empty file is up to date as soon as it is created
+        _ if exist(temp, dest_context) => (),
+        _ => return Ok(outcome),
     }
+    match (dest_context.rename(temp, dest_path), dest_context) {
+        (Ok(()), _) => (),
+        (Err(_), ProtocolContext::Samba(..)) => {
+            dest_context.unlink(dest_path)?;
+            dest_context.rename(temp, dest_path)?;
+        }
+        (Err(e), _) => return Err(e),
+    }
+    debug!("Replaced {:?} with {:?}", dest_path, temp);
+    Ok(outcome)
 }
 
 /// given a source sid, check through a list of destination acls for the acl
@@ -841,13 +1653,15 @@ fn copy_acl(
 }
 
 /// map named acl Sids from a source file to their destinarion numeric Sids
-/// then replace the incorrect destination acls with the source acls
+/// (translating the names through id_map first), then replace the incorrect
+/// destination acls with the source acls
 pub fn map_names_and_copy(
     dest_path: &Path,
     dest_ctx: &Smbc,
     src_acls: &[SmbcAclValue],
     src_acls_plus: &[SmbcAclValue],
     dest_acls: &mut Vec<SmbcAclValue>,
+    id_map: &IdMap,
     logs_send: &Sender<LogMessage>,
 ) -> ForkliftResult<bool> {
     let mut map = match SID_NAME_MAP.lock() {
@@ -867,16 +1681,19 @@ pub fn map_names_and_copy(
                 if sid == "\\Creator Owner" || sid == "\\Everyone" || sid == "\\Creator Group" {
                     creator_reached = true;
                 }
-                //check if sid is mapped, add to map if not already in
-                let mapped = match map.entry(sid.clone()) {
-                    E::Occupied(o) => o.into_mut(),
-                    E::Vacant(v) => v.insert(map_name(dest_path, dest_ctx, &sid)?),
-                };
-                trace!("Sid: {}, mapped: {}", sid, mapped);
-                //if reached "CREATOR" sids, ignore the rest
-                let temp_ace = (mapped.clone(), atype, aflags, mask);
-                if !creator_reached {
-                    copied = copy_acl(dest_path, dest_ctx, temp_ace, dest_acls)?;
+                //translate the name into the destination domain, unmapped names are skipped
+                if let Some(dest_name) = id_map.map_sid(&sid)? {
+                    //check if sid is mapped, add to map if not already in
+                    let mapped = match map.entry(dest_name.clone()) {
+                        E::Occupied(o) => o.into_mut(),
+                        E::Vacant(v) => v.insert(map_name(dest_path, dest_ctx, &dest_name)?),
+                    };
+                    trace!("Sid: {}, mapped: {}", dest_name, mapped);
+                    //if reached "CREATOR" sids, ignore the rest
+                    let temp_ace = (mapped.clone(), atype, aflags, mask);
+                    if !creator_reached {
+                        copied = copy_acl(dest_path, dest_ctx, temp_ace, dest_acls)?;
+                    }
                 }
                 creator_reached = false;
             }
@@ -958,6 +1775,134 @@ pub fn get_acl_list(
     }
 }
 
+/// check if a mode translates to the read-only DOS attribute
+/// (Samba sets read-only exactly when the owner write bit is missing)
+fn is_dos_readonly(mode: u32) -> bool {
+    mode & Mode::S_IWUSR.bits() == 0
+}
+
+/// translate the mode of a Samba file into a unix mode.
+/// Only the read-only attribute is kept; Archive, System and Hidden
+/// (which Samba reports as execute bits) are dropped
+fn dos_to_unix_mode(mode: u32) -> u32 {
+    let is_dir = mode & SFlag::S_IFMT.bits() == SFlag::S_IFDIR.bits();
+    let unix_mode = if is_dir { 0o755 } else { 0o644 };
+    if is_dos_readonly(mode) {
+        unix_mode & !0o222
+    } else {
+        unix_mode
+    }
+}
+
+/// translate a unix mode into the mode used to chmod a Samba file.
+/// The execute bits are cleared, otherwise Samba would map them to Archive,
+/// System and Hidden
+fn unix_to_dos_mode(mode: u32) -> u32 {
+    if is_dos_readonly(mode) {
+        0o444
+    } else {
+        0o644
+    }
+}
+
+/// translate a set of unix rwx bits into an NT access mask
+fn unix_to_access_mask(bits: u32) -> u32 {
+    if bits & 0o7 == 0o7 {
+        return FULL_MASK;
+    }
+    let mut mask = 0;
+    if bits & 0o4 != 0 {
+        mask |= READ_MASK;
+    }
+    if bits & 0o2 != 0 {
+        mask |= WRITE_MASK;
+    }
+    if bits & 0o1 != 0 {
+        mask |= EXECUTE_MASK;
+    }
+    mask
+}
+
+/// build the numeric NT ACEs equivalent to the owner and group bits of a unix file.
+/// The owner and group are mapped to Samba's Unix User (S-1-22-1-uid) and
+/// Unix Group (S-1-22-2-gid) SIDs.  Unmapped (None) owners and groups are skipped
+/// @note the other bits are not translated, since Everyone is skipped in
+/// permission mapping (see the README)
+fn unix_to_acl_list(
+    mode: u32,
+    (uid, gid): (Option<u32>, Option<u32>),
+) -> ForkliftResult<Vec<SmbcAclValue>> {
+    let mut aces: Vec<String> = vec![];
+    for &(prefix, id, bits) in
+        &[(UNIX_USER_SID, uid, (mode >> 6) & 0o7), (UNIX_GROUP_SID, gid, (mode >> 3) & 0o7)]
+    {
+        if let (Some(id), true) = (id, bits != 0) {
+            aces.push(format!("ACL:{}-{}:0/0/0x{:08x}", prefix, id, unix_to_access_mask(bits)));
+        }
+    }
+    if aces.is_empty() {
+        return Ok(vec![]);
+    }
+    let acls = aces.join(",");
+    match xattr_parser(CompleteByteSlice(acls.as_bytes())) {
+        Ok((_, SmbcXAttrValue::AclAll(acl_list))) => Ok(acl_list),
+        Ok((_, SmbcXAttrValue::Ace(ace))) => Ok(vec![SmbcAclValue::Acl(ace)]),
+        Ok((_, value)) => {
+            Err(ForkliftError::FSError(format!("Translated acls parsed to {}", value)))
+        }
+        Err(e) => Err(ForkliftError::FSError(format!(
+            "Error {}, unable to parse translated acls {}",
+            e, acls
+        ))),
+    }
+}
+
+/// set the translated unix owner and group permissions of a file as the acls of a
+/// destination Samba file, removing any other acls (apart from Creator Owner,
+/// Creator Group and Everyone).  Returns true if the destination acls changed
+fn copy_unix_acl(
+    dest_path: &Path,
+    dest_ctx: &Smbc,
+    src_stat: &Stat,
+    id_map: &IdMap,
+) -> ForkliftResult<bool> {
+    let src_acls = unix_to_acl_list(src_stat.mode(), mapped_owner(src_stat, id_map)?)?;
+    let mut dest_acls = get_acl_list(dest_path, dest_ctx, false)?;
+    let mut copied = false;
+    for src_acl in src_acls {
+        if let SmbcAclValue::Acl(ACE::Numeric(SidType::Numeric(Some(sid)), atype, flags, mask)) =
+            src_acl
+        {
+            copied |= copy_acl(dest_path, dest_ctx, (sid, atype, flags, mask), &mut dest_acls)?;
+        }
+    }
+    //remaining dest acls are not part of the translation
+    for dest_acl in dest_acls {
+        if let SmbcAclValue::Acl(ACE::Numeric(SidType::Numeric(Some(dest_sid)), a, f, m)) =
+            dest_acl
+        {
+            if !(dest_sid == Sid(vec![3, 0])
+                || dest_sid == Sid(vec![3, 1])
+                || dest_sid == Sid(vec![1, 0]))
+            {
+                let ace = ACE::Numeric(SidType::Numeric(Some(dest_sid)), a, f, m);
+                if let Err(e) = dest_ctx
+                    .removexattr(dest_path, &SmbcXAttr::AclAttr(SmbcAclAttr::Acl(ace.clone())))
+                {
+                    let err = format!(
+                        "Error {}, failed to remove the old acl {} from {:?}",
+                        e, ace, dest_path
+                    );
+                    return Err(ForkliftError::FSError(err));
+                }
+                debug!("Removed untranslated acl {}", ace);
+                copied = true;
+            }
+        }
+    }
+    Ok(copied)
+}
+
 /// change the destination linux stat mode to that of the source file
 /// @note           In a Samba Context, chmod will change the Dos Mode
 ///                 (it's necessary for Normal Dos Mode, otherwise
@@ -981,33 +1926,22 @@ pub fn copy_permissions(
     dest: &Entry,
     src_context: &ProtocolContext,
     dest_context: &ProtocolContext,
+    id_map: &IdMap,
     logs_send: &Sender<LogMessage>,
 ) -> ForkliftResult<SyncOutcome> {
-    let src_mode = match (src.is_link(), src.metadata()) {
-        (Some(false), Some(stat)) => stat.mode(),
+    let src_stat = match (src.is_link(), src.metadata()) {
+        (Some(false), Some(stat)) => stat,
         (Some(true), _) => return Ok(SyncOutcome::UpToDate),
         (..) => {
             return Err(ForkliftError::FSError(format!("src {:?} does not exist", src.path())));
         }
     };
+    let src_mode = src_stat.mode();
     let (src_path, dest_path) = (src.path(), dest.path());
     let outcome;
     match (src_context, dest_context) {
-        //stat mode diff
-        (ProtocolContext::Nfs(_), ProtocolContext::Nfs(_)) => {
-            match has_different_permissions(src, dest, src_context, dest_context) {
-                Ok(true) => {
-                    change_stat_mode(dest_path, dest_context, src_mode)?;
-                    outcome = SyncOutcome::PermissionsUpdated;
-                }
-                Ok(false) => outcome = SyncOutcome::UpToDate,
-                Err(e) => {
-                    return Err(e);
-                }
-            }
-        }
         //dos mode diff
-        (ProtocolContext::Samba(src_ctx), ProtocolContext::Samba(dest_ctx)) => {
+        (ProtocolContext::Samba(src_ctx, _), ProtocolContext::Samba(dest_ctx, _)) => {
             let src_acl = &get_acl_list(src_path, src_ctx, false)?;
             let src_plus_acl = &get_acl_list(src_path, src_ctx, true)?;
             let dest_acl = &mut get_acl_list(dest_path, dest_ctx, false)?;
@@ -1017,9 +1951,10 @@ pub fn copy_permissions(
                 src_acl,
                 src_plus_acl,
                 dest_acl,
+                id_map,
                 logs_send,
             )?;
-            match has_different_permissions(src, dest, src_context, dest_context) {
+            match has_different_permissions(src, dest, src_context, dest_context, id_map) {
                 Ok(true) => {
                     trace!("src mode {}", src_mode);
                     change_stat_mode(dest_path, dest_context, src_mode)?;
@@ -1038,7 +1973,481 @@ pub fn copy_permissions(
                 }
             }
         }
-        (..) => return Err(ForkliftError::FSError("Different contexts!".to_string())),
+        //Samba -> unix, NT ACLs are not translated
+        (ProtocolContext::Samba(..), _) => {
+            match has_different_permissions(src, dest, src_context, dest_context, id_map) {
+                Ok(true) => {
+                    change_stat_mode(dest_path, dest_context, dos_to_unix_mode(src_mode))?;
+                    outcome = SyncOutcome::PermissionsUpdated;
+                }
+                Ok(false) => outcome = SyncOutcome::UpToDate,
+                Err(e) => {
+                    return Err(e);
+                }
+            }
+        }
+        //unix -> Samba, owner and group bits become ACEs
+        (_, ProtocolContext::Samba(dest_ctx, _)) => {
+            let copied = copy_unix_acl(dest_path, dest_ctx, &src_stat, id_map)?;
+            match has_different_permissions(src, dest, src_context, dest_context, id_map) {
+                Ok(true) => {
+                    change_stat_mode(dest_path, dest_context, unix_to_dos_mode(src_mode))?;
+                    outcome = SyncOutcome::PermissionsUpdated;
+                }
+                Ok(false) => {
+                    outcome = if copied {
+                        SyncOutcome::PermissionsUpdated
+                    } else {
+                        SyncOutcome::UpToDate
+                    };
+                }
+                Err(e) => {
+                    return Err(e);
+                }
+            }
+        }
+        //stat mode and owner diff (Nfs, Local and Memory)
+        (..) => match has_different_permissions(src, dest, src_context, dest_context, id_map) {
+            Ok(true) => {
+                // chown first, as it may clear the setuid and setgid bits
+                let owner = mapped_owner(&src_stat, id_map)?;
+                let chowned = if has_different_owner(owner, dest) {
+                    change_owner(dest_path, dest_context, owner, false)
+                } else {
+                    Ok(())
+                };
+                change_stat_mode(dest_path, dest_context, src_mode)?;
+                // a refused chown fails the entry, once its mode is synced anyway
+                chowned?;
+                outcome = SyncOutcome::PermissionsUpdated;
+            }
+            Ok(false) => outcome = SyncOutcome::UpToDate,
+            Err(e) => {
+                return Err(e);
+            }
+        },
     }
     Ok(outcome)
 }
+
+#[test]
+fn test_sync_entry_copies_new_file() {
+    use crate::memory_filesystem::{MemoryContext, MemoryData};
+    let (src, dest) = (MemoryContext::new(1), MemoryContext::new(2));
+    src.add_file(Path::new("/dir/file"), b"hello world", 0o640).unwrap();
+    dest.add_dir(Path::new("/dir"), 0o755).unwrap();
+    let (src_context, dest_context) = (ProtocolContext::Memory(src), ProtocolContext::Memory(dest));
+    let (send_prog, _rec_prog) = crossbeam::channel::unbounded();
+    let (send_log, _rec_log) = crossbeam::channel::unbounded();
+    let id_map = IdMap::new();
+    let path = Path::new("/dir/file");
+    let (src_entry, dest_entry) = (Entry::new(path, &src_context), Entry::new(path, &dest_context));
+    let outcome = sync_entry(
+        &src_entry,
+        &dest_entry,
+        &src_context,
+        &dest_context,
+        (&id_map, SyncOptions::default(), None),
+        &send_prog,
+        &send_log,
+    );
+    match outcome {
+        Ok(SyncOutcome::FileCopied(..)) => (),
+        outcome => panic!("expected FileCopied, got {:?}", outcome),
+    }
+    let dest_entry = Entry::new(path, &dest_context);
+    let outcome =
+        copy_permissions(&src_entry, &dest_entry, &src_context, &dest_context, &id_map, &send_log);
+    assert_eq!(outcome.unwrap(), SyncOutcome::PermissionsUpdated);
+    assert_eq!(dest_context.stat(path).unwrap().mode() & 0o7777, 0o640);
+    if let ProtocolContext::Memory(dest) = &dest_context {
+        assert_eq!(dest.data(path), Some(MemoryData::File(b"hello world".to_vec())));
+    }
+}
+
+#[test]
+fn test_sync_entry_updates_changed_file() {
+    use crate::memory_filesystem::{MemoryContext, MemoryData};
+    let (src, dest) = (MemoryContext::new(1), MemoryContext::new(2));
+    src.add_file(Path::new("/file"), b"new contents", 0o644).unwrap();
+    dest.add_file(Path::new("/file"), b"old contents", 0o644).unwrap();
+    // same size and mtime, so only a content comparison finds the change
+    let time = Timespec::new(1_000_000, 0);
+    src.utimes(Path::new("/file"), time, time).unwrap();
+    dest.utimes(Path::new("/file"), time, time).unwrap();
+    let (src_context, dest_context) = (ProtocolContext::Memory(src), ProtocolContext::Memory(dest));
+    let (send_prog, _rec_prog) = crossbeam::channel::unbounded();
+    let (send_log, _rec_log) = crossbeam::channel::unbounded();
+    let id_map = IdMap::new();
+    let path = Path::new("/file");
+    let src_entry = Entry::new(path, &src_context);
+    let policies = [
+        (ComparePolicy::SizeMtime, "old contents"),
+        (ComparePolicy::SizeOnly, "old contents"),
+        (ComparePolicy::IgnoreExisting, "old contents"),
+        (ComparePolicy::Checksum, "new contents"),
+        (ComparePolicy::Checksum, "new contents"),
+    ];
+    for (i, &(compare, contents)) in policies.iter().enumerate() {
+        let options = SyncOptions { compare, ..SyncOptions::default() };
+        let dest_entry = Entry::new(path, &dest_context);
+        let outcome = sync_entry(
+            &src_entry,
+            &dest_entry,
+            &src_context,
+            &dest_context,
+            (&id_map, options, None),
+            &send_prog,
+            &send_log,
+        );
+        match (i, outcome) {
+            (3, Ok(SyncOutcome::ChecksumUpdated(..))) => (),
+            (3, outcome) => panic!("expected ChecksumUpdated, got {:?}", outcome),
+            (_, outcome) => assert_eq!(outcome.unwrap(), SyncOutcome::UpToDate),
+        }
+        if let ProtocolContext::Memory(dest) = &dest_context {
+            assert_eq!(dest.data(path), Some(MemoryData::File(contents.as_bytes().to_vec())));
+        }
+    }
+}
+
+#[test]
+fn test_sync_entry_sends_only_new_data() {
+    use crate::memory_filesystem::{MemoryContext, MemoryData};
+    let (src, dest) = (MemoryContext::new(1), MemoryContext::new(2));
+    let path = Path::new("/file");
+    let old: Vec<u8> = (0..3_000_000u32).map(|i| (i * 31 % 251) as u8).collect();
+    // inserting near the start shifts everything after it
+    let mut new = old[..1000].to_vec();
+    new.extend_from_slice(b"inserted");
+    new.extend_from_slice(&old[1000..]);
+    src.add_file(path, &new, 0o644).unwrap();
+    dest.add_file(path, &old, 0o644).unwrap();
+    let (src_context, dest_context) = (ProtocolContext::Memory(src), ProtocolContext::Memory(dest));
+    let (send_prog, _rec_prog) = crossbeam::channel::unbounded();
+    let (send_log, _rec_log) = crossbeam::channel::unbounded();
+    let id_map = IdMap::new();
+    let (src_entry, dest_entry) = (Entry::new(path, &src_context), Entry::new(path, &dest_context));
+    let outcome = sync_entry(
+        &src_entry,
+        &dest_entry,
+        &src_context,
+        &dest_context,
+        (&id_map, SyncOptions::default(), None),
+        &send_prog,
+        &send_log,
+    );
+    match outcome {
+        Ok(SyncOutcome::ChecksumUpdated(_, src_check, dest_check, .., matched, transferred)) => {
+            assert_eq!(src_check, dest_check);
+            assert_eq!(matched + transferred, new.len() as u64);
+            assert!(transferred < 10_000);
+        }
+        outcome => panic!("expected ChecksumUpdated, got {:?}", outcome),
+    }
+    if let ProtocolContext::Memory(dest) = &dest_context {
+        assert_eq!(dest.data(path), Some(MemoryData::File(new)));
+    }
+}
+
+#[test]
+fn test_sync_entry_replaces_through_temp_file() {
+    use crate::memory_filesystem::{MemoryContext, MemoryData};
+    let (src, dest) = (MemoryContext::new(1), MemoryContext::new(2));
+    let (small, large) = (Path::new("/small"), Path::new("/large"));
+    let old: Vec<u8> = (0..3_000_000u32).map(|i| (i * 31 % 251) as u8).collect();
+    let mut new = old.clone();
+    new[2_000_000..2_000_008].copy_from_slice(b"replaced");
+    new.extend_from_slice(b"appended");
+    src.add_file(small, b"new contents", 0o644).unwrap();
+    dest.add_file(small, b"old", 0o644).unwrap();
+    src.add_file(large, &new, 0o644).unwrap();
+    dest.add_file(large, &old, 0o644).unwrap();
+    // left over from a run that died
+    dest.add_file(&temp_path(small), b"partial", 0o644).unwrap();
+    let (src_context, dest_context) = (ProtocolContext::Memory(src), ProtocolContext::Memory(dest));
+    let (send_prog, _rec_prog) = crossbeam::channel::unbounded();
+    let (send_log, _rec_log) = crossbeam::channel::unbounded();
+    let id_map = IdMap::new();
+    let options = SyncOptions { temp_files: true, ..SyncOptions::default() };
+    for (path, contents) in &[(small, b"new contents".to_vec()), (large, new)] {
+        let (src_entry, dest_entry) =
+            (Entry::new(path, &src_context), Entry::new(path, &dest_context));
+        let outcome = sync_entry(
+            &src_entry,
+            &dest_entry,
+            &src_context,
+            &dest_context,
+            (&id_map, options, None),
+            &send_prog,
+            &send_log,
+        );
+        match outcome {
+            Ok(SyncOutcome::FileCopied(_, src_check, dest_check, ..))
+            | Ok(SyncOutcome::ChecksumUpdated(_, src_check, dest_check, ..)) => {
+                assert_eq!(src_check, dest_check)
+            }
+            outcome => panic!("expected {:?} to be replaced, got {:?}", path, outcome),
+        }
+        assert!(!exist(&temp_path(path), &dest_context));
+        if let ProtocolContext::Memory(dest) = &dest_context {
+            assert_eq!(dest.data(path), Some(MemoryData::File(contents.clone())));
+        }
+    }
+}
+
+#[test]
+fn test_sync_entry_continues_from_checkpoint() {
+    use crate::memory_filesystem::{MemoryContext, MemoryData};
+    let (src, dest) = (MemoryContext::new(1), MemoryContext::new(2));
+    let path = Path::new("/file");
+    let contents: Vec<u8> = (0..3_000_000u32).map(|i| (i * 31 % 251) as u8).collect();
+    let offset = 2_000_000;
+    src.add_file(path, &contents, 0o644).unwrap();
+    // an earlier run died after copying the first offset bytes
+    dest.add_file(&temp_path(path), &contents[..offset], 0o644).unwrap();
+    let (src_context, dest_context) = (ProtocolContext::Memory(src), ProtocolContext::Memory(dest));
+    let mut hasher = HashAlgorithm::default().hasher();
+    let prefix_hash = hash(&contents[..offset], hasher.as_mut());
+    let stat = src_context.stat(path).unwrap();
+    let checkpoint = Checkpoint::new(&stat, HashAlgorithm::default(), offset as u64, prefix_hash);
+    checkpoint.save(&checkpoint_path(path), &dest_context).unwrap();
+    let (send_prog, rec_prog) = crossbeam::channel::unbounded();
+    let (send_log, _rec_log) = crossbeam::channel::unbounded();
+    let id_map = IdMap::new();
+    let options = SyncOptions { temp_files: true, ..SyncOptions::default() };
+    let (src_entry, dest_entry) = (Entry::new(path, &src_context), Entry::new(path, &dest_context));
+    let outcome = sync_entry(
+        &src_entry,
+        &dest_entry,
+        &src_context,
+        &dest_context,
+        (&id_map, options, None),
+        &send_prog,
+        &send_log,
+    );
+    match outcome {
+        Ok(SyncOutcome::FileCopied(_, src_check, dest_check, ..)) => {
+            assert_eq!(src_check, dest_check);
+            assert_eq!(src_check, hash(&contents, hasher.as_mut()));
+        }
+        outcome => panic!("expected FileCopied, got {:?}", outcome),
+    }
+    // the copy went on from the checkpoint, instead of reading the source from the start
+    let done = rec_prog.try_iter().find_map(|progress| match progress {
+        ProgressMessage::CheckSyncing { done, .. } => Some(done),
+        _ => None,
+    });
+    assert_eq!(done, Some(contents.len()));
+    assert!(!exist(&temp_path(path), &dest_context));
+    assert!(!exist(&checkpoint_path(path), &dest_context));
+    if let ProtocolContext::Memory(dest) = &dest_context {
+        assert_eq!(dest.data(path), Some(MemoryData::File(contents)));
+    }
+}
+
+#[test]
+fn test_sync_entry_copies_special_files() {
+    use crate::memory_filesystem::{MemoryContext, MemoryData};
+    let (src, dest) = (MemoryContext::new(1), MemoryContext::new(2));
+    let (fifo, tty) = (Path::new("/fifo"), Path::new("/tty"));
+    src.mknod(fifo, SFlag::S_IFIFO, Mode::from_bits_truncate(0o644), 0).unwrap();
+    src.mknod(tty, SFlag::S_IFCHR, Mode::from_bits_truncate(0o620), 0x0501).unwrap();
+    dest.add_file(tty, b"not a device", 0o644).unwrap();
+    let (src_context, dest_context) = (ProtocolContext::Memory(src), ProtocolContext::Memory(dest));
+    let (send_prog, _rec_prog) = crossbeam::channel::unbounded();
+    let (send_log, _rec_log) = crossbeam::channel::unbounded();
+    let id_map = IdMap::new();
+    for expected in &[SyncOutcome::SpecialFileCreated, SyncOutcome::UpToDate] {
+        for path in &[fifo, tty] {
+            let (src_entry, dest_entry) =
+                (Entry::new(path, &src_context), Entry::new(path, &dest_context));
+            let outcome = sync_entry(
+                &src_entry,
+                &dest_entry,
+                &src_context,
+                &dest_context,
+                (&id_map, SyncOptions::default(), None),
+                &send_prog,
+                &send_log,
+            );
+            assert_eq!(&outcome.unwrap(), expected);
+        }
+    }
+    if let ProtocolContext::Memory(dest) = &dest_context {
+        assert_eq!(dest.data(fifo), Some(MemoryData::Special(SFlag::S_IFIFO, 0)));
+        assert_eq!(dest.data(tty), Some(MemoryData::Special(SFlag::S_IFCHR, 0x0501)));
+    }
+}
+
+#[test]
+fn test_sync_entry_keeps_holes() {
+    use crate::memory_filesystem::MemoryContext;
+    let (src, dest) = (MemoryContext::new(1), MemoryContext::new(2));
+    let path = Path::new("/sparse");
+    // 3MB file with 4 bytes of data in the middle
+    let file = src.create(path, OFlag::O_RDWR, Mode::S_IRWXU).unwrap();
+    file.ftruncate(3_000_000).unwrap();
+    file.pwrite(b"data", 2_000_000).unwrap();
+    let (src_context, dest_context) = (ProtocolContext::Memory(src), ProtocolContext::Memory(dest));
+    let (send_prog, rec_prog) = crossbeam::channel::unbounded();
+    let (send_log, _rec_log) = crossbeam::channel::unbounded();
+    let id_map = IdMap::new();
+    let (src_entry, dest_entry) = (Entry::new(path, &src_context), Entry::new(path, &dest_context));
+    let outcome = sync_entry(
+        &src_entry,
+        &dest_entry,
+        &src_context,
+        &dest_context,
+        (&id_map, SyncOptions::default(), None),
+        &send_prog,
+        &send_log,
+    );
+    match outcome {
+        Ok(SyncOutcome::FileCopied(..)) => (),
+        outcome => panic!("expected FileCopied, got {:?}", outcome),
+    }
+    let holes: u64 = rec_prog
+        .try_iter()
+        .map(|progress| match progress {
+            ProgressMessage::HolesSkipped(bytes) => bytes,
+            _ => 0,
+        })
+        .sum();
+    assert_eq!(holes, 3_000_000 - HOLE_SIZE as u64);
+    let (src_stat, dest_stat) = (src_context.stat(path).unwrap(), dest_context.stat(path).unwrap());
+    assert_eq!(dest_stat.size(), 3_000_000);
+    assert_eq!(dest_stat.blocks(), src_stat.blocks());
+    let dest_file = dest_context.open(path, OFlag::O_RDONLY, Mode::S_IRWXU).unwrap();
+    assert_eq!(dest_file.read(4, 2_000_000).unwrap(), b"data".to_vec());
+    assert!(is_zero(&dest_file.read(BUFF_SIZE, 0).unwrap()));
+}
+
+#[test]
+fn test_copy_link() {
+    use crate::memory_filesystem::{MemoryContext, MemoryData};
+    let (src, dest) = (MemoryContext::new(1), MemoryContext::new(2));
+    src.add_symlink(Path::new("/link"), Path::new("target")).unwrap();
+    dest.add_symlink(Path::new("/old_link"), Path::new("target")).unwrap();
+    dest.add_symlink(Path::new("/link"), Path::new("old_target")).unwrap();
+    let (src_context, dest_context) = (ProtocolContext::Memory(src), ProtocolContext::Memory(dest));
+    let (send_log, _rec_log) = crossbeam::channel::unbounded();
+    let id_map = IdMap::new();
+    let path = Path::new("/link");
+    let (src_entry, dest_entry) = (Entry::new(path, &src_context), Entry::new(path, &dest_context));
+    let outcome =
+        copy_link(&src_entry, &dest_entry, &src_context, &dest_context, &id_map, &send_log);
+    assert_eq!(outcome.unwrap(), SyncOutcome::SymlinkUpdated);
+    let dest_entry = Entry::new(path, &dest_context);
+    let outcome =
+        copy_link(&src_entry, &dest_entry, &src_context, &dest_context, &id_map, &send_log);
+    assert_eq!(outcome.unwrap(), SyncOutcome::UpToDate);
+    if let ProtocolContext::Memory(dest) = &dest_context {
+        assert_eq!(dest.data(path), Some(MemoryData::Symlink(PathBuf::from("target"))));
+    }
+}
+
+#[test]
+fn test_copy_permissions_copies_owner() {
+    use crate::memory_filesystem::MemoryContext;
+    let (src, dest) = (MemoryContext::new(1), MemoryContext::new(2));
+    src.add_file(Path::new("/file"), b"contents", 0o4755).unwrap();
+    src.chown(Path::new("/file"), 1000, 100).unwrap();
+    src.add_symlink(Path::new("/link"), Path::new("file")).unwrap();
+    src.chown(Path::new("/link"), 1000, 100).unwrap();
+    dest.add_file(Path::new("/file"), b"contents", 0o4755).unwrap();
+    dest.add_symlink(Path::new("/link"), Path::new("file")).unwrap();
+    let (src_ctx, dest_ctx) = (ProtocolContext::Memory(src), ProtocolContext::Memory(dest));
+    let (send_log, _rec_log) = crossbeam::channel::unbounded();
+    let id_map = IdMap::new();
+    for path in &[Path::new("/file"), Path::new("/link")] {
+        let (src_entry, dest_entry) = (Entry::new(path, &src_ctx), Entry::new(path, &dest_ctx));
+        let outcome = if let Some(true) = src_entry.is_link() {
+            copy_link(&src_entry, &dest_entry, &src_ctx, &dest_ctx, &id_map, &send_log)
+        } else {
+            copy_permissions(&src_entry, &dest_entry, &src_ctx, &dest_ctx, &id_map, &send_log)
+        };
+        assert_eq!(outcome.unwrap(), SyncOutcome::PermissionsUpdated);
+        let stat = dest_ctx.stat(path).unwrap();
+        assert_eq!((stat.uid(), stat.gid()), (1000, 100));
+        let dest_entry = Entry::new(path, &dest_ctx);
+        let different =
+            has_different_permissions(&src_entry, &dest_entry, &src_ctx, &dest_ctx, &id_map);
+        assert!(!different.unwrap());
+    }
+    // the mode is set after the owner, so the setuid bit survives
+    assert_eq!(dest_ctx.stat(Path::new("/file")).unwrap().mode() & 0o7777, 0o4755);
+}
+
+#[test]
+fn test_copy_permissions_maps_owner() {
+    use crate::memory_filesystem::MemoryContext;
+    let (src, dest) = (MemoryContext::new(1), MemoryContext::new(2));
+    src.add_file(Path::new("/file"), b"contents", 0o644).unwrap();
+    src.chown(Path::new("/file"), 1000, 100).unwrap();
+    dest.add_file(Path::new("/file"), b"contents", 0o644).unwrap();
+    dest.chown(Path::new("/file"), 5, 6).unwrap();
+    let (src_ctx, dest_ctx) = (ProtocolContext::Memory(src), ProtocolContext::Memory(dest));
+    let (send_log, _rec_log) = crossbeam::channel::unbounded();
+    // the gid is unmapped, so only the uid changes
+    let id_map = IdMap::parse(r#"{"uids": {"1000": 2000}, "unmapped": "Skip"}"#).unwrap();
+    let path = Path::new("/file");
+    let (src_entry, dest_entry) = (Entry::new(path, &src_ctx), Entry::new(path, &dest_ctx));
+    let outcome =
+        copy_permissions(&src_entry, &dest_entry, &src_ctx, &dest_ctx, &id_map, &send_log);
+    assert_eq!(outcome.unwrap(), SyncOutcome::PermissionsUpdated);
+    let stat = dest_ctx.stat(path).unwrap();
+    assert_eq!((stat.uid(), stat.gid()), (2000, 6));
+    let dest_entry = Entry::new(path, &dest_ctx);
+    let outcome =
+        copy_permissions(&src_entry, &dest_entry, &src_ctx, &dest_ctx, &id_map, &send_log);
+    assert_eq!(outcome.unwrap(), SyncOutcome::UpToDate);
+    assert_eq!(id_map.take_unmapped(), vec!["gid 100"]);
+}
+
+#[test]
+fn test_copy_permissions_without_chown() {
+    use crate::memory_filesystem::MemoryContext;
+    use nix::libc;
+    let (src, dest) = (MemoryContext::new(1), MemoryContext::new(2));
+    // only the owner of /b differs
+    for (name, dest_mode) in &[("/a", 0o644), ("/b", 0o640)] {
+        src.add_file(Path::new(name), b"contents", 0o640).unwrap();
+        src.chown(Path::new(name), 1000, 100).unwrap();
+        dest.add_file(Path::new(name), b"contents", *dest_mode).unwrap();
+    }
+    let src_ctx = ProtocolContext::Memory(src);
+    let dest_ctx = ProtocolContext::Memory(dest.without_chown());
+    let (send_log, _rec_log) = crossbeam::channel::unbounded();
+    let id_map = IdMap::new();
+    for name in &["/a", "/b"] {
+        let path = Path::new(name);
+        let (src_entry, dest_entry) = (Entry::new(path, &src_ctx), Entry::new(path, &dest_ctx));
+        let outcome =
+            copy_permissions(&src_entry, &dest_entry, &src_ctx, &dest_ctx, &id_map, &send_log);
+        // every refused chown fails its entry, with the errno kept
+        match outcome {
+            Err(ForkliftError::IoError(e)) => assert_eq!(e.raw_os_error(), Some(libc::EPERM)),
+            outcome => panic!("expected EPERM, got {:?}", outcome),
+        }
+        // the mode is still synced, the owner is left as it is
+        let stat = dest_ctx.stat(path).unwrap();
+        assert_eq!((stat.mode() & 0o7777, stat.uid(), stat.gid()), (0o640, 0, 0));
+    }
+}
+
+#[test]
+fn test_translate_modes() {
+    let (file, dir) = (SFlag::S_IFREG.bits(), SFlag::S_IFDIR.bits());
+    // Samba reports Archive as the owner execute bit, it should not be carried over
+    assert_eq!(dos_to_unix_mode(file | 0o744), 0o644);
+    assert_eq!(dos_to_unix_mode(file | 0o544), 0o444);
+    assert_eq!(dos_to_unix_mode(dir | 0o755), 0o755);
+    assert_eq!(dos_to_unix_mode(dir | 0o555), 0o555);
+    assert_eq!(unix_to_dos_mode(file | 0o755), 0o644);
+    assert_eq!(unix_to_dos_mode(file | 0o444), 0o444);
+    assert_eq!(is_dos_readonly(file | 0o444), is_dos_readonly(unix_to_dos_mode(file | 0o555)));
+    assert_eq!(unix_to_access_mask(0o7), FULL_MASK);
+    assert_eq!(unix_to_access_mask(0o5), READ_MASK | EXECUTE_MASK);
+    assert_eq!(unix_to_access_mask(0o0), 0);
+}
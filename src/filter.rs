@@ -0,0 +1,403 @@
+use crate::error::{ForkliftError, ForkliftResult};
+use crate::filesystem::{GenericFileType, Stat};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use log::*;
+use regex::Regex;
+use serde_derive::*;
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// what a matching rule does with an entry
+pub enum RuleKind {
+    /// sync the entry (and walk it, if a directory)
+    Include,
+    /// skip the entry (and everything in it, if a directory)
+    Exclude,
+}
+
+#[derive(Debug, Clone)]
+/// an include/exclude rule, written as "+ pattern" or "- pattern".
+/// A pattern is a glob, or a regex if prefixed with "re:".  Globs starting with "/"
+/// are anchored to the sync root, others match the end of the path (so a glob without
+/// a "/" matches the name of the entry).  A pattern ending in "/" only matches directories
+pub struct Rule {
+    /// include or exclude
+    kind: RuleKind,
+    /// pattern matched against the path relative to the sync root (ex. "a/b/c")
+    regex: Regex,
+    /// true if the rule only matches directories
+    dir_only: bool,
+}
+
+impl Rule {
+    /// parse a rule
+    pub fn new(rule: &str) -> ForkliftResult<Self> {
+        let (kind, pattern) = match (rule.strip_prefix("+ "), rule.strip_prefix("- ")) {
+            (Some(pattern), _) => (RuleKind::Include, pattern),
+            (_, Some(pattern)) => (RuleKind::Exclude, pattern),
+            (None, None) => {
+                return Err(ForkliftError::InvalidConfigError(format!(
+                    "Filter rule {:?} does not start with \"+ \" or \"- \"",
+                    rule
+                )));
+            }
+        };
+        let (pattern, dir_only) = match pattern.strip_suffix('/') {
+            Some(dir) if !dir.is_empty() => (dir, true),
+            _ => (pattern, false),
+        };
+        let regex = match (pattern.strip_prefix("re:"), pattern.strip_prefix('/')) {
+            (Some(regex), _) => regex.to_string(),
+            (_, Some(glob)) => format!("^{}$", glob_to_regex(glob)),
+            (None, None) => format!("(^|/){}$", glob_to_regex(pattern)),
+        };
+        match Regex::new(&regex) {
+            Ok(regex) => Ok(Rule { kind, regex, dir_only }),
+            Err(e) => Err(ForkliftError::InvalidConfigError(format!(
+                "Error {:?}, invalid filter rule {:?}",
+                e, rule
+            ))),
+        }
+    }
+
+    /// true if the rule applies to the entry at rel_path
+    fn matches(&self, rel_path: &str, is_dir: bool) -> bool {
+        (is_dir || !self.dir_only) && self.regex.is_match(rel_path)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+/// attributes a file must have to be synced, unset fields select every file.
+/// Directories are always selected, so that the files in them are walked
+pub struct Selection {
+    /// smallest size in bytes
+    #[serde(default)]
+    pub min_size: Option<u64>,
+    /// largest size in bytes
+    #[serde(default)]
+    pub max_size: Option<u64>,
+    /// the file was last modified before this time (a date, or a duration before now)
+    #[serde(default)]
+    pub mtime_older_than: Option<String>,
+    /// the file was last modified at or after this time
+    #[serde(default)]
+    pub mtime_newer_than: Option<String>,
+    /// the file was last accessed before this time
+    #[serde(default)]
+    pub atime_older_than: Option<String>,
+    /// the file was last accessed at or after this time
+    #[serde(default)]
+    pub atime_newer_than: Option<String>,
+    /// the file is owned by one of these (source) uids
+    #[serde(default)]
+    pub uids: Vec<u32>,
+    /// the file belongs to one of these (source) gids
+    #[serde(default)]
+    pub gids: Vec<u32>,
+    /// the file is of one of these types
+    #[serde(default)]
+    pub types: Vec<GenericFileType>,
+}
+
+#[derive(Debug, Clone, Default)]
+/// ordered include/exclude rules.  The first rule matching an entry decides whether it
+/// is synced, entries matching no rule are synced.  Files not excluded by the rules are
+/// then only synced if they have the attributes of the selection
+pub struct Filter {
+    rules: Vec<Rule>,
+    /// attributes of the files synced
+    selection: Selection,
+    /// (newer than, older than) bounds of the modification time, in seconds since the epoch
+    mtime: (Option<i64>, Option<i64>),
+    /// (newer than, older than) bounds of the access time, in seconds since the epoch
+    atime: (Option<i64>, Option<i64>),
+}
+
+impl Filter {
+    /// create a Filter from rules, in order
+    pub fn new(rules: &[String]) -> ForkliftResult<Self> {
+        let rules = rules.iter().map(|rule| Rule::new(rule)).collect::<ForkliftResult<_>>()?;
+        Ok(Filter { rules, ..Filter::default() })
+    }
+
+    /// only sync the files with the attributes of selection.  Durations are counted back
+    /// from now
+    pub fn select(self, selection: &Selection, now: DateTime<Utc>) -> ForkliftResult<Self> {
+        let bound = |time: &Option<String>| match time {
+            Some(time) => parse_time(time, now).map(Some),
+            None => Ok(None),
+        };
+        let mtime = (bound(&selection.mtime_newer_than)?, bound(&selection.mtime_older_than)?);
+        let atime = (bound(&selection.atime_newer_than)?, bound(&selection.atime_older_than)?);
+        Ok(Filter { selection: selection.clone(), mtime, atime, ..self })
+    }
+
+    /// create a Filter from the rules of the config, followed by those of the rules
+    /// file at path, one per line.  Empty lines and lines starting with "#" are ignored
+    pub fn load(rules: &[String], path: Option<&Path>) -> ForkliftResult<Self> {
+        let mut rules = rules.to_vec();
+        if let Some(path) = path {
+            match fs::read_to_string(path) {
+                Ok(file) => {
+                    debug!("loaded filter rules {:?}", path);
+                    let lines = file.lines().map(str::trim_end);
+                    rules.extend(
+                        lines.filter(|l| !l.is_empty() && !l.starts_with('#')).map(String::from),
+                    );
+                }
+                Err(e) => {
+                    return Err(ForkliftError::InvalidConfigError(format!(
+                        "Error {:?}, unable to read filter rules file {:?}",
+                        e, path
+                    )));
+                }
+            }
+        }
+        Filter::new(&rules)
+    }
+
+    /// true if the entry at rel_path, relative to the sync root, is excluded
+    pub fn is_excluded(&self, rel_path: &Path, is_dir: bool) -> bool {
+        let rel_path = rel_path.to_string_lossy();
+        match self.rules.iter().find(|rule| rule.matches(&rel_path, is_dir)) {
+            Some(rule) => rule.kind == RuleKind::Exclude,
+            None => false,
+        }
+    }
+
+    /// true if the entry with stat has the attributes of the selection
+    pub fn is_selected(&self, stat: &Stat) -> bool {
+        let file_type = GenericFileType::from_mode(stat.mode());
+        if file_type == GenericFileType::Directory {
+            return true;
+        }
+        let (selection, size) = (&self.selection, stat.size() as u64);
+        selection.min_size.iter().all(|&min| size >= min)
+            && selection.max_size.iter().all(|&max| size <= max)
+            && in_bounds(stat.mtime().num_seconds(), self.mtime)
+            && in_bounds(stat.atime().num_seconds(), self.atime)
+            && (selection.uids.is_empty() || selection.uids.contains(&stat.uid()))
+            && (selection.gids.is_empty() || selection.gids.contains(&stat.gid()))
+            && (selection.types.is_empty() || selection.types.contains(&file_type))
+    }
+}
+
+/// read the list of paths to sync of files_from, relative to the source path
+pub fn read_file_list(path: &Path) -> ForkliftResult<Vec<PathBuf>> {
+    match fs::read(path) {
+        Ok(list) => {
+            debug!("loaded file list {:?}", path);
+            parse_file_list(&list)
+        }
+        Err(e) => Err(ForkliftError::InvalidConfigError(format!(
+            "Error {:?}, unable to read file list {:?}",
+            e, path
+        ))),
+    }
+}
+
+/// parse a list of paths separated by NUL characters if it has any, or else by newlines.
+/// Empty paths are skipped, and leading "/"s ignored, so every path is relative
+fn parse_file_list(list: &[u8]) -> ForkliftResult<Vec<PathBuf>> {
+    let separator = if list.contains(&0) { 0 } else { b'\n' };
+    let mut paths = vec![];
+    for path in list.split(|&c| c == separator) {
+        let path = match (separator, path.split_last()) {
+            (b'\n', Some((b'\r', path))) => path,
+            _ => path,
+        };
+        let path = Path::new(OsStr::from_bytes(path));
+        let path: PathBuf = path.components().filter(|c| c != &Component::RootDir).collect();
+        if path.components().any(|c| c == Component::ParentDir) {
+            return Err(ForkliftError::InvalidConfigError(format!(
+                "{:?} in the file list is outside of the source path",
+                path
+            )));
+        }
+        if !path.as_os_str().is_empty() {
+            paths.push(path);
+        }
+    }
+    Ok(paths)
+}
+
+/// true if time is at or after the newer bound, and before the older bound
+fn in_bounds(time: i64, (newer, older): (Option<i64>, Option<i64>)) -> bool {
+    newer.iter().all(|&newer| time >= newer) && older.iter().all(|&older| time < older)
+}
+
+/// parse a time of a Selection into seconds since the epoch.  It is either a duration
+/// before now, a number followed by h (hours), d (days), w (weeks), or y (365 days),
+/// a date (YYYY-MM-DD, at midnight UTC), or an RFC 3339 date and time
+fn parse_time(time: &str, now: DateTime<Utc>) -> ForkliftResult<i64> {
+    let unit = match time.chars().last() {
+        Some('h') => Some(3600),
+        Some('d') => Some(24 * 3600),
+        Some('w') => Some(7 * 24 * 3600),
+        Some('y') => Some(365 * 24 * 3600),
+        _ => None,
+    };
+    if let Some(unit) = unit {
+        if let Ok(count) = time[..time.len() - 1].parse::<i64>() {
+            return Ok(now.timestamp() - count * unit);
+        }
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(time, "%Y-%m-%d") {
+        return Ok(date.and_hms(0, 0, 0).timestamp());
+    }
+    match DateTime::parse_from_rfc3339(time) {
+        Ok(datetime) => Ok(datetime.timestamp()),
+        Err(e) => Err(ForkliftError::InvalidConfigError(format!(
+            "Error {:?}, invalid time {:?} in select",
+            e, time
+        ))),
+    }
+}
+
+/// translate a glob into a regex.  "*" and "?" match within a path component,
+/// "**" matches across components, "[...]" matches a character class ("[!...]" negated)
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::new();
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str(".*");
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                regex.push('[');
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    regex.push('^');
+                }
+                // a "]" right after the opening bracket is part of the class
+                if chars.peek() == Some(&']') {
+                    chars.next();
+                    regex.push_str("\\]");
+                }
+                for c in &mut chars {
+                    match c {
+                        ']' => break,
+                        '[' | '\\' | '&' | '~' => {
+                            regex.push('\\');
+                            regex.push(c);
+                        }
+                        c => regex.push(c),
+                    }
+                }
+                regex.push(']');
+            }
+            '\\' => {
+                if let Some(c) = chars.next() {
+                    regex.push_str(&regex::escape(&c.to_string()));
+                }
+            }
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex
+}
+
+#[test]
+fn test_filter() {
+    use std::path::PathBuf;
+
+    let rules: Vec<String> = vec![
+        "+ /keep/Thumbs.db",
+        "- Thumbs.db",
+        "- ~$*",
+        "- .snapshot/",
+        "- /scratch",
+        "- build/**/*.o",
+        "- re:\\.te?mp$",
+        "- re:^tmp[0-9]+$/",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect();
+    let filter = Filter::new(&rules).unwrap();
+    let excluded = |path: &str, is_dir: bool| filter.is_excluded(&PathBuf::from(path), is_dir);
+
+    // unanchored globs match the name at any depth
+    assert!(excluded("Thumbs.db", false));
+    assert!(excluded("a/b/Thumbs.db", false));
+    assert!(!excluded("a/Thumbs.dbx", false));
+    assert!(excluded("docs/~$report.docx", false));
+    assert!(!excluded("docs/report~$.docx", false));
+    // the first matching rule wins
+    assert!(!excluded("keep/Thumbs.db", false));
+    // directory-only rules
+    assert!(excluded("vol/.snapshot", true));
+    assert!(!excluded("vol/.snapshot", false));
+    // anchored globs only match from the root
+    assert!(excluded("scratch", true));
+    assert!(!excluded("a/scratch", true));
+    // "**" matches across directories, "*" does not
+    assert!(excluded("build/x/y/main.o", false));
+    assert!(excluded("src/build/x/main.o", false));
+    assert!(!excluded("build/main.c", false));
+    // regexes
+    assert!(excluded("a/file.tmp", false));
+    assert!(excluded("a/file.temp", false));
+    assert!(excluded("tmp12", true));
+    assert!(!excluded("tmp12", false));
+    assert!(!excluded("a/tmp12", true));
+    assert!(!excluded("a/file.txt", false));
+
+    assert_eq!(glob_to_regex("[!a-c]?.*"), "[^a-c][^/]\\.[^/]*");
+    assert!(Filter::new(&["Thumbs.db".to_string()]).is_err());
+    assert!(Filter::new(&["- re:(".to_string()]).is_err());
+}
+
+#[test]
+fn test_parse_file_list() {
+    let expected = vec![PathBuf::from("a/file"), PathBuf::from("b c"), PathBuf::from("d/")];
+    assert_eq!(parse_file_list(b"a/file\r\n\n/b c\nd/\n").unwrap(), expected);
+    let expected = vec![PathBuf::from("a/new\nline"), PathBuf::from("b c")];
+    assert_eq!(parse_file_list(b"a/new\nline\0/b c\0").unwrap(), expected);
+    assert!(parse_file_list(b"a/../../etc/passwd\n").is_err());
+}
+
+#[test]
+fn test_selection() {
+    use crate::filesystem::Timespec;
+
+    let now = DateTime::parse_from_rfc3339("2020-06-01T00:00:00Z").unwrap().with_timezone(&Utc);
+    let day = 24 * 3600;
+    assert_eq!(parse_time("2d", now).unwrap(), now.timestamp() - 2 * day);
+    assert_eq!(parse_time("1y", now).unwrap(), now.timestamp() - 365 * day);
+    assert_eq!(parse_time("2020-05-31", now).unwrap(), now.timestamp() - day);
+    assert_eq!(parse_time("2020-05-31T12:00:00Z", now).unwrap(), now.timestamp() - day / 2);
+    assert!(parse_time("yesterday", now).is_err());
+
+    let selection: Selection = serde_json::from_str(
+        r#"{"min_size": 10, "max_size": 1000, "mtime_older_than": "1y",
+            "atime_newer_than": "2020-01-01", "uids": [1000], "types": ["File"]}"#,
+    )
+    .unwrap();
+    let filter = Filter::default().select(&selection, now).unwrap();
+    let stat = |mode: u32, size: i64, uid: u32, (atime, mtime): (i64, i64)| {
+        let (atime, mtime) = (Timespec::new(atime, 0), Timespec::new(mtime, 0));
+        Stat::new((0, 0, mode, 1, uid, 0, 0, size, 0, 0), atime, mtime, mtime)
+    };
+    let (old, recent) = (now.timestamp() - 400 * day, now.timestamp() - 10 * day);
+    assert!(filter.is_selected(&stat(0o100644, 100, 1000, (recent, old))));
+    // too small, too big
+    assert!(!filter.is_selected(&stat(0o100644, 5, 1000, (recent, old))));
+    assert!(!filter.is_selected(&stat(0o100644, 5000, 1000, (recent, old))));
+    // modified too recently, not accessed recently enough
+    assert!(!filter.is_selected(&stat(0o100644, 100, 1000, (recent, recent))));
+    assert!(!filter.is_selected(&stat(0o100644, 100, 1000, (old, old))));
+    // other owner, other type
+    assert!(!filter.is_selected(&stat(0o100644, 100, 0, (recent, old))));
+    assert!(!filter.is_selected(&stat(0o120777, 100, 1000, (recent, old))));
+    // directories are always selected
+    assert!(filter.is_selected(&stat(0o040755, 0, 0, (0, 0))));
+}
@@ -0,0 +1,195 @@
+use crate::error::{ForkliftError, ForkliftResult};
+
+use log::*;
+use serde_derive::*;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::mem;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// named SIDs that are the same in every domain, and are never mapped
+const WELL_KNOWN_SIDS: [&str; 3] = ["\\Everyone", "\\Creator Owner", "\\Creator Group"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+/// what to do with a uid, gid, or SID that is not in the mapping file
+pub enum UnmappedPolicy {
+    /// leave the destination owner as it is, and do not copy the ACL entry
+    Skip,
+    /// use the default_uid, default_gid, or default_sid of the mapping file
+    Default,
+    /// return an error, failing the entry (the rest of the sync goes on)
+    Fail,
+}
+
+impl Default for UnmappedPolicy {
+    fn default() -> Self {
+        UnmappedPolicy::Skip
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+/// Identity mapping file Input, translating source owners and ACL principals
+/// into destination ones
+pub struct IdMap {
+    /// source uid to destination uid
+    #[serde(default)]
+    uids: HashMap<u32, u32>,
+    /// source gid to destination gid
+    #[serde(default)]
+    gids: HashMap<u32, u32>,
+    /// source named SID (ex. "DOMAIN\\user") to destination named SID
+    #[serde(default)]
+    sids: HashMap<String, String>,
+    /// policy for principals not in the maps
+    #[serde(default)]
+    unmapped: UnmappedPolicy,
+    /// uid used for unmapped uids with the Default policy
+    default_uid: Option<u32>,
+    /// gid used for unmapped gids with the Default policy
+    default_gid: Option<u32>,
+    /// named SID used for unmapped SIDs with the Default policy
+    default_sid: Option<String>,
+    /// true if read from a mapping file, otherwise every principal maps to itself
+    #[serde(skip)]
+    from_file: bool,
+    /// unmapped principals seen since the last report
+    #[serde(skip)]
+    unmapped_seen: Mutex<BTreeSet<String>>,
+}
+
+impl IdMap {
+    /// create an IdMap mapping every principal to itself
+    pub fn new() -> Self {
+        IdMap::default()
+    }
+
+    /// create an IdMap from the contents of a mapping file
+    pub fn parse(map: &str) -> ForkliftResult<Self> {
+        let mut id_map: IdMap = match serde_json::from_str(map) {
+            Ok(e) => e,
+            Err(e) => {
+                return Err(ForkliftError::InvalidConfigError(format!(
+                    "Error {:?}, unable to parse id map file!",
+                    e
+                )));
+            }
+        };
+        id_map.from_file = true;
+        Ok(id_map)
+    }
+
+    /// load the mapping file at path, or the identity map if there is no path
+    pub fn load(path: Option<&Path>) -> ForkliftResult<Self> {
+        let path = match path {
+            Some(p) => p,
+            None => return Ok(IdMap::new()),
+        };
+        match fs::read_to_string(path) {
+            Ok(map) => {
+                debug!("loaded id map {:?}", path);
+                IdMap::parse(&map)
+            }
+            Err(e) => Err(ForkliftError::InvalidConfigError(format!(
+                "Error {:?}, unable to read id map file {:?}",
+                e, path
+            ))),
+        }
+    }
+
+    /// get the destination uid of a source uid, or None if it should be skipped
+    pub fn map_uid(&self, uid: u32) -> ForkliftResult<Option<u32>> {
+        self.map(uid, &self.uids, &self.default_uid, "uid")
+    }
+
+    /// get the destination gid of a source gid, or None if it should be skipped
+    pub fn map_gid(&self, gid: u32) -> ForkliftResult<Option<u32>> {
+        self.map(gid, &self.gids, &self.default_gid, "gid")
+    }
+
+    /// get the destination named SID of a source named SID, or None if it should be skipped
+    pub fn map_sid(&self, sid: &str) -> ForkliftResult<Option<String>> {
+        if WELL_KNOWN_SIDS.contains(&sid) {
+            return Ok(Some(sid.to_string()));
+        }
+        self.map(sid.to_string(), &self.sids, &self.default_sid, "sid")
+    }
+
+    /// helper for the map functions
+    fn map<T>(
+        &self,
+        id: T,
+        map: &HashMap<T, T>,
+        default: &Option<T>,
+        kind: &str,
+    ) -> ForkliftResult<Option<T>>
+    where
+        T: std::hash::Hash + Eq + Clone + std::fmt::Display,
+    {
+        if !self.from_file {
+            return Ok(Some(id));
+        }
+        if let Some(mapped) = map.get(&id) {
+            return Ok(Some(mapped.clone()));
+        }
+        let principal = format!("{} {}", kind, id);
+        match self.unmapped_seen.lock() {
+            Ok(mut seen) => {
+                seen.insert(principal.clone());
+            }
+            Err(_) => {
+                return Err(ForkliftError::FSError("failed to lock".to_string()));
+            }
+        }
+        match (self.unmapped, default) {
+            (UnmappedPolicy::Skip, _) => {
+                debug!("skipping unmapped {}", principal);
+                Ok(None)
+            }
+            (UnmappedPolicy::Default, Some(default)) => Ok(Some(default.clone())),
+            (UnmappedPolicy::Default, None) => Err(ForkliftError::InvalidConfigError(format!(
+                "{} is unmapped, and the id map has no default_{}",
+                principal, kind
+            ))),
+            (UnmappedPolicy::Fail, _) => {
+                Err(ForkliftError::InvalidConfigError(format!("{} is unmapped", principal)))
+            }
+        }
+    }
+
+    /// take the unmapped principals seen since the last call, in sorted order
+    pub fn take_unmapped(&self) -> Vec<String> {
+        match self.unmapped_seen.lock() {
+            Ok(mut seen) => mem::take(&mut *seen).into_iter().collect(),
+            Err(_) => vec![],
+        }
+    }
+}
+
+#[test]
+fn test_id_map_policies() {
+    let identity = IdMap::new();
+    assert_eq!(identity.map_uid(1000).unwrap(), Some(1000));
+    assert_eq!(identity.map_sid("\\DOMAIN\\user").unwrap(), Some("\\DOMAIN\\user".to_string()));
+
+    let map = r#"{"uids": {"1000": 2000}, "gids": {"100": 200},
+        "sids": {"\\OLD\\user": "\\NEW\\user"}, "unmapped": "Skip"}"#;
+    let id_map = IdMap::parse(map).unwrap();
+    assert_eq!(id_map.map_uid(1000).unwrap(), Some(2000));
+    assert_eq!(id_map.map_gid(100).unwrap(), Some(200));
+    assert_eq!(id_map.map_sid("\\OLD\\user").unwrap(), Some("\\NEW\\user".to_string()));
+    assert_eq!(id_map.map_sid("\\Everyone").unwrap(), Some("\\Everyone".to_string()));
+    assert_eq!(id_map.map_uid(1001).unwrap(), None);
+    assert_eq!(id_map.map_sid("\\OLD\\other").unwrap(), None);
+    assert_eq!(id_map.map_uid(1001).unwrap(), None);
+    assert_eq!(id_map.take_unmapped(), vec!["sid \\OLD\\other", "uid 1001"]);
+    assert!(id_map.take_unmapped().is_empty());
+
+    let id_map = IdMap::parse(r#"{"unmapped": "Default", "default_uid": 65534}"#).unwrap();
+    assert_eq!(id_map.map_uid(1000).unwrap(), Some(65534));
+    assert!(id_map.map_gid(100).is_err());
+
+    let id_map = IdMap::parse(r#"{"unmapped": "Fail"}"#).unwrap();
+    assert!(id_map.map_uid(1000).is_err());
+    assert_eq!(id_map.take_unmapped(), vec!["uid 1000"]);
+}
@@ -1,5 +1,10 @@
 use crate::error::{ForkliftError, ForkliftResult};
-use crate::filesystem::{DebugLevel, FileSystemType};
+use crate::filesystem::{DebugLevel, FileSystemType, SambaSymlinks};
+use crate::content_hash::HashAlgorithm;
+use crate::filesystem_ops::{ComparePolicy, DeletePolicy};
+use crate::filter::Selection;
+use crate::plan::PlanFormat;
+use crate::retry::RetryPolicy;
 
 use log::*;
 use serde_derive::*;
@@ -14,16 +19,26 @@ pub struct Input {
     /// Node lifetime
     #[serde(default = "default_lifetime")]
     pub lifetime: u64,
-    /// server of the source share
+    /// server of the source share (unused for Local)
+    #[serde(default)]
     pub src_server: String,
-    /// server of the destination share
+    /// server of the destination share (unused for Local)
+    #[serde(default)]
     pub dest_server: String,
     /// source share name; should be formatted as '/sharename'
+    /// For Local, this is the directory the source is mounted at
     pub src_share: String,
     /// destination share name; should be formatted as '/sharename'
+    /// For Local, this is the directory the destination is mounted at
     pub dest_share: String,
-    /// Share type (Nfs or Samba)
-    pub system: FileSystemType,
+    /// Share type of both sides (Nfs, Samba, or Local), for the side whose own type is
+    /// not given
+    pub system: Option<FileSystemType>,
+    /// Share type of the source (Nfs, Samba, or Local), by default system
+    pub src_system: Option<FileSystemType>,
+    /// Share type of the destination (Nfs, Samba, or Local), by default system
+    /// May differ from src_system, see the README for how permissions are translated
+    pub dest_system: Option<FileSystemType>,
     /// The debug level of the filesystem context
     pub debug_level: DebugLevel,
     /// The number of threads used in the processing
@@ -46,6 +61,71 @@ pub struct Input {
     /// False if program exits on finish.  By default rerun
     #[serde(default = "default_rerun")]
     pub rerun: bool,
+    /// Path of the identity mapping file translating source owners and ACL principals
+    /// to destination ones, or NULL if they are the same on both sides
+    pub id_map: Option<PathBuf>,
+    /// How symlinks are stored on Samba shares (Skip or MfSymlinks), by default Skip
+    #[serde(default)]
+    pub samba_symlinks: SambaSymlinks,
+    /// How existing destination files are checked for changes (size+mtime, checksum,
+    /// size-only, or ignore-existing), by default size+mtime
+    #[serde(default)]
+    pub compare: ComparePolicy,
+    /// Algorithm used to checksum file contents (Meow, Xxh3, Blake3, Sha256, or Sha512),
+    /// by default Meow
+    #[serde(default)]
+    pub hash: HashAlgorithm,
+    /// Write file contents to a hidden temporary file next to the destination, and rename
+    /// it over the destination once verified, by default false
+    #[serde(default)]
+    pub temp_files: bool,
+    /// Files of at least this many bytes are split into 1GB ranges, copied in parallel by
+    /// all the threads, by default files are not split
+    #[serde(default)]
+    pub chunked_copy_size: Option<u64>,
+    /// Read the files written in each run again at its end, with separate connections,
+    /// to check that they match the source, by default false
+    #[serde(default)]
+    pub verify: bool,
+    /// Local path the plan of a dry run is written to.  If set, nothing is synced and the
+    /// destination is left untouched, by default NULL (sync)
+    pub dry_run: Option<PathBuf>,
+    /// Format of the dry run plan (Json or Csv), by default Json
+    #[serde(default)]
+    pub plan_format: PlanFormat,
+    /// What is done with destination entries that are not in the source (never, during,
+    /// after, or quarantine), by default during
+    #[serde(default)]
+    pub delete: DeletePolicy,
+    /// Include/exclude rules ("+ pattern" or "- pattern") deciding which entries are
+    /// synced, the first matching rule wins.  By default everything is synced
+    #[serde(default)]
+    pub filter: Vec<String>,
+    /// Path of a file of more filter rules, one per line, applied after those of filter
+    pub filter_file: Option<PathBuf>,
+    /// Remove (or quarantine) excluded destination entries not in the source like any
+    /// other, by default false (excluded entries are left alone)
+    #[serde(default)]
+    pub delete_excluded: bool,
+    /// Attributes (size, times, owner, type) a file must have to be synced, by default
+    /// every file is synced
+    #[serde(default)]
+    pub select: Selection,
+    /// Path of a file listing the paths to sync, relative to src_path, separated by
+    /// newlines or NUL characters.  If set, only those entries are synced, nothing else
+    /// is walked or removed, by default NULL (sync the whole tree)
+    pub files_from: Option<PathBuf>,
+    /// How entries that failed with a transient error (such as EIO or a timeout) are
+    /// tried again, by default 4 attempts, waiting 500ms then doubling up to 30s
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    /// Local path the entries that failed in a run are written to at its end, by default
+    /// forklift-failures.json in the temporary directory
+    #[serde(default = "default_failures_path")]
+    pub failures_path: PathBuf,
+    /// Path of a failures file written by an earlier run.  If set, only the entries (and
+    /// directories) that failed in it are tried again, by default NULL (sync the whole tree)
+    pub retry_failures: Option<PathBuf>,
 }
 /// default workgroup helper
 fn default_workgroup() -> String {
@@ -65,6 +145,37 @@ fn default_path() -> PathBuf {
 fn default_rerun() -> bool {
     true
 }
+/// default failures file helper
+fn default_failures_path() -> PathBuf {
+    std::env::temp_dir().join("forklift-failures.json")
+}
+
+/// get the path sync starts from in a share of type system.
+/// Samba paths are prefixed with the smb url of the share
+fn share_path(
+    system: FileSystemType,
+    server: &str,
+    share: &str,
+    path: &Path,
+) -> ForkliftResult<PathBuf> {
+    match system {
+        FileSystemType::Nfs | FileSystemType::Local => {
+            // if the input is empty, exit
+            if path.to_string_lossy().is_empty() {
+                return Err(ForkliftError::InvalidConfigError(format!(
+                    "Empty path in share {}!",
+                    share
+                )));
+            }
+            Ok(path.to_path_buf())
+        }
+        FileSystemType::Samba => {
+            Ok(Path::new(&format!("smb://{}{}{}", server, share, path.to_string_lossy()))
+                .to_path_buf())
+        }
+    }
+}
+
 impl Input {
     //NOTE, send invalid config error when panicking
     /// create new Input object from config file
@@ -78,12 +189,13 @@ impl Input {
                 )));
             }
         };
-        if input.src_server.is_empty() {
+        let (src_system, dest_system) = input.systems()?;
+        if input.src_server.is_empty() && src_system != FileSystemType::Local {
             return Err(ForkliftError::InvalidConfigError(
                 "Error! source server not given!".to_string(),
             ));
         }
-        if input.dest_server.is_empty() {
+        if input.dest_server.is_empty() && dest_system != FileSystemType::Local {
             return Err(ForkliftError::InvalidConfigError(
                 "Error! destination server not given!".to_string(),
             ));
@@ -109,37 +221,56 @@ impl Input {
                 "Destination share does not start with '/'".to_string(),
             ));
         }
-        match input.system {
-            FileSystemType::Nfs => {
-                // if the input is empty, exit
-                if input.src_path.to_string_lossy().is_empty() {
-                    return Err(ForkliftError::InvalidConfigError("Empty source path!".to_string()));
-                }
-                if input.dest_path.to_string_lossy().is_empty() {
-                    return Err(ForkliftError::InvalidConfigError(
-                        "Empty destination path!".to_string(),
-                    ));
-                }
-            }
-            FileSystemType::Samba => {
-                input.src_path = Path::new(&format!(
-                    "smb://{}{}{}",
-                    input.src_server,
-                    input.src_share,
-                    input.src_path.to_string_lossy()
-                ))
-                .to_path_buf();
-                debug!("{:?}", input.src_path);
-                input.dest_path = Path::new(&format!(
-                    "smb://{}{}{}",
-                    input.dest_server,
-                    input.dest_share,
-                    input.dest_path.to_string_lossy()
-                ))
-                .to_path_buf();
-                debug!("{:?}", input.dest_path);
-            }
+        if input.files_from.is_some() && input.retry_failures.is_some() {
+            return Err(ForkliftError::InvalidConfigError(
+                "Error! files_from and retry_failures can not both be given!".to_string(),
+            ));
+        }
+        // split files are not checkpointed, so they could not be resumed from a temp file
+        if input.temp_files && input.chunked_copy_size.is_some() {
+            return Err(ForkliftError::InvalidConfigError(
+                "Error! temp_files and chunked_copy_size can not both be given!".to_string(),
+            ));
         }
+        input.src_path =
+            share_path(src_system, &input.src_server, &input.src_share, &input.src_path)?;
+        debug!("{:?}", input.src_path);
+        input.dest_path =
+            share_path(dest_system, &input.dest_server, &input.dest_share, &input.dest_path)?;
+        debug!("{:?}", input.dest_path);
         Ok(input)
     }
+
+    /// get the (source, destination) share types, either one defaulting to system
+    pub fn systems(&self) -> ForkliftResult<(FileSystemType, FileSystemType)> {
+        match (self.src_system.or(self.system), self.dest_system.or(self.system)) {
+            (Some(src_system), Some(dest_system)) => Ok((src_system, dest_system)),
+            _ => Err(ForkliftError::InvalidConfigError(
+                "Error! share type (system, src_system or dest_system) not given!".to_string(),
+            )),
+        }
+    }
+}
+
+#[test]
+fn test_share_types() {
+    let config = |systems: &str| {
+        format!(
+            r#"{{"nodes": ["127.0.0.1:7000"], "src_share": "/src", "dest_share": "/dest",
+                "debug_level": "OFF", "num_threads": 1, {}}}"#,
+            systems
+        )
+    };
+    // configs from before src_system and dest_system keep working
+    let input = Input::new_input(&config(r#""system": "Local""#)).unwrap();
+    assert_eq!(input.systems().unwrap(), (FileSystemType::Local, FileSystemType::Local));
+    let error = Input::new_input(&config(r#""system": "Local", "dest_system": "Nfs""#));
+    assert!(format!("{:?}", error.unwrap_err()).contains("destination server"));
+    let both = r#""system": "Nfs", "src_system": "Local", "dest_server": "filer""#;
+    let input = Input::new_input(&config(both)).unwrap();
+    assert_eq!(input.systems().unwrap(), (FileSystemType::Local, FileSystemType::Nfs));
+    assert!(Input::new_input(&config(r#""src_system": "Local""#)).is_err());
+    let chunked = r#""system": "Local", "temp_files": true, "chunked_copy_size": 1073741824"#;
+    let error = Input::new_input(&config(chunked));
+    assert!(format!("{:?}", error.unwrap_err()).contains("chunked_copy_size"));
 }
@@ -0,0 +1,360 @@
+use crate::error::{ForkliftError, ForkliftResult};
+use crate::filesystem::{GenericFileType, Stat, Timespec};
+
+use filetime::FileTime;
+use nix::fcntl::OFlag;
+use nix::libc;
+use nix::sys::stat::{Mode, SFlag};
+
+use std::ffi::CString;
+use std::fs::{self, DirBuilder, Metadata, OpenOptions, Permissions};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, DirBuilderExt, FileExt, FileTypeExt, MetadataExt,
+                        OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+/// convert std Metadata into a generic Stat
+fn metadata_to_stat(meta: &Metadata) -> Stat {
+    let atime = Timespec::new(meta.atime(), meta.atime_nsec());
+    let mtime = Timespec::new(meta.mtime(), meta.mtime_nsec());
+    let ctime = Timespec::new(meta.ctime(), meta.ctime_nsec());
+    let s = (
+        meta.dev(),
+        meta.ino(),
+        meta.mode(),
+        meta.nlink(),
+        meta.uid(),
+        meta.gid(),
+        meta.rdev(),
+        meta.size() as i64,
+        meta.blksize() as i64,
+        meta.blocks() as i64,
+    );
+    Stat::new(s, atime, mtime, ctime)
+}
+
+/// build the OpenOptions matching a set of open(2) flags
+fn open_options(flags: OFlag, mode: Mode) -> OpenOptions {
+    let mut options = OpenOptions::new();
+    match flags & OFlag::O_ACCMODE {
+        f if f == OFlag::O_WRONLY => options.write(true),
+        f if f == OFlag::O_RDWR => options.read(true).write(true),
+        _ => options.read(true),
+    };
+    if flags.contains(OFlag::O_CREAT) {
+        // O_CREAT requires write access through OpenOptions
+        options.write(true).create(true);
+    }
+    options.custom_flags((flags & !(OFlag::O_ACCMODE | OFlag::O_CREAT)).bits()).mode(mode.bits());
+    options
+}
+
+#[derive(Clone, Debug)]
+/// a filesystem context rooted at a locally mounted directory
+/// (local disk, kernel NFS/CIFS mount, FUSE mount, etc.)
+pub struct LocalContext {
+    /// the directory all paths given to this context are relative to
+    root: PathBuf,
+}
+
+impl LocalContext {
+    /// create a new LocalContext rooted at root, which must be an existing directory
+    pub fn new(root: &Path) -> ForkliftResult<Self> {
+        match fs::metadata(root) {
+            Ok(ref meta) if meta.is_dir() => Ok(LocalContext { root: root.to_path_buf() }),
+            Ok(_) => Err(ForkliftError::FSError(format!("{:?} is not a directory", root))),
+            Err(e) => Err(ForkliftError::FSError(format!(
+                "Error {}, unable to access local root {:?}",
+                e, root
+            ))),
+        }
+    }
+
+    /// resolve a share path (ex. /dir/file) to the real path under the root.
+    /// Paths with .. components are refused, so nothing outside the root is reached
+    fn full_path(&self, path: &Path) -> ForkliftResult<PathBuf> {
+        let mut rel = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => rel.push(name),
+                Component::ParentDir => {
+                    let err = format!("{:?} leads outside of the share", path);
+                    return Err(ForkliftError::IoError(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        err,
+                    )));
+                }
+                _ => (),
+            }
+        }
+        Ok(self.root.join(rel))
+    }
+
+    /// create a file at path (O_CREAT is implied)
+    pub fn create(&self, path: &Path, flags: OFlag, mode: Mode) -> ForkliftResult<LocalFile> {
+        self.open(path, (flags & !OFlag::O_ACCMODE) | OFlag::O_CREAT | OFlag::O_RDWR, mode)
+    }
+
+    /// resolve a share path to the real path under the root as a C string
+    fn c_path(&self, path: &Path) -> ForkliftResult<CString> {
+        match CString::new(self.full_path(path)?.as_os_str().as_bytes()) {
+            Ok(c_path) => Ok(c_path),
+            Err(e) => Err(ForkliftError::IoError(io::Error::new(io::ErrorKind::InvalidInput, e))),
+        }
+    }
+
+    /// open a file at path
+    pub fn open(&self, path: &Path, flags: OFlag, mode: Mode) -> ForkliftResult<LocalFile> {
+        let file = open_options(flags, mode).open(self.full_path(path)?)?;
+        Ok(LocalFile { file: Arc::new(file) })
+    }
+
+    /// change the permissions of the file at path, following symlinks
+    pub fn chmod(&self, path: &Path, mode: Mode) -> ForkliftResult<()> {
+        fs::set_permissions(self.full_path(path)?, Permissions::from_mode(mode.bits()))?;
+        Ok(())
+    }
+
+    /// change the owner and group of the file at path, following symlinks
+    pub fn chown(&self, path: &Path, uid: u32, gid: u32) -> ForkliftResult<()> {
+        let c_path = self.c_path(path)?;
+        if unsafe { libc::chown(c_path.as_ptr(), uid, gid) } != 0 {
+            return Err(ForkliftError::IoError(io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    /// change the owner and group of the file at path, without following symlinks
+    pub fn lchown(&self, path: &Path, uid: u32, gid: u32) -> ForkliftResult<()> {
+        let c_path = self.c_path(path)?;
+        if unsafe { libc::lchown(c_path.as_ptr(), uid, gid) } != 0 {
+            return Err(ForkliftError::IoError(io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    /// get the metadata of path without following symlinks
+    pub fn lstat(&self, path: &Path) -> ForkliftResult<Stat> {
+        let meta = fs::symlink_metadata(self.full_path(path)?)?;
+        Ok(metadata_to_stat(&meta))
+    }
+
+    /// create a hard link at newpath to the file at oldpath
+    pub fn link(&self, oldpath: &Path, newpath: &Path) -> ForkliftResult<()> {
+        fs::hard_link(self.full_path(oldpath)?, self.full_path(newpath)?)?;
+        Ok(())
+    }
+
+    /// create a special file of kind at path, with device number dev
+    pub fn mknod(&self, path: &Path, kind: SFlag, mode: Mode, dev: u64) -> ForkliftResult<()> {
+        let c_path = self.c_path(path)?;
+        let mode = (kind.bits() | mode.bits()) as libc::mode_t;
+        if unsafe { libc::mknod(c_path.as_ptr(), mode, dev as libc::dev_t) } != 0 {
+            return Err(ForkliftError::IoError(io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    /// make a new directory at path
+    pub fn mkdir(&self, path: &Path) -> ForkliftResult<()> {
+        DirBuilder::new().mode(0o777).create(self.full_path(path)?)?;
+        Ok(())
+    }
+
+    /// open a directory and read all of its entries
+    pub fn opendir(&self, path: &Path) -> ForkliftResult<LocalDirectory> {
+        let mut entries = vec![];
+        for entry in fs::read_dir(self.full_path(path)?)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            let d_type = if file_type.is_dir() {
+                GenericFileType::Directory
+            } else if file_type.is_file() {
+                GenericFileType::File
+            } else if file_type.is_symlink() {
+                GenericFileType::Link
+            } else if file_type.is_fifo() {
+                GenericFileType::Fifo
+            } else if file_type.is_char_device() {
+                GenericFileType::CharDevice
+            } else if file_type.is_block_device() {
+                GenericFileType::BlockDevice
+            } else if file_type.is_socket() {
+                GenericFileType::Socket
+            } else {
+                GenericFileType::Other
+            };
+            entries.push(LocalDirEntry { path: PathBuf::from(entry.file_name()), d_type });
+        }
+        Ok(LocalDirectory { entries: entries.into_iter() })
+    }
+
+    /// read the target of the symlink at path into buf
+    pub fn readlink(&self, path: &Path, buf: &mut [u8]) -> ForkliftResult<()> {
+        let target = fs::read_link(self.full_path(path)?)?;
+        let bytes = target.as_os_str().as_bytes();
+        if bytes.len() > buf.len() {
+            return Err(ForkliftError::FSError(format!("Link target of {:?} too long", path)));
+        }
+        buf[..bytes.len()].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    /// rename a file/directory
+    pub fn rename(&self, oldpath: &Path, newpath: &Path) -> ForkliftResult<()> {
+        fs::rename(self.full_path(oldpath)?, self.full_path(newpath)?)?;
+        Ok(())
+    }
+
+    /// remove an empty directory
+    pub fn rmdir(&self, path: &Path) -> ForkliftResult<()> {
+        fs::remove_dir(self.full_path(path)?)?;
+        Ok(())
+    }
+
+    /// create a symlink at newpath pointing to oldpath.
+    /// @note oldpath is stored as-is, it is not resolved against the root
+    pub fn symlink(&self, oldpath: &Path, newpath: &Path) -> ForkliftResult<()> {
+        symlink(oldpath, self.full_path(newpath)?)?;
+        Ok(())
+    }
+
+    /// remove a file or symlink
+    pub fn unlink(&self, path: &Path) -> ForkliftResult<()> {
+        fs::remove_file(self.full_path(path)?)?;
+        Ok(())
+    }
+
+    /// set the access and modification times of path, following symlinks
+    pub fn utimes(&self, path: &Path, atime: Timespec, mtime: Timespec) -> ForkliftResult<()> {
+        let (atime, mtime) = (
+            FileTime::from_unix_time(atime.tv_sec(), atime.tv_nsec() as u32),
+            FileTime::from_unix_time(mtime.tv_sec(), mtime.tv_nsec() as u32),
+        );
+        filetime::set_file_times(self.full_path(path)?, atime, mtime)?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+/// a handle to a file opened through a LocalContext
+pub struct LocalFile {
+    /// the shared open file
+    file: Arc<fs::File>,
+}
+
+impl LocalFile {
+    /// read up to count bytes starting at offset
+    pub fn pread(&self, count: u64, offset: u64) -> ForkliftResult<Vec<u8>> {
+        let mut buf = vec![0; count as usize];
+        let mut total = 0;
+        while total < buf.len() {
+            let n = self.file.read_at(&mut buf[total..], offset + total as u64)?;
+            if n == 0 {
+                break;
+            }
+            total += n;
+        }
+        buf.truncate(total);
+        Ok(buf)
+    }
+
+    /// write buf to the file starting at offset
+    pub fn pwrite(&self, buf: &[u8], offset: u64) -> ForkliftResult<u64> {
+        self.file.write_all_at(buf, offset)?;
+        Ok(buf.len() as u64)
+    }
+
+    /// get the metadata of the open file
+    pub fn fstat(&self) -> ForkliftResult<Stat> {
+        let meta = self.file.metadata()?;
+        Ok(metadata_to_stat(&meta))
+    }
+
+    /// truncate the file to size
+    pub fn ftruncate(&self, size: u64) -> ForkliftResult<()> {
+        self.file.set_len(size)?;
+        Ok(())
+    }
+
+    /// get the start of the first data at or after offset, or None if there is none
+    pub fn seek_data(&self, offset: u64) -> ForkliftResult<Option<u64>> {
+        match self.lseek(offset, libc::SEEK_DATA) {
+            Ok(data) => Ok(Some(data)),
+            // ENXIO means there is no data after offset
+            Err(ref e) if e.raw_os_error() == Some(libc::ENXIO) => Ok(None),
+            Err(e) => Err(ForkliftError::IoError(e)),
+        }
+    }
+
+    /// get the start of the first hole at or after offset
+    pub fn seek_hole(&self, offset: u64) -> ForkliftResult<u64> {
+        match self.lseek(offset, libc::SEEK_HOLE) {
+            Ok(hole) => Ok(hole),
+            // ENXIO means offset is past the end of the file
+            Err(ref e) if e.raw_os_error() == Some(libc::ENXIO) => Ok(offset),
+            Err(e) => Err(ForkliftError::IoError(e)),
+        }
+    }
+
+    /// helper for the seek functions
+    fn lseek(&self, offset: u64, whence: i32) -> io::Result<u64> {
+        let fd = self.file.as_raw_fd();
+        let ret = unsafe { libc::lseek(fd, offset as libc::off_t, whence) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ret as u64)
+    }
+}
+
+#[derive(Clone, Debug)]
+/// an entry of a LocalDirectory
+pub struct LocalDirEntry {
+    /// name of the entry
+    pub path: PathBuf,
+    /// type of the entry (symlinks are not followed)
+    pub d_type: GenericFileType,
+}
+
+#[derive(Clone, Debug)]
+/// the entries of a directory opened through a LocalContext
+pub struct LocalDirectory {
+    /// remaining entries
+    entries: std::vec::IntoIter<LocalDirEntry>,
+}
+
+impl Iterator for LocalDirectory {
+    type Item = LocalDirEntry;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.entries.next()
+    }
+}
+
+#[test]
+fn test_full_path_stays_in_root() {
+    let root = std::env::temp_dir();
+    let context = LocalContext::new(&root).unwrap();
+    assert_eq!(context.full_path(Path::new("/dir/./file")).unwrap(), root.join("dir/file"));
+    assert!(context.full_path(Path::new("/dir/../../etc/passwd")).is_err());
+    assert!(context.lstat(Path::new("/..")).is_err());
+}
+
+#[test]
+fn test_readlink_keeps_bytes() {
+    use std::ffi::OsStr;
+
+    let root = std::env::temp_dir().join(format!("forklift-readlink-{}", std::process::id()));
+    fs::create_dir_all(&root).unwrap();
+    let target = OsStr::from_bytes(b"caf\xe9");
+    symlink(target, root.join("link")).unwrap();
+    let context = LocalContext::new(&root).unwrap();
+    let mut buf = vec![0; 16];
+    let read = context.readlink(Path::new("/link"), &mut buf);
+    fs::remove_dir_all(&root).unwrap();
+    read.unwrap();
+    assert_eq!(&buf[..5], b"caf\xe9\0");
+}
@@ -13,24 +13,37 @@ use std::net::SocketAddr;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
 
+mod checkpoint;
+mod chunked_copy;
 mod cluster;
 mod console_output;
+mod content_hash;
+mod delta;
 mod error;
+mod failures;
 mod filesystem;
 mod filesystem_entry;
 mod filesystem_ops;
+mod filter;
+mod id_map;
 mod input;
+mod local_filesystem;
 mod local_ip;
+mod memory_filesystem;
 mod message;
+mod mf_symlink;
 mod node;
+mod plan;
 mod postgres_logger;
 mod progress_message;
 mod progress_worker;
 mod pulse;
+mod retry;
 mod rsync;
 mod rsync_worker;
 mod socket_node;
 mod tables;
+mod verify;
 mod walk_worker;
 
 use crate::cluster::Cluster;
@@ -220,6 +233,7 @@ fn init_args() -> ForkliftResult<(String, String, Input)> {
         }
     }
     let input = load_config(config_dir, "forklift.json")?;
+    let systems = input.systems()?;
 
     let mut username = matches.value_of("username").unwrap().to_string();
     let mut password = matches.value_of("password").unwrap().to_string();
@@ -228,16 +242,9 @@ fn init_args() -> ForkliftResult<(String, String, Input)> {
         if iter != 0 {
             println!("Username cannot be \\n");
         }
-        match input.system {
-            FileSystemType::Nfs => {
-                if username.is_empty() {
-                    username = "guest".to_string();
-                }
-                if password.is_empty() {
-                    password = LINE_ENDING.to_string();
-                }
-            }
-            FileSystemType::Samba => {
+        // Samba credentials are needed if either side of the sync is Samba
+        match systems {
+            (FileSystemType::Samba, _) | (_, FileSystemType::Samba) => {
                 if username.is_empty() || username == LINE_ENDING {
                     print!("Please enter your username: ");
                     stdout().flush()?;
@@ -251,6 +258,14 @@ fn init_args() -> ForkliftResult<(String, String, Input)> {
                     password = (&password).trim_end().to_string();
                 }
             }
+            (..) => {
+                if username.is_empty() {
+                    username = "guest".to_string();
+                }
+                if password.is_empty() {
+                    password = LINE_ENDING.to_string();
+                }
+            }
         }
         iter += 1;
     }
@@ -344,7 +359,6 @@ fn main() -> ForkliftResult<()> {
         send_mess(LogMessage::Error(e), &log_output)?;
     };
     let console_info = ConsoleProgressOutput::new();
-    let system = input.system;
 
     let send_nodes = RendezvousNodes::default();
     let active_nodes = Arc::new(Mutex::new(send_nodes));
@@ -352,7 +366,7 @@ fn main() -> ForkliftResult<()> {
     let syncer = Rsyncer::new(
         input.src_path,
         input.dest_path,
-        system,
+        systems,
         Box::new(console_info),
         log_output.clone(),
     );
@@ -0,0 +1,692 @@
+use crate::error::{ForkliftError, ForkliftResult};
+use crate::filesystem::{GenericFileType, Stat, Timespec};
+
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::sys::stat::{Mode, SFlag};
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// block size reported by memory files
+const MEMORY_BLKSIZE: i64 = 4096;
+
+/// get the current time as a Timespec
+fn now() -> Timespec {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => Timespec::new(d.as_secs() as i64, i64::from(d.subsec_nanos())),
+        Err(_) => Timespec::new(0, 0),
+    }
+}
+
+/// create an io error from an errno, so memory errors look like real syscall errors
+fn errno(err: Errno) -> ForkliftError {
+    ForkliftError::IoError(io::Error::from(err))
+}
+
+/// get the blocks holding any of the bytes from start to end
+fn blocks(start: u64, end: u64) -> std::ops::Range<u64> {
+    let size = MEMORY_BLKSIZE as u64;
+    start / size..(end + size - 1) / size
+}
+
+/// normalize a path to an absolute path without '.' or '..' components
+fn normalize(path: &Path) -> PathBuf {
+    let mut norm = PathBuf::from("/");
+    for component in path.components() {
+        if let Component::Normal(c) = component {
+            norm.push(c);
+        }
+    }
+    norm
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// the contents of a memory inode
+pub enum MemoryData {
+    /// a regular file and its bytes
+    File(Vec<u8>),
+    /// a directory
+    Directory,
+    /// a symlink and its target
+    Symlink(PathBuf),
+    /// a fifo, device, or socket, with its file type and device number
+    Special(SFlag, u64),
+}
+
+#[derive(Clone, Debug)]
+/// a file, directory, or symlink stored in a MemoryContext
+struct MemoryInode {
+    /// contents of the inode
+    data: MemoryData,
+    /// permission bits (without file type)
+    mode: u32,
+    /// User ID of the owner
+    uid: u32,
+    /// Group ID of the owner
+    gid: u32,
+    /// number of paths linked to this inode
+    nlink: u64,
+    /// time of last Access
+    atime: Timespec,
+    /// time of last modification
+    mtime: Timespec,
+    /// time of last status change
+    ctime: Timespec,
+    /// blocks of a file that hold data, the rest of the file is holes
+    allocated: BTreeSet<u64>,
+}
+
+impl MemoryInode {
+    /// create a new inode with the current time
+    fn new(data: MemoryData, mode: u32) -> Self {
+        let time = now();
+        let allocated = match &data {
+            MemoryData::File(buf) => blocks(0, buf.len() as u64).collect(),
+            _ => BTreeSet::new(),
+        };
+        MemoryInode {
+            data,
+            mode,
+            uid: 0,
+            gid: 0,
+            nlink: 1,
+            atime: time,
+            mtime: time,
+            ctime: time,
+            allocated,
+        }
+    }
+    /// get the generic filetype of the inode
+    fn filetype(&self) -> GenericFileType {
+        match self.data {
+            MemoryData::File(_) => GenericFileType::File,
+            MemoryData::Directory => GenericFileType::Directory,
+            MemoryData::Symlink(_) => GenericFileType::Link,
+            MemoryData::Special(kind, _) => GenericFileType::from_mode(kind.bits()),
+        }
+    }
+    /// convert the inode into a generic Stat
+    fn stat(&self, dev: u64, ino: u64) -> Stat {
+        let (kind, size, rdev) = match &self.data {
+            MemoryData::File(buf) => (SFlag::S_IFREG, buf.len() as i64, 0),
+            MemoryData::Directory => (SFlag::S_IFDIR, MEMORY_BLKSIZE, 0),
+            MemoryData::Symlink(target) => {
+                (SFlag::S_IFLNK, target.to_string_lossy().len() as i64, 0)
+            }
+            MemoryData::Special(kind, rdev) => (*kind, 0, *rdev),
+        };
+        let s = (
+            dev,
+            ino,
+            kind.bits() | self.mode,
+            self.nlink,
+            self.uid,
+            self.gid,
+            rdev,
+            size,
+            MEMORY_BLKSIZE,
+            match self.data {
+                MemoryData::File(_) => self.allocated.len() as i64 * MEMORY_BLKSIZE / 512,
+                _ => (size + 511) / 512,
+            },
+        );
+        Stat::new(s, self.atime, self.mtime, self.ctime)
+    }
+}
+
+#[derive(Debug, Default)]
+/// the shared state of a MemoryContext
+struct MemoryTree {
+    /// all paths in the tree mapped to their inode number
+    paths: BTreeMap<PathBuf, u64>,
+    /// inode number to inode
+    inodes: HashMap<u64, MemoryInode>,
+    /// next free inode number
+    next_ino: u64,
+}
+
+impl MemoryTree {
+    /// get the inode number at path
+    fn lookup(&self, path: &Path) -> ForkliftResult<u64> {
+        match self.paths.get(path) {
+            Some(ino) => Ok(*ino),
+            None => Err(errno(Errno::ENOENT)),
+        }
+    }
+    /// get the inode at path
+    fn inode(&self, path: &Path) -> ForkliftResult<&MemoryInode> {
+        let ino = self.lookup(path)?;
+        match self.inodes.get(&ino) {
+            Some(inode) => Ok(inode),
+            None => Err(errno(Errno::ENOENT)),
+        }
+    }
+    /// get the inode at path mutably
+    fn inode_mut(&mut self, path: &Path) -> ForkliftResult<&mut MemoryInode> {
+        let ino = self.lookup(path)?;
+        match self.inodes.get_mut(&ino) {
+            Some(inode) => Ok(inode),
+            None => Err(errno(Errno::ENOENT)),
+        }
+    }
+    /// check that the parent of path exists and is a directory
+    fn check_parent(&self, path: &Path) -> ForkliftResult<()> {
+        match path.parent() {
+            Some(parent) => match self.inode(parent)?.data {
+                MemoryData::Directory => Ok(()),
+                _ => Err(errno(Errno::ENOTDIR)),
+            },
+            None => Err(errno(Errno::EEXIST)),
+        }
+    }
+    /// add a new inode at path, failing if the path exists or has no parent
+    fn insert(&mut self, path: &Path, inode: MemoryInode) -> ForkliftResult<u64> {
+        if self.paths.contains_key(path) {
+            return Err(errno(Errno::EEXIST));
+        }
+        self.check_parent(path)?;
+        self.next_ino += 1;
+        let ino = self.next_ino;
+        self.inodes.insert(ino, inode);
+        self.paths.insert(path.to_path_buf(), ino);
+        self.touch_parent(path);
+        Ok(ino)
+    }
+    /// remove the path, dropping the inode once nothing links to it
+    fn remove(&mut self, path: &Path) -> ForkliftResult<()> {
+        let ino = match self.paths.remove(path) {
+            Some(ino) => ino,
+            None => return Err(errno(Errno::ENOENT)),
+        };
+        let drop_inode = match self.inodes.get_mut(&ino) {
+            Some(inode) => {
+                inode.nlink = inode.nlink.saturating_sub(1);
+                inode.ctime = now();
+                inode.nlink == 0
+            }
+            None => false,
+        };
+        if drop_inode {
+            self.inodes.remove(&ino);
+        }
+        self.touch_parent(path);
+        Ok(())
+    }
+    /// update the modification time of the parent of path, as adding or
+    /// removing an entry does on a real filesystem
+    fn touch_parent(&mut self, path: &Path) {
+        if let Some(parent) = path.parent() {
+            if let Ok(inode) = self.inode_mut(parent) {
+                let time = now();
+                inode.mtime = time;
+                inode.ctime = time;
+            }
+        }
+    }
+    /// get the direct children of a directory path
+    fn children(&self, path: &Path) -> Vec<(PathBuf, u64)> {
+        self.paths
+            .range(path.to_path_buf()..)
+            .skip_while(|(p, _)| p.as_path() == path)
+            .take_while(|(p, _)| p.starts_with(path))
+            .filter(|(p, _)| p.parent() == Some(path))
+            .map(|(p, ino)| (p.clone(), *ino))
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug)]
+/// an in-memory filesystem context of files, directories, and symlinks.
+/// Clones share the same tree, so a test can keep a clone to inspect
+/// the results of a sync.
+pub struct MemoryContext {
+    /// device id reported in Stat
+    dev: u64,
+    /// chown fails with EPERM, as it does for unprivileged users
+    chown_denied: bool,
+    /// the shared tree
+    tree: Arc<Mutex<MemoryTree>>,
+}
+
+impl Default for MemoryContext {
+    fn default() -> Self {
+        MemoryContext::new(0)
+    }
+}
+
+impl MemoryContext {
+    /// create a new MemoryContext containing only the root directory
+    pub fn new(dev: u64) -> Self {
+        let mut tree = MemoryTree { next_ino: 1, ..MemoryTree::default() };
+        tree.inodes.insert(1, MemoryInode::new(MemoryData::Directory, 0o755));
+        tree.paths.insert(PathBuf::from("/"), 1);
+        MemoryContext { dev, chown_denied: false, tree: Arc::new(Mutex::new(tree)) }
+    }
+
+    /// get a context sharing the tree of this one, but reporting the device id dev,
+    /// like the mount of the same share on another machine
+    pub fn with_dev(&self, dev: u64) -> Self {
+        MemoryContext { dev, ..self.clone() }
+    }
+
+    /// get a context sharing the tree of this one, on which chown fails with EPERM,
+    /// like the mount of an unprivileged user
+    pub fn without_chown(&self) -> Self {
+        MemoryContext { chown_denied: true, ..self.clone() }
+    }
+
+    /// lock the shared tree
+    fn lock(&self) -> ForkliftResult<MutexGuard<'_, MemoryTree>> {
+        match self.tree.lock() {
+            Ok(tree) => Ok(tree),
+            Err(_) => Err(ForkliftError::FSError("Poisoned memory filesystem".to_string())),
+        }
+    }
+
+    /// add a file with contents and permission bits mode, creating any missing
+    /// parent directories
+    pub fn add_file(&self, path: &Path, contents: &[u8], mode: u32) -> ForkliftResult<()> {
+        self.add(path, MemoryData::File(contents.to_vec()), mode)
+    }
+
+    /// add a directory with permission bits mode, creating any missing parent directories
+    pub fn add_dir(&self, path: &Path, mode: u32) -> ForkliftResult<()> {
+        self.add(path, MemoryData::Directory, mode)
+    }
+
+    /// add a symlink pointing to target, creating any missing parent directories
+    pub fn add_symlink(&self, path: &Path, target: &Path) -> ForkliftResult<()> {
+        self.add(path, MemoryData::Symlink(target.to_path_buf()), 0o777)
+    }
+
+    /// helper for the add functions
+    fn add(&self, path: &Path, data: MemoryData, mode: u32) -> ForkliftResult<()> {
+        let path = normalize(path);
+        let mut tree = self.lock()?;
+        let mut missing = vec![];
+        let mut parent = path.parent();
+        while let Some(p) = parent {
+            if tree.paths.contains_key(p) {
+                break;
+            }
+            missing.push(p.to_path_buf());
+            parent = p.parent();
+        }
+        while let Some(dir) = missing.pop() {
+            tree.insert(&dir, MemoryInode::new(MemoryData::Directory, 0o755))?;
+        }
+        tree.insert(&path, MemoryInode::new(data, mode))?;
+        Ok(())
+    }
+
+    /// get the contents of path, or None if path does not exist
+    pub fn data(&self, path: &Path) -> Option<MemoryData> {
+        let tree = self.lock().ok()?;
+        let inode = tree.inode(&normalize(path)).ok()?;
+        Some(inode.data.clone())
+    }
+
+    /// get all paths in the filesystem (including "/") in sorted order
+    pub fn paths(&self) -> Vec<PathBuf> {
+        match self.lock() {
+            Ok(tree) => tree.paths.keys().cloned().collect(),
+            Err(_) => vec![],
+        }
+    }
+
+    /// create a file at path (O_CREAT is implied)
+    pub fn create(&self, path: &Path, flags: OFlag, mode: Mode) -> ForkliftResult<MemoryFile> {
+        self.open(path, flags | OFlag::O_CREAT, mode)
+    }
+
+    /// open a file at path
+    pub fn open(&self, path: &Path, flags: OFlag, mode: Mode) -> ForkliftResult<MemoryFile> {
+        let path = normalize(path);
+        let mut tree = self.lock()?;
+        let ino = match tree.lookup(&path) {
+            Ok(ino) => {
+                if flags.contains(OFlag::O_CREAT | OFlag::O_EXCL) {
+                    return Err(errno(Errno::EEXIST));
+                }
+                ino
+            }
+            Err(_) if flags.contains(OFlag::O_CREAT) => {
+                let inode = MemoryInode::new(MemoryData::File(vec![]), mode.bits());
+                tree.insert(&path, inode)?
+            }
+            Err(e) => return Err(e),
+        };
+        let inode = tree.inode_mut(&path)?;
+        match &mut inode.data {
+            MemoryData::File(buf) => {
+                if flags.contains(OFlag::O_TRUNC) {
+                    buf.clear();
+                    inode.allocated.clear();
+                }
+            }
+            MemoryData::Directory => return Err(errno(Errno::EISDIR)),
+            MemoryData::Symlink(_) => return Err(errno(Errno::ELOOP)),
+            MemoryData::Special(..) => return Err(errno(Errno::ENXIO)),
+        }
+        Ok(MemoryFile { dev: self.dev, ino, tree: self.tree.clone() })
+    }
+
+    /// change the permissions of the file at path
+    pub fn chmod(&self, path: &Path, mode: Mode) -> ForkliftResult<()> {
+        let mut tree = self.lock()?;
+        let inode = tree.inode_mut(&normalize(path))?;
+        inode.mode = mode.bits() & 0o7777;
+        inode.ctime = now();
+        Ok(())
+    }
+
+    /// change the owner and group of the file at path.
+    /// @note symlinks are not followed, as the memory filesystem does not resolve them
+    pub fn chown(&self, path: &Path, uid: u32, gid: u32) -> ForkliftResult<()> {
+        if self.chown_denied {
+            return Err(errno(Errno::EPERM));
+        }
+        let mut tree = self.lock()?;
+        let inode = tree.inode_mut(&normalize(path))?;
+        inode.uid = uid;
+        inode.gid = gid;
+        inode.ctime = now();
+        Ok(())
+    }
+
+    /// get the metadata of path without following symlinks
+    pub fn lstat(&self, path: &Path) -> ForkliftResult<Stat> {
+        let path = normalize(path);
+        let tree = self.lock()?;
+        let ino = tree.lookup(&path)?;
+        Ok(tree.inode(&path)?.stat(self.dev, ino))
+    }
+
+    /// create a hard link at newpath to the file at oldpath
+    pub fn link(&self, oldpath: &Path, newpath: &Path) -> ForkliftResult<()> {
+        let (oldpath, newpath) = (normalize(oldpath), normalize(newpath));
+        let mut tree = self.lock()?;
+        if tree.inode(&oldpath)?.data == MemoryData::Directory {
+            return Err(errno(Errno::EPERM));
+        }
+        if tree.paths.contains_key(&newpath) {
+            return Err(errno(Errno::EEXIST));
+        }
+        tree.check_parent(&newpath)?;
+        let ino = tree.lookup(&oldpath)?;
+        let inode = tree.inode_mut(&oldpath)?;
+        inode.nlink += 1;
+        inode.ctime = now();
+        tree.paths.insert(newpath.clone(), ino);
+        tree.touch_parent(&newpath);
+        Ok(())
+    }
+
+    /// create a special file of kind at path, with device number dev
+    pub fn mknod(&self, path: &Path, kind: SFlag, mode: Mode, dev: u64) -> ForkliftResult<()> {
+        if !GenericFileType::from_mode(kind.bits()).is_special() {
+            return Err(errno(Errno::EINVAL));
+        }
+        let mut tree = self.lock()?;
+        let inode = MemoryInode::new(MemoryData::Special(kind, dev), mode.bits());
+        tree.insert(&normalize(path), inode)?;
+        Ok(())
+    }
+
+    /// make a new directory at path
+    pub fn mkdir(&self, path: &Path) -> ForkliftResult<()> {
+        let mut tree = self.lock()?;
+        tree.insert(&normalize(path), MemoryInode::new(MemoryData::Directory, 0o755))?;
+        Ok(())
+    }
+
+    /// open a directory and read all of its entries
+    pub fn opendir(&self, path: &Path) -> ForkliftResult<MemoryDirectory> {
+        let path = normalize(path);
+        let tree = self.lock()?;
+        if tree.inode(&path)?.data != MemoryData::Directory {
+            return Err(errno(Errno::ENOTDIR));
+        }
+        let mut entries = vec![];
+        for (child, ino) in tree.children(&path) {
+            let d_type = match tree.inodes.get(&ino) {
+                Some(inode) => inode.filetype(),
+                None => GenericFileType::Other,
+            };
+            if let Some(name) = child.file_name() {
+                entries.push(MemoryDirEntry { path: PathBuf::from(name), d_type });
+            }
+        }
+        Ok(MemoryDirectory { entries: entries.into_iter() })
+    }
+
+    /// read the target of the symlink at path into buf
+    pub fn readlink(&self, path: &Path, buf: &mut [u8]) -> ForkliftResult<()> {
+        let tree = self.lock()?;
+        match &tree.inode(&normalize(path))?.data {
+            MemoryData::Symlink(target) => {
+                let target = target.to_string_lossy();
+                let bytes = target.as_bytes();
+                if bytes.len() > buf.len() {
+                    return Err(errno(Errno::ENAMETOOLONG));
+                }
+                buf[..bytes.len()].copy_from_slice(bytes);
+                Ok(())
+            }
+            _ => Err(errno(Errno::EINVAL)),
+        }
+    }
+
+    /// rename a file/directory, replacing newpath if it is not a non-empty directory
+    pub fn rename(&self, oldpath: &Path, newpath: &Path) -> ForkliftResult<()> {
+        let (oldpath, newpath) = (normalize(oldpath), normalize(newpath));
+        let mut tree = self.lock()?;
+        tree.lookup(&oldpath)?;
+        tree.check_parent(&newpath)?;
+        if newpath.starts_with(&oldpath) && newpath != oldpath {
+            return Err(errno(Errno::EINVAL));
+        }
+        if let Ok(ino) = tree.lookup(&newpath) {
+            if !tree.children(&newpath).is_empty() {
+                return Err(errno(Errno::ENOTEMPTY));
+            }
+            if ino != tree.lookup(&oldpath)? {
+                tree.remove(&newpath)?;
+            }
+        }
+        let moved: Vec<(PathBuf, u64)> = tree
+            .paths
+            .iter()
+            .filter(|(p, _)| p.starts_with(&oldpath))
+            .map(|(p, ino)| (p.clone(), *ino))
+            .collect();
+        for (path, ino) in moved {
+            tree.paths.remove(&path);
+            let rel = path.strip_prefix(&oldpath).unwrap_or_else(|_| Path::new(""));
+            tree.paths.insert(newpath.join(rel), ino);
+        }
+        tree.touch_parent(&oldpath);
+        tree.touch_parent(&newpath);
+        Ok(())
+    }
+
+    /// remove an empty directory
+    pub fn rmdir(&self, path: &Path) -> ForkliftResult<()> {
+        let path = normalize(path);
+        let mut tree = self.lock()?;
+        if tree.inode(&path)?.data != MemoryData::Directory {
+            return Err(errno(Errno::ENOTDIR));
+        }
+        if !tree.children(&path).is_empty() {
+            return Err(errno(Errno::ENOTEMPTY));
+        }
+        if path.parent().is_none() {
+            return Err(errno(Errno::EBUSY));
+        }
+        tree.remove(&path)
+    }
+
+    /// create a symlink at newpath pointing to oldpath
+    pub fn symlink(&self, oldpath: &Path, newpath: &Path) -> ForkliftResult<()> {
+        let mut tree = self.lock()?;
+        let inode = MemoryInode::new(MemoryData::Symlink(oldpath.to_path_buf()), 0o777);
+        tree.insert(&normalize(newpath), inode)?;
+        Ok(())
+    }
+
+    /// remove a file or symlink
+    pub fn unlink(&self, path: &Path) -> ForkliftResult<()> {
+        let path = normalize(path);
+        let mut tree = self.lock()?;
+        if tree.inode(&path)?.data == MemoryData::Directory {
+            return Err(errno(Errno::EISDIR));
+        }
+        tree.remove(&path)
+    }
+
+    /// set the access and modification times of path
+    pub fn utimes(&self, path: &Path, atime: Timespec, mtime: Timespec) -> ForkliftResult<()> {
+        let mut tree = self.lock()?;
+        let inode = tree.inode_mut(&normalize(path))?;
+        inode.atime = atime;
+        inode.mtime = mtime;
+        inode.ctime = now();
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+/// a handle to a file opened through a MemoryContext
+pub struct MemoryFile {
+    /// device id reported in Stat
+    dev: u64,
+    /// inode number of the open file
+    ino: u64,
+    /// the shared tree
+    tree: Arc<Mutex<MemoryTree>>,
+}
+
+impl MemoryFile {
+    /// run f on the open inode
+    fn with_inode<T>(&self, f: impl FnOnce(&mut MemoryInode) -> T) -> ForkliftResult<T> {
+        let mut tree = match self.tree.lock() {
+            Ok(tree) => tree,
+            Err(_) => {
+                return Err(ForkliftError::FSError("Poisoned memory filesystem".to_string()));
+            }
+        };
+        match tree.inodes.get_mut(&self.ino) {
+            Some(inode) => Ok(f(inode)),
+            None => Err(errno(Errno::EBADF)),
+        }
+    }
+
+    /// read up to count bytes starting at offset
+    pub fn pread(&self, count: u64, offset: u64) -> ForkliftResult<Vec<u8>> {
+        self.with_inode(|inode| match &inode.data {
+            MemoryData::File(buf) => {
+                let start = (offset as usize).min(buf.len());
+                let end = (offset.saturating_add(count) as usize).min(buf.len());
+                buf[start..end].to_vec()
+            }
+            _ => vec![],
+        })
+    }
+
+    /// write buf to the file starting at offset, filling any gap with zeros
+    pub fn pwrite(&self, buf: &[u8], offset: u64) -> ForkliftResult<u64> {
+        self.with_inode(|inode| {
+            if let MemoryData::File(data) = &mut inode.data {
+                let end = offset as usize + buf.len();
+                if data.len() < end {
+                    data.resize(end, 0);
+                }
+                data[offset as usize..end].copy_from_slice(buf);
+                inode.allocated.extend(blocks(offset, end as u64));
+                let time = now();
+                inode.mtime = time;
+                inode.ctime = time;
+            }
+            buf.len() as u64
+        })
+    }
+
+    /// get the metadata of the open file
+    pub fn fstat(&self) -> ForkliftResult<Stat> {
+        let (dev, ino) = (self.dev, self.ino);
+        self.with_inode(|inode| inode.stat(dev, ino))
+    }
+
+    /// truncate (or extend with a hole) the file to size
+    pub fn ftruncate(&self, size: u64) -> ForkliftResult<()> {
+        self.with_inode(|inode| {
+            if let MemoryData::File(data) = &mut inode.data {
+                data.resize(size as usize, 0);
+                let end = blocks(0, size).end;
+                inode.allocated = inode.allocated.range(..end).cloned().collect();
+                let time = now();
+                inode.mtime = time;
+                inode.ctime = time;
+            }
+        })
+    }
+
+    /// get the start of the first data at or after offset, or None if there is none
+    pub fn seek_data(&self, offset: u64) -> ForkliftResult<Option<u64>> {
+        self.with_inode(|inode| {
+            let size = match &inode.data {
+                MemoryData::File(buf) => buf.len() as u64,
+                _ => 0,
+            };
+            let block = inode.allocated.range(blocks(offset, offset).start..).next();
+            match block {
+                Some(b) if offset.max(b * MEMORY_BLKSIZE as u64) < size => {
+                    Some(offset.max(b * MEMORY_BLKSIZE as u64))
+                }
+                _ => None,
+            }
+        })
+    }
+
+    /// get the start of the first hole at or after offset
+    pub fn seek_hole(&self, offset: u64) -> ForkliftResult<u64> {
+        self.with_inode(|inode| {
+            let size = match &inode.data {
+                MemoryData::File(buf) => buf.len() as u64,
+                _ => 0,
+            };
+            let mut block = blocks(offset, offset).start;
+            while inode.allocated.contains(&block) {
+                block += 1;
+            }
+            offset.max(block * MEMORY_BLKSIZE as u64).min(size.max(offset))
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+/// an entry of a MemoryDirectory
+pub struct MemoryDirEntry {
+    /// name of the entry
+    pub path: PathBuf,
+    /// type of the entry
+    pub d_type: GenericFileType,
+}
+
+#[derive(Clone, Debug)]
+/// the entries of a directory opened through a MemoryContext
+pub struct MemoryDirectory {
+    /// remaining entries
+    entries: std::vec::IntoIter<MemoryDirEntry>,
+}
+
+impl Iterator for MemoryDirectory {
+    type Item = MemoryDirEntry;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.entries.next()
+    }
+}
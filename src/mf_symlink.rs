@@ -0,0 +1,115 @@
+use crate::error::{ForkliftError, ForkliftResult};
+
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::sys::stat::Mode;
+use rust_smb::Smbc;
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::str;
+
+/// size of every Minshall-French symlink file
+pub const MF_SYMLINK_SIZE: i64 = 1067;
+/// start of every Minshall-French symlink file
+const MF_SYMLINK_MAGIC: &[u8] = b"XSym\n";
+/// offset of the symlink target in the file, after the magic, length, and md5 lines
+const MF_SYMLINK_TARGET_OFFSET: usize = 43;
+/// longest target a Minshall-French symlink file can hold
+const MF_SYMLINK_MAX_TARGET: usize = 1024;
+
+/// create the contents of a Minshall-French symlink file pointing to target
+///
+/// @note   the format is the one used by the Linux cifs client's mfsymlinks
+///         mount option: "XSym\n", the target length as 4 digits, the md5
+///         of the target in hex, then the target, each on their own line,
+///         padded with spaces to MF_SYMLINK_SIZE bytes
+pub fn encode(target: &Path) -> ForkliftResult<Vec<u8>> {
+    let target = target.as_os_str().as_bytes();
+    if target.len() > MF_SYMLINK_MAX_TARGET {
+        return Err(ForkliftError::FSError(format!(
+            "Symlink target {:?} is too long for a Minshall-French symlink",
+            OsStr::from_bytes(target)
+        )));
+    }
+    let mut buf = MF_SYMLINK_MAGIC.to_vec();
+    buf.extend_from_slice(format!("{:04}\n{:x}\n", target.len(), md5::compute(target)).as_bytes());
+    buf.extend_from_slice(target);
+    if buf.len() < MF_SYMLINK_SIZE as usize {
+        buf.push(b'\n');
+    }
+    buf.resize(MF_SYMLINK_SIZE as usize, b' ');
+    Ok(buf)
+}
+
+/// get the target of the contents of a Minshall-French symlink file,
+/// or None if buf is not a valid Minshall-French symlink
+pub fn decode(buf: &[u8]) -> Option<PathBuf> {
+    if buf.len() != MF_SYMLINK_SIZE as usize || !buf.starts_with(MF_SYMLINK_MAGIC) {
+        return None;
+    }
+    let len: usize = str::from_utf8(&buf[5..9]).ok()?.parse().ok()?;
+    if buf[9] != b'\n' || buf[42] != b'\n' || len > MF_SYMLINK_MAX_TARGET {
+        return None;
+    }
+    let target = &buf[MF_SYMLINK_TARGET_OFFSET..MF_SYMLINK_TARGET_OFFSET + len];
+    if str::from_utf8(&buf[10..42]).ok()? != format!("{:x}", md5::compute(target)) {
+        return None;
+    }
+    Some(PathBuf::from(OsStr::from_bytes(target)))
+}
+
+/// read the target of the Minshall-French symlink file at path,
+/// or None if the file is not one
+pub fn read_symlink(smbc: &Smbc, path: &Path) -> ForkliftResult<Option<PathBuf>> {
+    let file = smbc.open(path, OFlag::O_RDONLY, Mode::empty())?;
+    let mut buf = vec![];
+    while buf.len() < MF_SYMLINK_SIZE as usize {
+        let mut chunk = file.fread(MF_SYMLINK_SIZE as u64 - buf.len() as u64)?;
+        if chunk.is_empty() {
+            break;
+        }
+        buf.append(&mut chunk);
+    }
+    Ok(decode(&buf))
+}
+
+/// create a Minshall-French symlink file at path pointing to target
+pub fn write_symlink(smbc: &Smbc, target: &Path, path: &Path) -> ForkliftResult<()> {
+    if smbc.stat(path).is_ok() {
+        return Err(ForkliftError::IoError(io::Error::from(Errno::EEXIST)));
+    }
+    let buf = encode(target)?;
+    let file = smbc.create(path, Mode::S_IRWXU | Mode::S_IRWXG | Mode::S_IRWXO)?;
+    let mut written = 0;
+    while written < buf.len() {
+        let num_written = file.fwrite(&buf[written..])?;
+        if num_written <= 0 {
+            return Err(ForkliftError::FSError(format!(
+                "Could not write Minshall-French symlink {:?}",
+                path
+            )));
+        }
+        written += num_written as usize;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_mf_symlink_format() {
+    let buf = encode(Path::new("abc")).unwrap();
+    assert_eq!(buf.len(), MF_SYMLINK_SIZE as usize);
+    assert!(buf.starts_with(b"XSym\n0003\n900150983cd24fb0d6963f7d28e17f72\nabc\n "));
+    assert_eq!(decode(&buf), Some(PathBuf::from("abc")));
+
+    let long = "a".repeat(MF_SYMLINK_MAX_TARGET);
+    let buf = encode(Path::new(&long)).unwrap();
+    assert_eq!(decode(&buf), Some(PathBuf::from(&long)));
+    assert!(encode(Path::new(&format!("{}a", long))).is_err());
+
+    let mut corrupt = encode(Path::new("../dir/file")).unwrap();
+    corrupt[MF_SYMLINK_TARGET_OFFSET] = b'/';
+    assert_eq!(decode(&corrupt), None);
+    assert_eq!(decode(b"XSym\n"), None);
+}
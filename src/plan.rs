@@ -0,0 +1,329 @@
+use crate::error::{ForkliftError, ForkliftResult};
+use crate::filesystem::*;
+use crate::filesystem_entry::Entry;
+use crate::filesystem_ops::*;
+use crate::id_map::IdMap;
+
+use nix::sys::stat::SFlag;
+use serde_derive::*;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+/// format the plan of a dry run is written in
+pub enum PlanFormat {
+    Json,
+    Csv,
+}
+
+impl Default for PlanFormat {
+    fn default() -> Self {
+        PlanFormat::Json
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+/// what a sync would do to a destination entry, named after the SyncOutcome it would get
+pub enum Action {
+    UpToDate,
+    FileCopied,
+    ChecksumUpdated,
+    SymlinkCreated,
+    SymlinkUpdated,
+    SymlinkSkipped,
+    HardLinkCreated,
+    SpecialFileCreated,
+    SpecialFileSkipped,
+    PermissionsUpdated,
+    DirectoryCreated,
+    DirectoryUpdated,
+    /// the entry is not in the source, and would be removed from the destination
+    Removed,
+    /// the entry is not in the source, and would be moved into the quarantine directory
+    Quarantined,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// one destination entry of a plan
+pub struct PlanEntry {
+    /// destination path
+    pub path: PathBuf,
+    pub action: Action,
+    /// size of the source entry, or of the destination entry if it would be removed
+    /// (0 for directories)
+    pub size: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// number and size of the entries of a plan with the same action
+pub struct Total {
+    pub action: Action,
+    pub count: u64,
+    pub size: i64,
+}
+
+#[derive(Serialize)]
+/// a plan as it is written in Json
+struct PlanFile<'a> {
+    entries: &'a [PlanEntry],
+    totals: Vec<Total>,
+}
+
+#[derive(Clone, Default)]
+/// the entries planned by the workers of a dry run, written out at the end of the run
+pub struct Plan {
+    entries: Arc<Mutex<Vec<PlanEntry>>>,
+}
+
+impl Plan {
+    pub fn new() -> Self {
+        Plan { entries: Arc::new(Mutex::new(vec![])) }
+    }
+    /// add the action planned for the destination path
+    pub fn add(&self, path: &Path, action: Action, size: i64) -> ForkliftResult<()> {
+        match self.entries.lock() {
+            Ok(mut entries) => {
+                entries.push(PlanEntry { path: path.to_path_buf(), action, size });
+                Ok(())
+            }
+            Err(_) => Err(ForkliftError::FSError("failed to lock".to_string())),
+        }
+    }
+    /// write the entries planned so far to a local file, sorted by path, and start a
+    /// new plan.  Returns the number of entries written
+    pub fn write(&self, path: &Path, format: PlanFormat) -> ForkliftResult<usize> {
+        let mut entries = match self.entries.lock() {
+            Ok(mut entries) => std::mem::take(&mut *entries),
+            Err(_) => return Err(ForkliftError::FSError("failed to lock".to_string())),
+        };
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        let mut out = BufWriter::new(File::create(path)?);
+        write_plan(&entries, format, &mut out)?;
+        out.flush()?;
+        Ok(entries.len())
+    }
+}
+
+/// count the entries and add up their sizes, by action
+pub fn totals(entries: &[PlanEntry]) -> Vec<Total> {
+    let mut totals: Vec<Total> = vec![];
+    for entry in entries {
+        match totals.iter_mut().find(|total| total.action == entry.action) {
+            Some(total) => {
+                total.count += 1;
+                total.size += entry.size;
+            }
+            None => totals.push(Total { action: entry.action, count: 1, size: entry.size }),
+        }
+    }
+    totals.sort_by_key(|total| total.action);
+    totals
+}
+
+/// write a plan.  Json plans are an object with the entries and the totals; Csv plans
+/// list the entries under a path,action,size header, then the totals under an
+/// action,count,size header
+pub fn write_plan<W: Write>(
+    entries: &[PlanEntry],
+    format: PlanFormat,
+    out: &mut W,
+) -> ForkliftResult<()> {
+    match format {
+        PlanFormat::Json => {
+            let plan = PlanFile { entries, totals: totals(entries) };
+            serde_json::to_writer_pretty(&mut *out, &plan)?;
+            writeln!(out)?;
+        }
+        PlanFormat::Csv => {
+            writeln!(out, "path,action,size")?;
+            for entry in entries {
+                let path = csv_field(&entry.path.to_string_lossy());
+                writeln!(out, "{},{:?},{}", path, entry.action, entry.size)?;
+            }
+            writeln!(out, "action,count,size")?;
+            for total in totals(entries) {
+                writeln!(out, "{:?},{},{}", total.action, total.count, total.size)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// quote a Csv field if it contains a separator, quote, or line break
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// work out what sync_entry, and the permission sync after it, would do to dest without
+/// writing anything.  Existing files the compare policy does not find unchanged are read
+/// from both sides, to tell whether their contents differ
+///
+/// @note   hard links are planned by the RsyncWorker, which knows the other names synced
+pub fn plan_entry(
+    src: &Entry,
+    dest: &Entry,
+    src_context: &ProtocolContext,
+    dest_context: &ProtocolContext,
+    (id_map, options): (&IdMap, SyncOptions),
+) -> ForkliftResult<Action> {
+    let src_stat = match src.metadata() {
+        Some(stat) => stat,
+        None => {
+            let err = format!("Source file {:?} does not exist", src.path());
+            return Err(ForkliftError::FSError(err));
+        }
+    };
+    let kind = GenericFileType::from_mode(src_stat.mode());
+    if kind == GenericFileType::Link {
+        return plan_link(src, dest, src_context, dest_context, id_map);
+    }
+    if kind.is_special() {
+        return plan_special(&src_stat, dest, src_context, dest_context);
+    }
+    let different = has_different_permissions(src, dest, src_context, dest_context, id_map);
+    if kind == GenericFileType::Directory {
+        return match dest.metadata() {
+            None => Ok(Action::DirectoryCreated),
+            Some(_) if different? => Ok(Action::DirectoryUpdated),
+            Some(_) => Ok(Action::UpToDate),
+        };
+    }
+    let (src_path, dest_path) = (src.path(), dest.path());
+    let changed = match dest.metadata() {
+        None => return Ok(Action::FileCopied),
+        Some(_) if options.compare == ComparePolicy::IgnoreExisting => return Ok(Action::UpToDate),
+        Some(_) if options.compare.is_unchanged(src, dest)? => false,
+        Some(_) => !same_contents(src_path, dest_path, src_context, dest_context, options.hash)?,
+    };
+    match (changed, different?) {
+        (true, _) => Ok(Action::ChecksumUpdated),
+        (false, true) => Ok(Action::PermissionsUpdated),
+        (false, false) => Ok(Action::UpToDate),
+    }
+}
+
+/// helper for plan_entry; what copy_link would do
+fn plan_link(
+    src: &Entry,
+    dest: &Entry,
+    src_context: &ProtocolContext,
+    dest_context: &ProtocolContext,
+    id_map: &IdMap,
+) -> ForkliftResult<Action> {
+    match (src_context, dest_context) {
+        (ProtocolContext::Samba(_, SambaSymlinks::Skip), _) => {
+            return Err(ForkliftError::FSError("Samba symlinks are disabled".to_string()));
+        }
+        (_, ProtocolContext::Samba(_, SambaSymlinks::Skip)) => return Ok(Action::SymlinkSkipped),
+        (..) => (),
+    }
+    let (src_stat, dest_stat) = match (src.metadata(), dest.metadata()) {
+        (Some(src_stat), Some(dest_stat)) => (src_stat, dest_stat),
+        (..) => return Ok(Action::SymlinkCreated),
+    };
+    if dest.is_link() != Some(true) {
+        return Err(ForkliftError::FSError(format!(
+            "Refusing to replace existing path {:?} by symlink",
+            dest.path()
+        )));
+    }
+    let src_target = read_link(src.path(), src_context, src_stat.size() + 1)?;
+    let dest_target = read_link(dest.path(), dest_context, dest_stat.size() + 1)?;
+    let owner = match (src_context, dest_context) {
+        (ProtocolContext::Samba(..), _) | (_, ProtocolContext::Samba(..)) => (None, None),
+        (..) => mapped_owner(&src_stat, id_map)?,
+    };
+    if src_target != dest_target {
+        Ok(Action::SymlinkUpdated)
+    } else if has_different_owner(owner, dest) {
+        Ok(Action::PermissionsUpdated)
+    } else {
+        Ok(Action::UpToDate)
+    }
+}
+
+/// helper for plan_entry; what copy_special would do.  Creating the special file may
+/// still fail (ex. device files without root), which the plan can not tell
+fn plan_special(
+    src_stat: &Stat,
+    dest: &Entry,
+    src_context: &ProtocolContext,
+    dest_context: &ProtocolContext,
+) -> ForkliftResult<Action> {
+    match (src_context, dest_context) {
+        (ProtocolContext::Samba(..), _) | (_, ProtocolContext::Samba(..)) => {
+            return Ok(Action::SpecialFileSkipped);
+        }
+        (..) => (),
+    }
+    let dest_stat = match dest.metadata() {
+        Some(stat) => stat,
+        None => return Ok(Action::SpecialFileCreated),
+    };
+    let kind = src_stat.mode() & SFlag::S_IFMT.bits();
+    if dest_stat.mode() & SFlag::S_IFMT.bits() == kind && dest_stat.rdev() == src_stat.rdev() {
+        return Ok(Action::UpToDate);
+    }
+    if let Some(true) = dest.is_dir() {
+        return Err(ForkliftError::FSError(format!(
+            "Refusing to replace existing directory {:?} by special file",
+            dest.path()
+        )));
+    }
+    Ok(Action::SpecialFileCreated)
+}
+
+#[test]
+fn test_plan_entry_and_write() {
+    use crate::memory_filesystem::MemoryContext;
+    let (src, dest) = (MemoryContext::new(1), MemoryContext::new(2));
+    src.add_file(Path::new("/new"), b"new file", 0o644).unwrap();
+    src.add_file(Path::new("/same"), b"same", 0o644).unwrap();
+    src.add_file(Path::new("/changed"), b"new contents", 0o644).unwrap();
+    src.add_file(Path::new("/mode"), b"mode", 0o600).unwrap();
+    src.add_symlink(Path::new("/link"), Path::new("new")).unwrap();
+    dest.add_file(Path::new("/same"), b"same", 0o644).unwrap();
+    dest.add_file(Path::new("/changed"), b"old contents", 0o644).unwrap();
+    dest.add_file(Path::new("/mode"), b"mode", 0o644).unwrap();
+    dest.add_symlink(Path::new("/link"), Path::new("old")).unwrap();
+    let time = Timespec::new(1_000_000, 0);
+    for path in &["/same", "/changed", "/mode"] {
+        src.utimes(Path::new(path), time, time).unwrap();
+        dest.utimes(Path::new(path), time, time).unwrap();
+    }
+    let (src_context, dest_context) = (ProtocolContext::Memory(src), ProtocolContext::Memory(dest));
+    let (id_map, plan) = (IdMap::new(), Plan::new());
+    // the changed file has the same size and mtime, so it is only found by checksum
+    let options = SyncOptions { compare: ComparePolicy::Checksum, ..SyncOptions::default() };
+    for name in &["/new", "/same", "/changed", "/mode", "/link"] {
+        let path = Path::new(name);
+        let (src, dest) = (Entry::new(path, &src_context), Entry::new(path, &dest_context));
+        let action = plan_entry(&src, &dest, &src_context, &dest_context, (&id_map, options));
+        plan.add(path, action.unwrap(), src.metadata().unwrap().size()).unwrap();
+    }
+    plan.add(Path::new("/extra"), Action::Removed, 3).unwrap();
+    let mut entries = plan.entries.lock().unwrap().clone();
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    let actions: Vec<Action> = entries.iter().map(|entry| entry.action).collect();
+    let expected = [
+        Action::ChecksumUpdated,
+        Action::Removed,
+        Action::SymlinkUpdated,
+        Action::PermissionsUpdated,
+        Action::FileCopied,
+        Action::UpToDate,
+    ];
+    assert_eq!(actions, expected);
+    let mut out = vec![];
+    write_plan(&entries[..2], PlanFormat::Csv, &mut out).unwrap();
+    let csv = "path,action,size\n/changed,ChecksumUpdated,12\n/extra,Removed,3\n\
+               action,count,size\nChecksumUpdated,1,12\nRemoved,1,3\n";
+    assert_eq!(String::from_utf8(out).unwrap(), csv);
+    assert_eq!(csv_field("a,\"b\""), "\"a,\"\"b\"\"\"");
+}
@@ -1,7 +1,10 @@
 use crate::error::ForkliftError;
+use crate::failures::Operation;
 use crate::filesystem_ops::SyncOutcome;
 use crate::rsync::SyncStats;
 
+use std::path::PathBuf;
+
 /// enum holding progress messages
 #[derive(Debug)]
 pub enum ProgressMessage {
@@ -13,6 +16,15 @@ pub enum ProgressMessage {
     Todo { num_files: u64, tot_size: usize },
     /// Error message
     SendError(ForkliftError),
+    /// number of bytes of a sparse file left as holes instead of being written
+    HolesSkipped(u64),
+    /// a synced file was read again to verify it, true if it matched the source
+    Verified(bool),
+    /// a file was skipped because it did not have the selected attributes
+    FilteredOut,
+    /// an entry (at a path relative to the sync root) failed with a permanent error, or
+    /// kept failing until out of attempts
+    Failed { path: PathBuf, operation: Operation, error: ForkliftError, attempts: u32 },
     /// sync in progress
     CheckSyncing { description: String, size: usize, done: usize },
     /// end the Sync
@@ -1,12 +1,15 @@
 use crossbeam::channel::{Receiver, Sender};
 use log::*;
+use std::path::{Path, PathBuf};
 use std::time::Instant;
 
 use crate::error::{ForkliftError, ForkliftResult};
+use crate::failures::{write_failures, Failure};
 use crate::filesystem_ops::SyncOutcome;
 use crate::postgres_logger::{send_mess, EndState, LogMessage};
 use crate::progress_message::*;
 use crate::rsync::SyncStats;
+use crate::socket_node::SocketNode;
 use crate::tables::*;
 
 /// threaded worker handling progress messages
@@ -23,17 +26,21 @@ pub struct ProgressWorker {
     is_rerun: Sender<EndState>,
     /// channel input for is rerun
     end_run: Receiver<EndState>,
+    /// local file the entries that failed in a run are written to at its end
+    failures_path: PathBuf,
+    /// this node, recorded with its failures
+    node: SocketNode,
 }
 
 impl ProgressWorker {
     /// create a new ProgressWorker
     pub fn new(
-        src_share: &str,
-        dest_share: &str,
+        (src_share, dest_share): (&str, &str),
         progress_info: Box<ProgressInfo + Send + Sync>,
         input: Receiver<ProgressMessage>,
         is_rerun: Sender<EndState>,
         end_run: Receiver<EndState>,
+        (failures_path, node): (&Path, SocketNode),
     ) -> ProgressWorker {
         ProgressWorker {
             src_share: src_share.to_string(),
@@ -42,6 +49,8 @@ impl ProgressWorker {
             progress_info,
             is_rerun,
             end_run,
+            failures_path: failures_path.to_path_buf(),
+            node,
         }
     }
 
@@ -56,6 +65,7 @@ impl ProgressWorker {
         let mut file_done;
         loop {
             stats.reset();
+            let mut failures: Vec<Failure> = vec![];
             let mut current_file = "".to_string();
             let mut index = 0;
             let mut total_done = 0;
@@ -77,11 +87,19 @@ impl ProgressWorker {
                         self.progress_info.done_syncing();
                         stats.add_outcome(&x);
                         match x {
-                            SyncOutcome::FileCopied(path, src_check, dest_check, size, update) => {
+                            SyncOutcome::FileCopied(
+                                path,
+                                src_check,
+                                dest_check,
+                                name,
+                                size,
+                                update,
+                            ) => {
                                 let file = Files::new(
                                     &format!("{:?}{:?}", self.src_share, path),
                                     src_check,
                                     dest_check,
+                                    &name,
                                     size,
                                     update,
                                 );
@@ -91,13 +109,16 @@ impl ProgressWorker {
                                 path,
                                 src_check,
                                 dest_check,
+                                name,
                                 size,
                                 update,
+                                ..
                             ) => {
                                 let file = Files::new(
                                     &format!("{:?}{:?}", self.src_share, path),
                                     src_check,
                                     dest_check,
+                                    &name,
                                     size,
                                     update,
                                 );
@@ -106,6 +127,19 @@ impl ProgressWorker {
                             _ => {}
                         }
                     }
+                    ProgressMessage::HolesSkipped(bytes) => {
+                        stats.hole_bytes_skipped += bytes;
+                    }
+                    ProgressMessage::Verified(true) => stats.files_verified += 1,
+                    ProgressMessage::Verified(false) => stats.verify_failed += 1,
+                    ProgressMessage::FilteredOut => stats.filtered_out += 1,
+                    ProgressMessage::Failed { path, operation, error, attempts } => {
+                        error!("{:?} failed after {} attempt(s): {:?}", path, attempts, error);
+                        stats.failed += 1;
+                        let path = (path.as_path(), operation);
+                        failures.push(Failure::new(path, &error, attempts, &self.node));
+                        send_mess(LogMessage::Error(error), send_log)?;
+                    }
                     ProgressMessage::SendError(error) => {
                         send_mess(LogMessage::Error(error), send_log)?;
                     }
@@ -131,6 +165,14 @@ impl ProgressWorker {
                         self.progress_info.progress(&detailed_progress);
                     }
                     ProgressMessage::EndSync => {
+                        // written even if empty, so the file lists the failures of the last run
+                        match write_failures(&self.failures_path, &mut failures) {
+                            Ok(()) => {
+                                let (num, path) = (failures.len(), &self.failures_path);
+                                info!("{} failed entries written to {:?}", num, path);
+                            }
+                            Err(e) => send_mess(LogMessage::Error(e), send_log)?,
+                        }
                         break;
                     }
                 }
@@ -0,0 +1,159 @@
+use crate::error::ForkliftError;
+use crate::failures::errno;
+
+use log::*;
+use nix::libc;
+use serde_derive::*;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+/// errnos of failures that may go away if the operation is tried again
+const TRANSIENT_ERRNOS: [i32; 11] = [
+    libc::EIO,
+    libc::EAGAIN,
+    libc::EINTR,
+    libc::EBUSY,
+    libc::ESTALE,
+    libc::ETIMEDOUT,
+    libc::ECONNRESET,
+    libc::ECONNABORTED,
+    libc::ENETRESET,
+    libc::ENETUNREACH,
+    libc::EHOSTUNREACH,
+];
+
+/// kinds of io errors without an errno that may go away if the operation is tried again
+const TRANSIENT_KINDS: [ErrorKind; 6] = [
+    ErrorKind::TimedOut,
+    ErrorKind::Interrupted,
+    ErrorKind::WouldBlock,
+    ErrorKind::ConnectionReset,
+    ErrorKind::ConnectionAborted,
+    ErrorKind::BrokenPipe,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+/// how often, and how long apart, an entry is tried again after a transient failure
+pub struct RetryPolicy {
+    /// number of times an entry is tried before giving up on it, at least 1
+    #[serde(default = "default_attempts")]
+    pub attempts: u32,
+    /// milliseconds waited before the second attempt, doubled before each later one
+    #[serde(default = "default_initial_delay_ms")]
+    pub initial_delay_ms: u64,
+    /// longest wait between two attempts, in milliseconds
+    #[serde(default = "default_max_delay_ms")]
+    pub max_delay_ms: u64,
+}
+
+/// default attempts helper
+fn default_attempts() -> u32 {
+    4
+}
+/// default initial delay helper
+fn default_initial_delay_ms() -> u64 {
+    500
+}
+/// default max delay helper
+fn default_max_delay_ms() -> u64 {
+    30_000
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: default_attempts(),
+            initial_delay_ms: default_initial_delay_ms(),
+            max_delay_ms: default_max_delay_ms(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// run op until it succeeds, fails with a permanent error, or was tried as many times
+    /// as the policy allows, waiting longer before each new attempt.  On failure, get the
+    /// last error and the number of attempts made.  path is the entry op works on
+    pub fn run<T, F>(&self, path: &Path, mut op: F) -> Result<T, (ForkliftError, u32)>
+    where
+        F: FnMut() -> Result<T, ForkliftError>,
+    {
+        let mut attempt = 1;
+        loop {
+            match op() {
+                Ok(value) => return Ok(value),
+                Err(e) => {
+                    if attempt >= self.attempts || !is_transient(&e) {
+                        return Err((e, attempt));
+                    }
+                    let delay = self.delay(attempt);
+                    warn!("{:?} failed with {:?} on attempt {}, retrying", path, e, attempt);
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    /// get the time waited after the failed attempt (counting from 1)
+    fn delay(&self, attempt: u32) -> Duration {
+        let factor = 1u64.checked_shl(attempt - 1).unwrap_or(u64::MAX);
+        Duration::from_millis(self.initial_delay_ms.saturating_mul(factor).min(self.max_delay_ms))
+    }
+}
+
+/// true if the operation that failed with error may succeed if tried again, such as
+/// I/O errors, timeouts, and dropped connections.  Samba errors are classified by the
+/// errno libsmbclient mapped their status to; those without one are treated as transient
+pub fn is_transient(error: &ForkliftError) -> bool {
+    match error {
+        ForkliftError::IoError(e) => match e.raw_os_error() {
+            Some(errno) => TRANSIENT_ERRNOS.contains(&errno),
+            None => TRANSIENT_KINDS.contains(&e.kind()),
+        },
+        ForkliftError::SmbcError(_) => match errno(error) {
+            Some(errno) => TRANSIENT_ERRNOS.contains(&errno),
+            None => true,
+        },
+        ForkliftError::TimeoutError(_) | ForkliftError::ChecksumError(_) => true,
+        _ => false,
+    }
+}
+
+#[test]
+fn test_retry_policy() {
+    use std::cell::Cell;
+    use std::io;
+
+    let policy = RetryPolicy { attempts: 3, initial_delay_ms: 1, max_delay_ms: 3 };
+    assert_eq!(policy.delay(1), Duration::from_millis(1));
+    assert_eq!(policy.delay(3), Duration::from_millis(3));
+    assert_eq!(policy.delay(64), Duration::from_millis(3));
+    let errno = |errno| ForkliftError::IoError(io::Error::from_raw_os_error(errno));
+
+    // transient errors are retried until they go away, or the attempts run out
+    let tries = Cell::new(0);
+    let result = policy.run(Path::new("/flaky"), || {
+        tries.set(tries.get() + 1);
+        if tries.get() < 3 {
+            return Err(errno(libc::EIO));
+        }
+        Ok(tries.get())
+    });
+    assert_eq!(result.unwrap(), 3);
+    tries.set(0);
+    let result: Result<(), _> = policy.run(Path::new("/broken"), || {
+        tries.set(tries.get() + 1);
+        Err(errno(libc::ETIMEDOUT))
+    });
+    assert_eq!((result.unwrap_err().1, tries.get()), (3, 3));
+    // permanent errors are not
+    tries.set(0);
+    let result: Result<(), _> = policy.run(Path::new("/missing"), || {
+        tries.set(tries.get() + 1);
+        Err(errno(libc::ENOENT))
+    });
+    assert_eq!((result.unwrap_err().1, tries.get()), (1, 1));
+    assert!(!is_transient(&ForkliftError::FSError("src entry does not exist".to_string())));
+}
@@ -1,22 +1,36 @@
 //SyncStats
+use crate::chunked_copy::{RangeQueue, RANGE_SIZE};
 use crate::error::{ForkliftError, ForkliftResult};
 use crate::filesystem::*;
 use crate::filesystem_entry::Entry;
-use crate::filesystem_ops::SyncOutcome;
+use crate::filesystem_ops::{SyncOptions, SyncOutcome};
+use crate::failures::{read_failures, retry_paths};
+use crate::filter::{read_file_list, Filter};
+use crate::id_map::IdMap;
 use crate::input::Input;
+use crate::plan::Plan;
 use crate::postgres_logger::{send_mess, EndState};
 use crate::progress_message::*;
 use crate::progress_worker::*;
 use crate::rsync_worker::*;
 use crate::socket_node::*;
+use crate::tables::ErrorType;
+use crate::verify::{verify_synced, Synced};
 use crate::walk_worker::*;
 use crate::LogMessage;
+#[cfg(test)]
+use crate::memory_filesystem::MemoryContext;
 
+use chrono::Utc;
 use crossbeam::channel;
 use crossbeam::channel::{Receiver, Sender};
 use log::*;
 use rendezvous_hash::{DefaultNodeHasher, RendezvousNodes};
+use rust_smb::Smbc;
+use std::collections::HashMap;
+use std::mem;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 
 #[derive(Default, Debug, Clone, Copy)]
@@ -40,10 +54,35 @@ pub struct SyncStats {
     pub symlink_updated: u64,
     /// number of symlinks skipped in dest
     pub symlink_skipped: u64,
+    /// number of hard links created in dest
+    pub hard_link_created: u64,
+    /// number of fifos, devices, and sockets created in dest
+    pub special_created: u64,
+    /// number of fifos, devices, and sockets that could not be created in dest
+    pub special_skipped: u64,
+    /// number of bytes of sparse files left as holes in dest instead of being written
+    pub hole_bytes_skipped: u64,
     /// number of files for which the permissions were updated
     pub permissions_update: u64,
     /// the number of files where dest file contents were updated
     pub checksum_updated: u64,
+    /// number of bytes of updated files that were already in dest
+    pub bytes_matched: u64,
+    /// number of bytes of updated files that had to be written to dest
+    pub bytes_transferred: u64,
+    /// number of synced files read again that matched the source
+    pub files_verified: u64,
+    /// number of synced files read again that still did not match the source after
+    /// being synced again
+    pub verify_failed: u64,
+    /// number of destination entries not in the source that were removed
+    pub removed: u64,
+    /// number of destination entries not in the source that were moved to the quarantine
+    pub quarantined: u64,
+    /// number of files not synced because they did not have the selected attributes
+    pub filtered_out: u64,
+    /// number of entries that could not be synced, even after being tried again
+    pub failed: u64,
     /// the number of directories where dest directory was created
     pub directory_created: u64,
     /// the number of directories where the dest directory permissions were updated
@@ -62,25 +101,50 @@ impl SyncStats {
             symlink_created: 0,
             symlink_updated: 0,
             symlink_skipped: 0,
+            hard_link_created: 0,
+            special_created: 0,
+            special_skipped: 0,
+            hole_bytes_skipped: 0,
             permissions_update: 0,
             checksum_updated: 0,
+            bytes_matched: 0,
+            bytes_transferred: 0,
+            files_verified: 0,
+            verify_failed: 0,
+            removed: 0,
+            quarantined: 0,
+            filtered_out: 0,
+            failed: 0,
             directory_created: 0,
             directory_updated: 0,
         }
     }
     /// Add a SyncOutcome to the stats
     pub fn add_outcome(&mut self, outcome: &SyncOutcome) {
-        self.num_synced += 1;
+        // destination entries not in the source are not synced
+        match outcome {
+            SyncOutcome::Removed => self.removed += 1,
+            SyncOutcome::Quarantined => self.quarantined += 1,
+            _ => self.num_synced += 1,
+        }
         match outcome {
             SyncOutcome::FileCopied(..) => self.copied += 1,
             SyncOutcome::UpToDate => self.up_to_date += 1,
             SyncOutcome::SymlinkUpdated => self.symlink_updated += 1,
             SyncOutcome::SymlinkCreated => self.symlink_created += 1,
             SyncOutcome::SymlinkSkipped => self.symlink_skipped += 1,
+            SyncOutcome::HardLinkCreated => self.hard_link_created += 1,
+            SyncOutcome::SpecialFileCreated => self.special_created += 1,
+            SyncOutcome::SpecialFileSkipped => self.special_skipped += 1,
             SyncOutcome::PermissionsUpdated => self.permissions_update += 1,
-            SyncOutcome::ChecksumUpdated(..) => self.checksum_updated += 1,
+            SyncOutcome::ChecksumUpdated(.., matched, transferred) => {
+                self.checksum_updated += 1;
+                self.bytes_matched += matched;
+                self.bytes_transferred += transferred;
+            }
             SyncOutcome::DirectoryUpdated => self.directory_updated += 1,
             SyncOutcome::DirectoryCreated => self.directory_created += 1,
+            SyncOutcome::Removed | SyncOutcome::Quarantined => (),
         }
     }
     pub fn reset(&mut self) {
@@ -88,6 +152,14 @@ impl SyncStats {
         self.tot_size = 0;
         self.up_to_date = 0;
         self.checksum_updated = 0;
+        self.bytes_matched = 0;
+        self.bytes_transferred = 0;
+        self.files_verified = 0;
+        self.verify_failed = 0;
+        self.removed = 0;
+        self.quarantined = 0;
+        self.filtered_out = 0;
+        self.failed = 0;
         self.directory_created = 0;
         self.directory_updated = 0;
         self.num_synced = 0;
@@ -95,18 +167,53 @@ impl SyncStats {
         self.symlink_created = 0;
         self.symlink_skipped = 0;
         self.symlink_updated = 0;
+        self.hard_link_created = 0;
+        self.special_created = 0;
+        self.special_skipped = 0;
+        self.hole_bytes_skipped = 0;
         self.copied = 0;
     }
 }
 
+/// create the Filesystem context of one side of the sync
+fn create_context(
+    system: FileSystemType,
+    server: &str,
+    share: &str,
+    level: DebugLevel,
+    (smbc, symlinks): (&Option<Smbc>, SambaSymlinks),
+) -> ForkliftResult<ProtocolContext> {
+    match (system, smbc) {
+        (FileSystemType::Samba, Some(smbc)) => {
+            Ok(ProtocolContext::Samba(Box::new(smbc.clone()), symlinks))
+        }
+        (FileSystemType::Samba, None) => {
+            Err(ForkliftError::FSError("Samba context was not initialized".to_string()))
+        }
+        (FileSystemType::Nfs, _) => create_nfs_context(server, share, level),
+        (FileSystemType::Local, _) => create_local_context(share),
+    }
+}
+
+/// log the principals the id map had no mapping for during a run
+fn report_unmapped(id_map: &IdMap, log_output: &Sender<LogMessage>) -> ForkliftResult<()> {
+    let unmapped = id_map.take_unmapped();
+    if unmapped.is_empty() {
+        return Ok(());
+    }
+    let report = format!("Unmapped principals: {}", unmapped.join(", "));
+    warn!("{}", report);
+    send_mess(LogMessage::ErrorType(ErrorType::InvalidConfigError, report), log_output)
+}
+
 /// Struct to build and run Rsync
 pub struct Rsyncer {
     /// source root path
     source: PathBuf,
     /// destination root path,
     destination: PathBuf,
-    /// share protocol to usize
-    filesystem_type: FileSystemType,
+    /// share protocols of the (source, destination)
+    filesystem_types: (FileSystemType, FileSystemType),
     /// console ouput functions
     progress_info: Box<ProgressInfo + Send + Sync>,
     /// channel to send postgres logs
@@ -118,11 +225,11 @@ impl Rsyncer {
     pub fn new(
         source: PathBuf,
         destination: PathBuf,
-        filesystem_type: FileSystemType,
+        filesystem_types: (FileSystemType, FileSystemType),
         progress_info: Box<ProgressInfo + Send + Sync>,
         log_output: Sender<LogMessage>,
     ) -> Rsyncer {
-        Rsyncer { source, destination, filesystem_type, progress_info, log_output }
+        Rsyncer { source, destination, filesystem_types, progress_info, log_output }
     }
 
     /// create the rsync workers and store them along with their
@@ -130,10 +237,13 @@ impl Rsyncer {
     pub fn create_syncers(
         &self,
         contexts: &[(ProtocolContext, ProtocolContext)],
+        id_map: &Arc<IdMap>,
+        (options, plan): (SyncOptions, &Plan),
         send_progress: &Sender<ProgressMessage>,
     ) -> (Vec<Sender<Option<Entry>>>, Vec<RsyncWorker>) {
         let mut send_handles: Vec<Sender<Option<Entry>>> = Vec::new();
         let mut syncers: Vec<RsyncWorker> = Vec::new();
+        let hard_links: HardLinks = Arc::new(Mutex::new(HashMap::new()));
         for (src_context, dest_context) in contexts {
             let (send_e, rec_e) = channel::unbounded();
             send_handles.push(send_e);
@@ -141,8 +251,8 @@ impl Rsyncer {
             syncers.push(RsyncWorker::new(
                 self.source.as_path(),
                 self.destination.as_path(),
-                src_context.clone(),
-                dest_context.clone(),
+                (src_context.clone(), dest_context.clone()),
+                (id_map.clone(), hard_links.clone(), options, plan.clone()),
                 rec_e,
                 sync_progress,
                 self.log_output.clone(),
@@ -159,26 +269,23 @@ impl Rsyncer {
         password: &str,
     ) -> ForkliftResult<Vec<(ProtocolContext, ProtocolContext)>> {
         let mut contexts: Vec<(ProtocolContext, ProtocolContext)> = Vec::new();
-        let level = &config.debug_level;
+        let level = config.debug_level;
         let workgroup = &config.workgroup;
-        let smbc = init_samba(&workgroup, username, password, *level)?;
-        for _ in 0..config.num_threads {
-            match self.filesystem_type {
-                FileSystemType::Samba => {
-                    let (src_context, dest_context) = (
-                        ProtocolContext::Samba(Box::new(smbc.clone())),
-                        ProtocolContext::Samba(Box::new(smbc.clone())),
-                    );
-                    contexts.push((src_context, dest_context));
-                }
-                FileSystemType::Nfs => {
-                    let (src_context, dest_context) = (
-                        create_nfs_context(&config.src_server, &config.src_share, *level)?,
-                        create_nfs_context(&config.dest_server, &config.dest_share, *level)?,
-                    );
-                    contexts.push((src_context, dest_context));
-                }
+        let (src_system, dest_system) = self.filesystem_types;
+        // only initialize Samba if one of the sides needs it
+        let smbc = match self.filesystem_types {
+            (FileSystemType::Samba, _) | (_, FileSystemType::Samba) => {
+                Some(init_samba(&workgroup, username, password, level)?)
             }
+            (..) => None,
+        };
+        let samba = (&smbc, config.samba_symlinks);
+        for _ in 0..config.num_threads {
+            let (src_context, dest_context) = (
+                create_context(src_system, &config.src_server, &config.src_share, level, samba)?,
+                create_context(dest_system, &config.dest_server, &config.dest_share, level, samba)?,
+            );
+            contexts.push((src_context, dest_context));
         }
         Ok(contexts)
     }
@@ -192,32 +299,79 @@ impl Rsyncer {
         current_node: SocketNode,
         is_rerun: Sender<EndState>,
         end_run: Receiver<EndState>,
+    ) -> ForkliftResult<()> {
+        let contexts = self.create_contexts(config, username, password)?;
+        // verifying reads the files through contexts that did not write them
+        let verify_contexts = if config.verify {
+            self.create_contexts(config, username, password)?
+        } else {
+            vec![]
+        };
+        let contexts = (contexts, verify_contexts);
+        self.sync_with_contexts(config, contexts, nodelist, current_node, is_rerun, end_run)
+    }
+
+    /// run the rsync protocol using already created (source, destination) contexts,
+    /// one pair per thread, and the separate pairs used to verify synced files
+    pub fn sync_with_contexts(
+        self,
+        config: &Input,
+        (contexts, verify_contexts): (
+            Vec<(ProtocolContext, ProtocolContext)>,
+            Vec<(ProtocolContext, ProtocolContext)>,
+        ),
+        nodelist: Arc<Mutex<RendezvousNodes<SocketNode, DefaultNodeHasher>>>,
+        current_node: SocketNode,
+        is_rerun: Sender<EndState>,
+        end_run: Receiver<EndState>,
     ) -> ForkliftResult<()> {
         let (num_threads, src_share, dest_share) =
             (config.num_threads, &config.src_share, &config.dest_share);
         let (send_prog, rec_prog) = channel::unbounded::<ProgressMessage>();
         let (send_prog_thread, copy_log_output) = (send_prog.clone(), self.log_output.clone());
+        let report_log_output = self.log_output.clone();
         let (get_signal, restart_signal) = channel::unbounded::<EndState>();
-        let contexts = self.create_contexts(config, username, password)?;
+        let id_map = Arc::new(IdMap::load(config.id_map.as_deref())?);
+        let filter = Filter::load(&config.filter, config.filter_file.as_deref())?;
+        let filter = Arc::new(filter.select(&config.select, Utc::now())?);
+        // the entries, and directories, synced instead of the whole tree
+        let listed = match (&config.files_from, &config.retry_failures) {
+            (Some(path), _) => Some((read_file_list(path)?, vec![])),
+            (None, Some(path)) => Some(retry_paths(&read_failures(path)?)),
+            (None, None) => None,
+        };
         //create workers
-        let (send_handles, syncers) = self.create_syncers(&contexts, &send_prog);
+        let options = SyncOptions {
+            compare: config.compare,
+            hash: config.hash,
+            temp_files: config.temp_files,
+            chunked_copy_size: config.chunked_copy_size,
+            verify: config.verify,
+            dry_run: config.dry_run.is_some(),
+            delete: config.delete,
+            delete_excluded: config.delete_excluded,
+            retry: config.retry,
+        };
+        let plan = Plan::new();
+        let (send_handles, syncers) =
+            self.create_syncers(&contexts, &id_map, (options, &plan), &send_prog);
         let (src_path, dest_path) = (self.source.as_path(), self.destination.as_path());
         let walk_worker = WalkWorker::new(
-            src_path,
-            dest_path,
+            (src_path, dest_path),
             contexts,
             current_node,
             nodelist,
             send_handles,
             send_prog,
+            (options, plan.clone(), filter),
         );
         let progress_worker = ProgressWorker::new(
-            src_share,
-            dest_share,
+            (src_share, dest_share),
             self.progress_info,
             rec_prog,
             is_rerun,
             end_run,
+            (&config.failures_path, current_node),
         );
 
         rayon::spawn(move || {
@@ -230,14 +384,24 @@ impl Rsyncer {
             .expect("Unable to build ThreadPool");
         loop {
             if num_threads == 1 {
-                walk_worker.s_walk()?;
+                match &listed {
+                    Some((paths, dirs)) => walk_worker.list_walk((paths, dirs))?,
+                    None => walk_worker.s_walk()?,
+                }
                 walk_worker.stop()?;
             }
-            let rsyncers = syncers.clone();
+            // only the workers keep the queue of this run, so it closes once they are all done
+            let ranges = RangeQueue::new(RANGE_SIZE);
+            let synced: Synced = Arc::new(Mutex::new(vec![]));
+            let rsyncers: Vec<RsyncWorker> =
+                syncers.iter().map(|s| s.for_run(&ranges, &synced)).collect();
+            drop(ranges);
+            let failed = AtomicBool::new(false);
             pool.install(|| {
                 if num_threads > 1 {
-                    if let Err(e) = walk_worker.t_walk(src_path, &pool) {
-                        return Err(e);
+                    match &listed {
+                        Some((paths, dirs)) => walk_worker.list_walk((paths, dirs))?,
+                        None => walk_worker.t_walk(src_path, &pool)?,
                     }
                     walk_worker.stop()?;
                 }
@@ -245,10 +409,15 @@ impl Rsyncer {
                     for syncer in rsyncers {
                         spawner.spawn(|_| {
                             let input = syncer.input.clone();
-                            if let Err(e) = syncer.start(&pool) {
-                                let mess = ProgressMessage::SendError(e);
-                                send_prog_thread.send(mess).expect("Unable to send progress");
-                            };
+                            match syncer.start(&pool) {
+                                Ok(0) => (),
+                                Ok(_) => failed.store(true, Ordering::SeqCst),
+                                Err(e) => {
+                                    failed.store(true, Ordering::SeqCst);
+                                    let mess = ProgressMessage::SendError(e);
+                                    send_prog_thread.send(mess).expect("Unable to send progress");
+                                }
+                            }
                             debug!(
                                 "Syncer Stopped, Thread {:?}, num left {:?}",
                                 pool.current_thread_index(),
@@ -257,6 +426,25 @@ impl Rsyncer {
                         });
                     }
                 });
+                if let Some(path) = &config.dry_run {
+                    let planned = plan.write(path, config.plan_format)?;
+                    info!("Dry run planned {} entries, written to {:?}", planned, path);
+                } else if config.verify {
+                    let files = match synced.lock() {
+                        Ok(mut synced) => mem::take(&mut *synced),
+                        Err(_) => return Err(ForkliftError::FSError("failed to lock".to_string())),
+                    };
+                    verify_synced(
+                        files,
+                        &verify_contexts,
+                        (&id_map, options, src_path),
+                        &send_prog_thread,
+                        &report_log_output,
+                    )?;
+                }
+                walk_worker.remove_pending(failed.load(Ordering::SeqCst))?;
+                walk_worker.copy_dir_times()?;
+                report_unmapped(&id_map, &report_log_output)?;
                 if send_prog_thread.send(ProgressMessage::EndSync).is_err() {
                     return Err(ForkliftError::CrossbeamChannelError(
                         "Unable to send End signal to progress_worker".to_string(),
@@ -282,3 +470,422 @@ impl Rsyncer {
         Ok(())
     }
 }
+
+#[cfg(test)]
+/// run a single sync of config from src to dest, as the first of its nodes, getting
+/// the total stats and the errors logged
+fn run_memory_sync(
+    config: &str,
+    (src, dest): (&MemoryContext, &MemoryContext),
+) -> (SyncStats, Vec<ForkliftError>) {
+    struct NoProgress;
+    impl ProgressInfo for NoProgress {}
+
+    let config: Input = serde_json::from_str(config).unwrap();
+    let mut nodes = RendezvousNodes::default();
+    for node in &config.nodes {
+        nodes.insert(SocketNode::new(*node));
+    }
+    let current_node = SocketNode::new(config.nodes[0]);
+    let (send_log, rec_log) = channel::unbounded();
+    let (is_rerun, rec_rerun) = channel::unbounded();
+    let (send_end, end_run) = channel::unbounded();
+    // stand in for the heartbeat thread, ending the program after one run
+    std::thread::spawn(move || {
+        if rec_rerun.recv().is_ok() {
+            send_end.send(EndState::EndProgram).unwrap();
+        }
+    });
+    let rsyncer = Rsyncer::new(
+        PathBuf::from("/"),
+        PathBuf::from("/"),
+        (FileSystemType::Local, FileSystemType::Local),
+        Box::new(NoProgress),
+        send_log,
+    );
+    let (src, dest) = (ProtocolContext::Memory(src.clone()), ProtocolContext::Memory(dest.clone()));
+    let (contexts, nodes) = ((vec![(src, dest)], vec![]), Arc::new(Mutex::new(nodes)));
+    rsyncer.sync_with_contexts(&config, contexts, nodes, current_node, is_rerun, end_run).unwrap();
+
+    let (mut stats, mut errors) = (SyncStats::new(), vec![]);
+    for log in rec_log.try_iter() {
+        match log {
+            LogMessage::TotalSync(s) => stats = s,
+            LogMessage::Error(e) => errors.push(e),
+            _ => (),
+        }
+    }
+    (stats, errors)
+}
+
+#[test]
+fn test_sync_with_memory_contexts() {
+    use crate::memory_filesystem::{MemoryContext, MemoryData};
+    use nix::sys::stat::{Mode, SFlag};
+    use std::path::Path;
+
+    let (src, dest) = (MemoryContext::new(1), MemoryContext::new(2));
+    src.add_file(Path::new("/a/b/file"), b"contents", 0o644).unwrap();
+    src.add_file(Path::new("/top"), b"top level file", 0o600).unwrap();
+    src.add_symlink(Path::new("/a/link"), Path::new("b/file")).unwrap();
+    src.link(Path::new("/a/b/file"), Path::new("/hard")).unwrap();
+    src.mknod(Path::new("/a/fifo"), SFlag::S_IFIFO, Mode::S_IRWXU, 0).unwrap();
+    dest.add_file(Path::new("/a/extra"), b"not in source", 0o644).unwrap();
+    let (atime, mtime) = (Timespec::new(1_000_000, 0), Timespec::new(2_000_000, 500_000));
+    for path in &["/a/b/file", "/a/b", "/a"] {
+        src.utimes(Path::new(path), atime, mtime).unwrap();
+    }
+    let config = r#"{"nodes": ["127.0.0.1:7000"], "src_share": "/src", "dest_share": "/dest",
+        "src_system": "Local", "dest_system": "Local", "debug_level": "OFF",
+        "num_threads": 1}"#;
+    let (stats, errors) = run_memory_sync(config, (&src, &dest));
+
+    assert!(errors.is_empty(), "sync errors {:?}", errors);
+    assert_eq!(stats.num_files, 7);
+    assert_eq!(stats.copied, 2);
+    assert_eq!(stats.symlink_created, 1);
+    assert_eq!(stats.hard_link_created, 1);
+    assert_eq!(stats.special_created, 1);
+    assert_eq!(stats.directory_created, 1);
+    assert_eq!(dest.data(Path::new("/a/b/file")), Some(MemoryData::File(b"contents".to_vec())));
+    assert_eq!(dest.data(Path::new("/a/link")), Some(MemoryData::Symlink(PathBuf::from("b/file"))));
+    assert_eq!(dest.data(Path::new("/a/extra")), None);
+    let (file, hard) = (dest.lstat(Path::new("/a/b/file")), dest.lstat(Path::new("/hard")));
+    let (file, hard) = (file.unwrap(), hard.unwrap());
+    assert_eq!((file.ino(), file.nlink()), (hard.ino(), 2));
+    // directory times are set after their contents are synced
+    for path in &["/a/b/file", "/a/b", "/a"] {
+        let stat = dest.lstat(Path::new(path)).unwrap();
+        assert_eq!(stat.atime().num_microseconds(), atime.num_microseconds());
+        assert_eq!(stat.mtime().num_microseconds(), mtime.num_microseconds());
+    }
+}
+
+#[test]
+fn test_dry_run_writes_plan() {
+    use crate::memory_filesystem::{MemoryContext, MemoryData};
+    use std::path::Path;
+
+    let (src, dest) = (MemoryContext::new(1), MemoryContext::new(2));
+    src.add_file(Path::new("/a/file"), b"contents", 0o644).unwrap();
+    src.add_file(Path::new("/b/new"), b"new", 0o644).unwrap();
+    dest.add_file(Path::new("/a/extra"), b"not in source", 0o644).unwrap();
+    dest.add_file(Path::new("/gone/old"), b"old", 0o644).unwrap();
+    let plan_path = std::env::temp_dir().join(format!("forklift-plan-{}.csv", std::process::id()));
+    let config = format!(
+        r#"{{"nodes": ["127.0.0.1:7000"], "src_share": "/src", "dest_share": "/dest",
+            "src_system": "Local", "dest_system": "Local", "debug_level": "OFF",
+            "num_threads": 1, "dry_run": {:?}, "plan_format": "Csv"}}"#,
+        plan_path
+    );
+    run_memory_sync(&config, (&src, &dest));
+
+    let plan = std::fs::read_to_string(&plan_path).unwrap();
+    std::fs::remove_file(&plan_path).unwrap();
+    let expected = "path,action,size\n/a,UpToDate,0\n/a/extra,Removed,13\n/a/file,FileCopied,8\n\
+                    /b,DirectoryCreated,0\n/b/new,FileCopied,3\n/gone,Removed,0\n\
+                    /gone/old,Removed,3\naction,count,size\nUpToDate,1,0\nFileCopied,2,11\n\
+                    DirectoryCreated,1,0\nRemoved,3,16\n";
+    assert_eq!(plan, expected);
+    // nothing was written to the destination
+    assert_eq!(dest.data(Path::new("/a/file")), None);
+    assert_eq!(dest.data(Path::new("/b")), None);
+    assert_eq!(dest.data(Path::new("/a/extra")), Some(MemoryData::File(b"not in source".to_vec())));
+}
+
+#[test]
+fn test_delete_policies() {
+    use crate::memory_filesystem::{MemoryContext, MemoryData};
+    use std::path::Path;
+
+    let date = chrono::Utc::now().format("%Y-%m-%d").to_string();
+    let quarantine = Path::new("/.forklift-quarantine").join(date);
+    for policy in &["never", "after", "quarantine"] {
+        let (src, dest) = (MemoryContext::new(1), MemoryContext::new(2));
+        src.add_file(Path::new("/a/file"), b"contents", 0o644).unwrap();
+        dest.add_file(Path::new("/a/extra"), b"not in source", 0o644).unwrap();
+        dest.add_file(Path::new("/gone/old"), b"old", 0o644).unwrap();
+        let config = format!(
+            r#"{{"nodes": ["127.0.0.1:7000"], "src_share": "/src", "dest_share": "/dest",
+                "src_system": "Local", "dest_system": "Local", "debug_level": "OFF",
+                "num_threads": 1, "delete": "{}"}}"#,
+            policy
+        );
+        let (stats, _) = run_memory_sync(&config, (&src, &dest));
+
+        assert_eq!(stats.copied, 1);
+        let (extra, old) = (dest.data(Path::new("/a/extra")), dest.data(Path::new("/gone/old")));
+        let moved = dest.data(&quarantine.join("a/extra"));
+        match *policy {
+            "never" => {
+                assert_eq!((stats.removed, stats.quarantined), (0, 0));
+                assert_eq!(extra, Some(MemoryData::File(b"not in source".to_vec())));
+                assert_eq!(old, Some(MemoryData::File(b"old".to_vec())));
+            }
+            "after" => {
+                // the file, the directory and its file
+                assert_eq!((stats.removed, stats.quarantined), (3, 0));
+                assert_eq!((extra, old), (None, None));
+            }
+            _ => {
+                assert_eq!((stats.removed, stats.quarantined), (0, 2));
+                assert_eq!((extra, dest.data(Path::new("/gone"))), (None, None));
+                assert_eq!(moved, Some(MemoryData::File(b"not in source".to_vec())));
+                let old = dest.data(&quarantine.join("gone/old"));
+                assert_eq!(old, Some(MemoryData::File(b"old".to_vec())));
+            }
+        }
+        assert_eq!(dest.data(Path::new("/a/file")), Some(MemoryData::File(b"contents".to_vec())));
+    }
+}
+
+#[test]
+fn test_filter_rules() {
+    use crate::memory_filesystem::{MemoryContext, MemoryData};
+    use std::path::Path;
+
+    for delete_excluded in &[false, true] {
+        let (src, dest) = (MemoryContext::new(1), MemoryContext::new(2));
+        src.add_file(Path::new("/a/file"), b"contents", 0o644).unwrap();
+        src.add_file(Path::new("/a/Thumbs.db"), b"thumbs", 0o644).unwrap();
+        src.add_file(Path::new("/.snapshot/hourly/file"), b"snapshot", 0o644).unwrap();
+        dest.add_file(Path::new("/a/Thumbs.db"), b"old thumbs", 0o644).unwrap();
+        dest.add_file(Path::new("/.snapshot/daily/file"), b"old snapshot", 0o644).unwrap();
+        let config = format!(
+            r#"{{"nodes": ["127.0.0.1:7000"], "src_share": "/src", "dest_share": "/dest",
+                "src_system": "Local", "dest_system": "Local", "debug_level": "OFF",
+                "num_threads": 1, "filter": ["- Thumbs.db", "- /.snapshot/"],
+                "delete_excluded": {}}}"#,
+            delete_excluded
+        );
+        let (stats, _) = run_memory_sync(&config, (&src, &dest));
+
+        // excluded source entries are not synced
+        assert_eq!(stats.copied, 1);
+        assert_eq!(dest.data(Path::new("/.snapshot/hourly")), None);
+        let (thumbs, snapshot) =
+            (dest.data(Path::new("/a/Thumbs.db")), dest.data(Path::new("/.snapshot/daily/file")));
+        if *delete_excluded {
+            assert_eq!(stats.removed, 4);
+            assert_eq!((thumbs, dest.data(Path::new("/.snapshot"))), (None, None));
+        } else {
+            // excluded destination entries are kept
+            assert_eq!(stats.removed, 0);
+            assert_eq!(thumbs, Some(MemoryData::File(b"old thumbs".to_vec())));
+            assert_eq!(snapshot, Some(MemoryData::File(b"old snapshot".to_vec())));
+        }
+    }
+}
+
+#[test]
+fn test_filter_protects_entries_in_extraneous_dirs() {
+    use crate::memory_filesystem::{MemoryContext, MemoryData};
+    use std::path::Path;
+
+    let date = chrono::Utc::now().format("%Y-%m-%d").to_string();
+    let quarantine = Path::new("/.forklift-quarantine").join(date);
+    let plan_path = std::env::temp_dir().join(format!("forklift-kept-{}.csv", std::process::id()));
+    for policy in &["during", "after", "quarantine", "dry_run"] {
+        let (src, dest) = (MemoryContext::new(1), MemoryContext::new(2));
+        src.add_file(Path::new("/a/file"), b"contents", 0o644).unwrap();
+        dest.add_file(Path::new("/old/Thumbs.db"), b"thumbs", 0o644).unwrap();
+        dest.add_file(Path::new("/old/sub/file"), b"old", 0o644).unwrap();
+        let delete = match *policy {
+            "dry_run" => format!(r#""dry_run": {:?}, "plan_format": "Csv""#, plan_path),
+            policy => format!(r#""delete": "{}""#, policy),
+        };
+        let config = format!(
+            r#"{{"nodes": ["127.0.0.1:7000"], "src_share": "/src", "dest_share": "/dest",
+                "src_system": "Local", "dest_system": "Local", "debug_level": "OFF",
+                "num_threads": 1, "filter": ["- Thumbs.db"], {}}}"#,
+            delete
+        );
+        let (stats, _) = run_memory_sync(&config, (&src, &dest));
+
+        // the excluded file is kept, and so is the directory holding it
+        let thumbs = Some(MemoryData::File(b"thumbs".to_vec()));
+        assert_eq!(dest.data(Path::new("/old/Thumbs.db")), thumbs);
+        match *policy {
+            "quarantine" => {
+                assert_eq!(stats.quarantined, 1);
+                let moved = dest.data(&quarantine.join("old/sub/file"));
+                assert_eq!(moved, Some(MemoryData::File(b"old".to_vec())));
+                assert_eq!(dest.data(&quarantine.join("old/Thumbs.db")), None);
+            }
+            "dry_run" => {
+                let plan = std::fs::read_to_string(&plan_path).unwrap();
+                std::fs::remove_file(&plan_path).unwrap();
+                assert!(plan.contains("/old/sub,Removed,0\n/old/sub/file,Removed,3\n"));
+                assert!(!plan.contains("Thumbs.db") && !plan.contains("/old,"));
+            }
+            _ => {
+                assert_eq!(stats.removed, 2);
+                assert_eq!(dest.data(Path::new("/old/sub")), None);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_files_from() {
+    use crate::memory_filesystem::{MemoryContext, MemoryData};
+    use std::path::Path;
+
+    let (src, dest) = (MemoryContext::new(1), MemoryContext::new(2));
+    src.add_file(Path::new("/a/b/file"), b"listed", 0o644).unwrap();
+    src.add_file(Path::new("/a/other"), b"not listed", 0o644).unwrap();
+    src.add_file(Path::new("/c/file"), b"also listed", 0o644).unwrap();
+    dest.add_file(Path::new("/extra"), b"not in source", 0o644).unwrap();
+    let list_path = std::env::temp_dir().join(format!("forklift-list-{}", std::process::id()));
+    std::fs::write(&list_path, "a/b/file\n/c/file\nmissing\n").unwrap();
+    let config = |nodes: &str| {
+        format!(
+            r#"{{"nodes": [{}], "src_share": "/src", "dest_share": "/dest",
+                "src_system": "Local", "dest_system": "Local", "debug_level": "OFF",
+                "num_threads": 1, "files_from": {:?}}}"#,
+            nodes, list_path
+        )
+    };
+    let (stats, errors) = run_memory_sync(&config(r#""127.0.0.1:7000""#), (&src, &dest));
+
+    // only the listed files are synced, in parent directories created for them
+    assert_eq!((stats.copied, stats.removed, errors.len()), (2, 0, 1));
+    assert_eq!(stats.failed, 1);
+    assert_eq!(dest.data(Path::new("/a/b/file")), Some(MemoryData::File(b"listed".to_vec())));
+    assert_eq!(dest.data(Path::new("/c/file")), Some(MemoryData::File(b"also listed".to_vec())));
+    assert_eq!(dest.data(Path::new("/a/other")), None);
+    // nothing is removed
+    assert_eq!(dest.data(Path::new("/extra")), Some(MemoryData::File(b"not in source".to_vec())));
+
+    // with two nodes, the missing path is only reported by one of them
+    let orders = [r#""127.0.0.1:7000", "127.0.0.1:7001""#, r#""127.0.0.1:7001", "127.0.0.1:7000""#];
+    let failed: u64 =
+        orders.iter().map(|nodes| run_memory_sync(&config(nodes), (&src, &dest)).0.failed).sum();
+    std::fs::remove_file(&list_path).unwrap();
+    assert_eq!(failed, 1);
+}
+
+#[test]
+fn test_ignore_existing() {
+    use crate::memory_filesystem::{MemoryContext, MemoryData};
+    use std::path::Path;
+
+    let (src, dest) = (MemoryContext::new(1), MemoryContext::new(2));
+    src.add_file(Path::new("/file"), b"new contents", 0o644).unwrap();
+    src.add_file(Path::new("/new"), b"new", 0o644).unwrap();
+    dest.add_file(Path::new("/file"), b"old", 0o600).unwrap();
+    let (src_time, dest_time) = (Timespec::new(2_000_000, 0), Timespec::new(1_000_000, 0));
+    src.utimes(Path::new("/file"), src_time, src_time).unwrap();
+    dest.utimes(Path::new("/file"), dest_time, dest_time).unwrap();
+    let config = r#"{"nodes": ["127.0.0.1:7000"], "src_share": "/src", "dest_share": "/dest",
+        "src_system": "Local", "dest_system": "Local", "debug_level": "OFF",
+        "num_threads": 1, "compare": "ignore-existing"}"#;
+    let (stats, errors) = run_memory_sync(config, (&src, &dest));
+
+    assert!(errors.is_empty(), "sync errors {:?}", errors);
+    assert_eq!(stats.copied, 1);
+    assert_eq!(dest.data(Path::new("/new")), Some(MemoryData::File(b"new".to_vec())));
+    // the existing file keeps its contents, permissions and times
+    assert_eq!(dest.data(Path::new("/file")), Some(MemoryData::File(b"old".to_vec())));
+    let stat = dest.lstat(Path::new("/file")).unwrap();
+    assert_eq!(stat.mode() & 0o7777, 0o600);
+    assert_eq!(stat.mtime().num_microseconds(), dest_time.num_microseconds());
+}
+
+#[test]
+fn test_failed_entries_are_skipped() {
+    use crate::memory_filesystem::{MemoryContext, MemoryData};
+    use std::path::Path;
+
+    let (src, dest) = (MemoryContext::new(1), MemoryContext::new(2));
+    src.add_file(Path::new("/a/file"), b"blocked", 0o644).unwrap();
+    src.add_file(Path::new("/b/file"), b"contents", 0o644).unwrap();
+    // the destination of the source directory /a is a file, so it can not be synced
+    dest.add_file(Path::new("/a"), b"not a directory", 0o644).unwrap();
+    let config = r#"{"nodes": ["127.0.0.1:7000"], "src_share": "/src", "dest_share": "/dest",
+        "src_system": "Local", "dest_system": "Local", "debug_level": "OFF",
+        "num_threads": 1}"#;
+    let (stats, _) = run_memory_sync(config, (&src, &dest));
+
+    // walking the destination /a and syncing /a/file both fail, without stopping the run
+    assert_eq!((stats.failed, stats.copied), (2, 1));
+    assert_eq!(dest.data(Path::new("/b/file")), Some(MemoryData::File(b"contents".to_vec())));
+}
+
+#[test]
+fn test_retry_failures() {
+    use crate::failures::{read_failures, Operation};
+    use crate::memory_filesystem::{MemoryContext, MemoryData};
+    use std::path::Path;
+
+    let src = MemoryContext::new(1);
+    src.add_file(Path::new("/a/file"), b"blocked", 0o644).unwrap();
+    src.add_file(Path::new("/b/file"), b"contents", 0o644).unwrap();
+    // the destination of the source directory /a is a file, so it can not be synced
+    let dest = MemoryContext::new(2);
+    dest.add_file(Path::new("/a"), b"not a directory", 0o644).unwrap();
+    let id = std::process::id();
+    let failures_path = std::env::temp_dir().join(format!("forklift-failures-{}.json", id));
+    let config = format!(
+        r#"{{"nodes": ["127.0.0.1:7000"], "src_share": "/src", "dest_share": "/dest",
+            "src_system": "Local", "dest_system": "Local", "debug_level": "OFF",
+            "num_threads": 1, "failures_path": {:?}}}"#,
+        failures_path
+    );
+    let (stats, _) = run_memory_sync(&config, (&src, &dest));
+    let failures = read_failures(&failures_path).unwrap();
+    assert_eq!(failures.len() as u64, stats.failed);
+    assert!(failures.iter().all(|f| f.path.starts_with("a") && f.node == "127.0.0.1:7000"));
+    assert!(failures.iter().any(|f| f.operation == Operation::OpenDestinationDir));
+
+    // only the failed entries are tried again
+    let dest = MemoryContext::new(3);
+    let config = format!(
+        r#"{{"nodes": ["127.0.0.1:7000"], "src_share": "/src", "dest_share": "/dest",
+            "src_system": "Local", "dest_system": "Local", "debug_level": "OFF",
+            "num_threads": 1, "failures_path": {:?}, "retry_failures": {:?}}}"#,
+        failures_path, failures_path
+    );
+    let (stats, _) = run_memory_sync(&config, (&src, &dest));
+    assert_eq!((stats.failed, stats.copied), (0, 1));
+    assert_eq!(read_failures(&failures_path).unwrap(), vec![]);
+    std::fs::remove_file(&failures_path).unwrap();
+    assert_eq!(dest.data(Path::new("/a/file")), Some(MemoryData::File(b"blocked".to_vec())));
+    assert_eq!(dest.data(Path::new("/b/file")), None);
+}
+
+#[test]
+fn test_hard_links_on_two_nodes() {
+    use crate::memory_filesystem::{MemoryContext, MemoryData};
+    use std::path::Path;
+
+    let src = MemoryContext::new(1);
+    src.add_file(Path::new("/a/file"), b"linked", 0o644).unwrap();
+    src.add_dir(Path::new("/b"), 0o755).unwrap();
+    src.link(Path::new("/a/file"), Path::new("/b/hard")).unwrap();
+    let (first, second) = ("127.0.0.1:7000", "127.0.0.1:7001");
+    // each node mounts the source itself, so the device ids they see differ
+    for devs in &[(10, 20), (11, 21), (12, 22), (13, 23), (14, 24), (15, 25)] {
+        let dest = MemoryContext::new(2);
+        let (mut synced, mut copied, mut linked) = (0, 0, 0);
+        for (dev, (this, other)) in &[(devs.0, (first, second)), (devs.1, (second, first))] {
+            let config = format!(
+                r#"{{"nodes": [{:?}, {:?}], "src_share": "/src", "dest_share": "/dest",
+                    "src_system": "Local", "dest_system": "Local", "debug_level": "OFF",
+                    "num_threads": 1}}"#,
+                this, other
+            );
+            let (stats, errors) = run_memory_sync(&config, (&src.with_dev(*dev), &dest));
+            assert!(errors.is_empty(), "sync errors {:?}", errors);
+            synced += stats.num_synced;
+            copied += stats.copied;
+            linked += stats.hard_link_created;
+        }
+        // each entry is synced by exactly one node, which syncs both names of the file
+        assert_eq!((synced, copied, linked), (4, 1, 1));
+        assert_eq!(dest.data(Path::new("/b/hard")), Some(MemoryData::File(b"linked".to_vec())));
+        let (file, hard) = (dest.lstat(Path::new("/a/file")), dest.lstat(Path::new("/b/hard")));
+        let (file, hard) = (file.unwrap(), hard.unwrap());
+        assert_eq!((file.ino(), file.nlink()), (hard.ino(), 2));
+    }
+}
@@ -1,16 +1,27 @@
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 use crossbeam::channel::Receiver;
 use crossbeam::channel::Sender;
+use crossbeam::select;
 use log::*;
 use rayon::*;
 
+use crate::chunked_copy::{run_range, RangeQueue, RANGE_SIZE};
 use crate::error::*;
+use crate::failures::Operation;
 use crate::filesystem::*;
 use crate::filesystem_entry::Entry;
 use crate::filesystem_ops::*;
+use crate::id_map::IdMap;
+use crate::plan::{plan_entry, Action, Plan};
 use crate::postgres_logger::LogMessage;
 use crate::progress_message::ProgressMessage;
+use crate::verify::Synced;
+
+/// (dev, ino) of a hard linked source file, to the first destination path synced for it
+pub type HardLinks = Arc<Mutex<HashMap<(u64, u64), PathBuf>>>;
 
 #[derive(Clone)]
 /// threaded worker handling Entry Processing for the rsync
@@ -23,6 +34,18 @@ pub struct RsyncWorker {
     src_context: ProtocolContext,
     /// destination context
     dest_context: ProtocolContext,
+    /// map of source owners/ACL principals to destination ones
+    id_map: Arc<IdMap>,
+    /// hard linked files already synced by this node
+    hard_links: HardLinks,
+    /// settings of how each entry is synced
+    options: SyncOptions,
+    /// ranges of huge files, copied by all the workers of the run
+    ranges: RangeQueue,
+    /// files whose contents were written in the run, to verify at its end
+    synced: Synced,
+    /// what a dry run would do to each entry
+    plan: Plan,
     /// input channel from WalkWorker
     pub input: Receiver<Option<Entry>>,
     /// channel to send progress
@@ -36,8 +59,8 @@ impl RsyncWorker {
     pub fn new(
         source: &Path,
         destination: &Path,
-        src_context: ProtocolContext,
-        dest_context: ProtocolContext,
+        (src_context, dest_context): (ProtocolContext, ProtocolContext),
+        (id_map, hard_links, options, plan): (Arc<IdMap>, HardLinks, SyncOptions, Plan),
         input: Receiver<Option<Entry>>,
         progress_output: Sender<ProgressMessage>,
         log_output: Sender<LogMessage>,
@@ -47,21 +70,62 @@ impl RsyncWorker {
             destination: destination.to_path_buf(),
             src_context,
             dest_context,
+            id_map,
+            hard_links,
+            options,
+            ranges: RangeQueue::new(RANGE_SIZE),
+            synced: Arc::new(Mutex::new(vec![])),
+            plan,
             input,
             progress_output,
             log_output,
         }
     }
 
-    /// Process the entries sent through input channel
-    pub fn start(self, pool: &ThreadPool) -> ForkliftResult<()> {
+    /// a copy of the worker for one run, sharing ranges and synced files with the other
+    /// workers of the run
+    pub fn for_run(&self, ranges: &RangeQueue, synced: &Synced) -> RsyncWorker {
+        RsyncWorker { ranges: ranges.clone(), synced: synced.clone(), ..self.clone() }
+    }
+
+    /// Process the entries sent through input channel.  Entries are tried again after
+    /// transient errors, and skipped once they fail for good, so the other entries are
+    /// still synced.  Returns the number of entries that failed
+    pub fn start(self, pool: &ThreadPool) -> ForkliftResult<u64> {
         let id = get_index_or_rand(pool);
-        for entry in self.input.iter() {
+        let contexts = (&self.src_context, &self.dest_context);
+        let mut failed = 0;
+        loop {
+            let entry = select! {
+                recv(self.input) -> entry => entry,
+                recv(self.ranges.receiver()) -> job => {
+                    if let Ok(job) = job {
+                        run_range(job, contexts, &self.progress_output);
+                    }
+                    continue;
+                }
+            };
             let input_entry = match entry {
-                Some(e) => e,
-                None => break,
+                Ok(Some(e)) => e,
+                Ok(None) | Err(_) => break,
+            };
+            let path = input_entry.path();
+            if self.options.dry_run {
+                let planned = self.options.retry.run(path, || self.plan(&input_entry));
+                if let Err((e, attempts)) = planned {
+                    self.report_failure((path, Operation::Plan), e, attempts)?;
+                    failed += 1;
+                }
+                continue;
+            }
+            let sync_outcome = match self.options.retry.run(path, || self.sync(&input_entry)) {
+                Ok(outcome) => outcome,
+                Err((e, attempts)) => {
+                    self.report_failure((path, Operation::Sync), e, attempts)?;
+                    failed += 1;
+                    continue;
+                }
             };
-            let sync_outcome = self.sync(&input_entry)?;
             let len = self.input.len();
             debug!("Sync Thread {:?} Outcome: {:?} Num left {:?}", id, sync_outcome, len,);
             let progress = ProgressMessage::DoneSyncing(sync_outcome);
@@ -73,6 +137,29 @@ impl RsyncWorker {
             };
             trace!("rec len {:?}", len);
         }
+        // help with the huge files other workers are still copying, until they are done
+        let RsyncWorker { src_context, dest_context, ranges, progress_output, .. } = self;
+        for job in ranges.into_receiver().iter() {
+            run_range(job, (&src_context, &dest_context), &progress_output);
+        }
+        Ok(failed)
+    }
+
+    /// record an entry that could not be synced
+    fn report_failure(
+        &self,
+        (path, operation): (&Path, Operation),
+        error: ForkliftError,
+        attempts: u32,
+    ) -> ForkliftResult<()> {
+        let path = get_rel_path(path, &self.source)?;
+        let failed = ProgressMessage::Failed { path, operation, error, attempts };
+        if let Err(e) = self.progress_output.send(failed) {
+            return Err(ForkliftError::CrossbeamChannelError(format!(
+                "Error: {:?}, unable to send progress",
+                e
+            )));
+        };
         Ok(())
     }
 
@@ -87,17 +174,45 @@ impl RsyncWorker {
             &self.destination,
             src_context,
             dest_context,
+            &self.id_map,
             &self.log_output,
         )?;
-        let dest_entry = Entry::new(&dest_path, dest_context);
+        if let Some(target) = self.hard_link_target(src_entry, dest_path)? {
+            return make_hard_link(&target, dest_path, dest_context);
+        }
+        let mut dest_entry = Entry::new(&dest_path, dest_context);
         let mut outcome = sync_entry(
             src_entry,
             &dest_entry,
             src_context,
             dest_context,
+            (&self.id_map, self.options, Some(&self.ranges)),
             &self.progress_output,
             &self.log_output,
         )?;
+        if outcome == SyncOutcome::SpecialFileSkipped {
+            return Ok(outcome);
+        }
+        // like rsync, ignore-existing leaves existing files alone, permissions and times too
+        if outcome == SyncOutcome::UpToDate && self.options.compare == ComparePolicy::IgnoreExisting
+        {
+            return Ok(outcome);
+        }
+        if self.options.temp_files {
+            // the contents may have been renamed over the destination as a new file
+            dest_entry = Entry::new(dest_path, dest_context);
+        }
+        // files whose contents were written are read again at the end of the run
+        match (&outcome, self.options.verify) {
+            (SyncOutcome::FileCopied(..), true) | (SyncOutcome::ChecksumUpdated(..), true) => {
+                let paths = (src_entry.path().to_path_buf(), dest_path.to_path_buf());
+                match self.synced.lock() {
+                    Ok(mut synced) => synced.push(paths),
+                    Err(_) => return Err(ForkliftError::FSError("failed to lock".to_string())),
+                }
+            }
+            _ => (),
+        }
         let is_dir = match src_entry.is_dir() {
             Some(d) => d,
             None => {
@@ -105,21 +220,89 @@ impl RsyncWorker {
             }
         };
         if !is_dir {
-            let temp_outcome = copy_permissions(
+            let permissions = copy_permissions(
                 src_entry,
                 &dest_entry,
                 src_context,
                 dest_context,
+                &self.id_map,
                 &self.log_output,
-            )?;
+            );
+            // set times last, since writes change the destination mtime
+            // (directory times are set once their children are synced, see WalkWorker).
+            // They are set even if the permissions failed, so the contents are not
+            // copied again by the next run
+            copy_times(src_entry, dest_path, dest_context)?;
+            let temp_outcome = permissions?;
             let current_outcome = outcome.clone();
             outcome = match (outcome, temp_outcome) {
                 (SyncOutcome::UpToDate, SyncOutcome::PermissionsUpdated) => {
                     SyncOutcome::PermissionsUpdated
                 }
                 (..) => current_outcome,
-            }
+            };
         }
         Ok(outcome)
     }
+
+    /// add what syncing an Entry would do to the plan, without writing anything
+    fn plan(&self, src_entry: &Entry) -> ForkliftResult<()> {
+        let rel_path = get_rel_path(src_entry.path(), &self.source)?;
+        let dest_path = &self.destination.join(&rel_path);
+        let (src_context, dest_context) = (&self.src_context, &self.dest_context);
+        let is_file = src_entry.is_dir() == Some(false);
+        let size = src_entry.metadata().filter(|_| is_file).map_or(0, |stat| stat.size());
+        let action = match self.hard_link_target(src_entry, dest_path)? {
+            Some(target) => {
+                let inode = |path: &Path| dest_context.stat(path).map(|s| (s.dev(), s.ino())).ok();
+                match (inode(dest_path), inode(&target)) {
+                    (Some(linked), Some(first)) if linked == first => Action::UpToDate,
+                    (..) => Action::HardLinkCreated,
+                }
+            }
+            None => {
+                let dest_entry = Entry::new(dest_path, dest_context);
+                let settings = (self.id_map.as_ref(), self.options);
+                plan_entry(src_entry, &dest_entry, src_context, dest_context, settings)?
+            }
+        };
+        self.plan.add(dest_path, action, size)
+    }
+
+    /// if src_entry is a name of a hard linked file that was already synced, get the
+    /// first destination name, to link dest_path to instead of copying the data again.
+    /// Otherwise, remember dest_path as the first name and return None
+    fn hard_link_target(
+        &self,
+        src_entry: &Entry,
+        dest_path: &Path,
+    ) -> ForkliftResult<Option<PathBuf>> {
+        match (&self.src_context, &self.dest_context) {
+            (ProtocolContext::Samba(..), _) | (_, ProtocolContext::Samba(..)) => return Ok(None),
+            (..) => (),
+        }
+        let key = match src_entry.metadata().as_ref().and_then(hard_link_key) {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        let target = match self.hard_links.lock() {
+            Ok(mut links) => match links.get(&key) {
+                // an entry tried again finds the path it was remembered at
+                Some(target) if target != dest_path => target.clone(),
+                _ => {
+                    links.insert(key, dest_path.to_path_buf());
+                    return Ok(None);
+                }
+            },
+            Err(_) => {
+                return Err(ForkliftError::FSError("failed to lock".to_string()));
+            }
+        };
+        // a dry run does not create the first name, so it plans a link to it anyway
+        if !self.options.dry_run && self.dest_context.stat(&target).is_err() {
+            debug!("hard link target {:?} is missing, copying {:?}", target, dest_path);
+            return Ok(None);
+        }
+        Ok(Some(target))
+    }
 }
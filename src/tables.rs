@@ -152,6 +152,18 @@ pub struct TotalSync {
     checksum_updated: i64,
     directory_created: i64,
     directory_updated: i64,
+    hard_link_created: i64,
+    hole_bytes_skipped: i64,
+    special_created: i64,
+    special_skipped: i64,
+    bytes_matched: i64,
+    bytes_transferred: i64,
+    files_verified: i64,
+    verify_failed: i64,
+    removed: i64,
+    quarantined: i64,
+    filtered_out: i64,
+    failed: i64,
 }
 
 impl TotalSync {
@@ -170,6 +182,18 @@ impl TotalSync {
             checksum_updated: stats.checksum_updated as i64,
             directory_created: stats.directory_created as i64,
             directory_updated: stats.directory_updated as i64,
+            hard_link_created: stats.hard_link_created as i64,
+            hole_bytes_skipped: stats.hole_bytes_skipped as i64,
+            special_created: stats.special_created as i64,
+            special_skipped: stats.special_skipped as i64,
+            bytes_matched: stats.bytes_matched as i64,
+            bytes_transferred: stats.bytes_transferred as i64,
+            files_verified: stats.files_verified as i64,
+            verify_failed: stats.verify_failed as i64,
+            removed: stats.removed as i64,
+            quarantined: stats.quarantined as i64,
+            filtered_out: stats.filtered_out as i64,
+            failed: stats.failed as i64,
         }
     }
 }
@@ -181,6 +205,8 @@ pub struct Files {
     path: String, //Share/Path
     src_checksum: Vec<u8>,
     dest_checksum: Vec<u8>,
+    /// name of the hash algorithm of the checksums
+    checksum_algorithm: String,
     size: i64,
     last_modified_time: NaiveDateTime,
 }
@@ -191,10 +217,18 @@ impl Files {
         path: &str,
         src_checksum: Vec<u8>,
         dest_checksum: Vec<u8>,
+        checksum_algorithm: &str,
         size: i64,
         last_modified_time: NaiveDateTime,
     ) -> Self {
-        Files { path: path.to_string(), src_checksum, dest_checksum, size, last_modified_time }
+        Files {
+            path: path.to_string(),
+            src_checksum,
+            dest_checksum,
+            checksum_algorithm: checksum_algorithm.to_string(),
+            size,
+            last_modified_time,
+        }
     }
 }
 
@@ -278,9 +312,13 @@ pub fn init_files(conn: &Connection) -> ForkliftResult<()> {
         node_id BIGINT REFERENCES Nodes(node_id),
         src_checksum BYTEA,
         dest_checksum BYTEA,
+        checksum_algorithm TEXT,
         size BIGINT,
         last_modified_time TIMESTAMP)";
     conn.execute(state, &[])?;
+    // tables created by older versions are missing the newer columns
+    let state = "ALTER TABLE Files ADD COLUMN IF NOT EXISTS checksum_algorithm TEXT";
+    conn.execute(state, &[])?;
     Ok(())
 }
 
@@ -300,8 +338,35 @@ pub fn init_totalsync(conn: &Connection) -> ForkliftResult<()> {
         checksum_updated BIGINT,
         directory_created BIGINT,
         directory_updated BIGINT,
+        hard_link_created BIGINT,
+        hole_bytes_skipped BIGINT,
+        special_created BIGINT,
+        special_skipped BIGINT,
+        bytes_matched BIGINT,
+        bytes_transferred BIGINT,
+        files_verified BIGINT,
+        verify_failed BIGINT,
+        removed BIGINT,
+        quarantined BIGINT,
+        filtered_out BIGINT,
+        failed BIGINT,
         timestamp Timestamp)";
     conn.execute(state, &[])?;
+    // tables created by older versions are missing the newer columns
+    let state = "ALTER TABLE TotalSync
+        ADD COLUMN IF NOT EXISTS hard_link_created BIGINT,
+        ADD COLUMN IF NOT EXISTS hole_bytes_skipped BIGINT,
+        ADD COLUMN IF NOT EXISTS special_created BIGINT,
+        ADD COLUMN IF NOT EXISTS special_skipped BIGINT,
+        ADD COLUMN IF NOT EXISTS bytes_matched BIGINT,
+        ADD COLUMN IF NOT EXISTS bytes_transferred BIGINT,
+        ADD COLUMN IF NOT EXISTS files_verified BIGINT,
+        ADD COLUMN IF NOT EXISTS verify_failed BIGINT,
+        ADD COLUMN IF NOT EXISTS removed BIGINT,
+        ADD COLUMN IF NOT EXISTS quarantined BIGINT,
+        ADD COLUMN IF NOT EXISTS filtered_out BIGINT,
+        ADD COLUMN IF NOT EXISTS failed BIGINT";
+    conn.execute(state, &[])?;
     Ok(())
 }
 
@@ -417,8 +482,8 @@ pub fn update_totalsync(stat: &TotalSync, conn: &Connection) -> ForkliftResult<(
     let socket = get_current_node()?;
     let node_id = get_node_id(&socket, conn)?;
     conn.execute(
-        "INSERT INTO TotalSync(node_id, total_files, total_size, num_synced, up_to_date, copied, symlink_created, symlink_updated, symlink_skipped, permissions_updated, checksum_updated, directory_created, directory_updated) VALUES($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
-        ON CONFLICT (node_id) DO UPDATE SET total_files = $2, total_size = $3, num_synced = $4, up_to_date = $5, copied = $6, symlink_created = $7, symlink_updated = $8, symlink_skipped = $9, permissions_updated = $10, checksum_updated = $11, directory_created = $12, directory_updated = $13 WHERE totalsync.node_id = $1",
+        "INSERT INTO TotalSync(node_id, total_files, total_size, num_synced, up_to_date, copied, symlink_created, symlink_updated, symlink_skipped, permissions_updated, checksum_updated, directory_created, directory_updated, hard_link_created, hole_bytes_skipped, special_created, special_skipped, bytes_matched, bytes_transferred, files_verified, verify_failed, removed, quarantined, filtered_out, failed) VALUES($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20, $21, $22, $23, $24, $25)
+        ON CONFLICT (node_id) DO UPDATE SET total_files = $2, total_size = $3, num_synced = $4, up_to_date = $5, copied = $6, symlink_created = $7, symlink_updated = $8, symlink_skipped = $9, permissions_updated = $10, checksum_updated = $11, directory_created = $12, directory_updated = $13, hard_link_created = $14, hole_bytes_skipped = $15, special_created = $16, special_skipped = $17, bytes_matched = $18, bytes_transferred = $19, files_verified = $20, verify_failed = $21, removed = $22, quarantined = $23, filtered_out = $24, failed = $25 WHERE totalsync.node_id = $1",
         &[
             &node_id,
             &stat.total_files,
@@ -433,6 +498,18 @@ pub fn update_totalsync(stat: &TotalSync, conn: &Connection) -> ForkliftResult<(
             &stat.checksum_updated,
             &stat.directory_created,
             &stat.directory_updated,
+            &stat.hard_link_created,
+            &stat.hole_bytes_skipped,
+            &stat.special_created,
+            &stat.special_skipped,
+            &stat.bytes_matched,
+            &stat.bytes_transferred,
+            &stat.files_verified,
+            &stat.verify_failed,
+            &stat.removed,
+            &stat.quarantined,
+            &stat.filtered_out,
+            &stat.failed,
         ],
     )?;
     Ok(())
@@ -442,12 +519,13 @@ pub fn update_totalsync(stat: &TotalSync, conn: &Connection) -> ForkliftResult<(
 pub fn update_files(file: &Files, conn: &Connection) -> ForkliftResult<()> {
     let socket = get_current_node()?;
     let node_id = get_node_id(&socket, conn)?;
-    conn.execute("INSERT INTO Files(path, node_id, src_checksum, dest_checksum, size, last_modified_time) VALUES($1, $2, $3, $4, $5, $6)
-        ON CONFLICT (path) DO UPDATE SET node_id = $2, src_checksum = $3, dest_checksum = $4, size = $5, last_modified_time = $6 WHERE files.path = $1",
+    conn.execute("INSERT INTO Files(path, node_id, src_checksum, dest_checksum, checksum_algorithm, size, last_modified_time) VALUES($1, $2, $3, $4, $5, $6, $7)
+        ON CONFLICT (path) DO UPDATE SET node_id = $2, src_checksum = $3, dest_checksum = $4, checksum_algorithm = $5, size = $6, last_modified_time = $7 WHERE files.path = $1",
          &[&file.path,
          &node_id,
          &file.src_checksum,
          &file.dest_checksum,
+         &file.checksum_algorithm,
          &file.size,
          &file.last_modified_time])?;
     Ok(())
@@ -0,0 +1,225 @@
+use crate::content_hash::HashAlgorithm;
+use crate::delta::checksum;
+use crate::error::{ForkliftError, ForkliftResult};
+use crate::failures::Operation;
+use crate::filesystem::*;
+use crate::filesystem_entry::Entry;
+use crate::filesystem_ops::*;
+use crate::id_map::IdMap;
+use crate::postgres_logger::{send_mess, LogMessage};
+use crate::progress_message::ProgressMessage;
+
+use crossbeam::channel::{self, Receiver, Sender};
+use log::*;
+use nix::fcntl::OFlag;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// number of times a file that fails verification is synced again
+const VERIFY_RETRIES: u32 = 2;
+
+/// (source, destination) paths of the files whose contents were written in a run
+pub type Synced = Arc<Mutex<Vec<(PathBuf, PathBuf)>>>;
+
+/// re-read every synced file from both sides with the verify contexts (opened separately
+/// from the ones that wrote them, so nothing comes back from their caches), and compare
+/// the checksums of the whole files.  Mismatched files are synced again with the options
+/// of the run, up to VERIFY_RETRIES times, and reported as failed (with their path relative
+/// to source) if they still do not match.  Each context pair verifies files on its own thread
+pub fn verify_synced(
+    synced: Vec<(PathBuf, PathBuf)>,
+    contexts: &[(ProtocolContext, ProtocolContext)],
+    (id_map, options, source): (&IdMap, SyncOptions, &Path),
+    progress_send: &Sender<ProgressMessage>,
+    logs_send: &Sender<LogMessage>,
+) -> ForkliftResult<()> {
+    if contexts.is_empty() {
+        return Err(ForkliftError::FSError("No contexts to verify with".to_string()));
+    }
+    debug!("Verifying {} files", synced.len());
+    let (send_files, files) = channel::unbounded();
+    for paths in synced {
+        if send_files.send(paths).is_err() {
+            return Err(ForkliftError::CrossbeamChannelError("Unable to queue file".to_string()));
+        }
+    }
+    drop(send_files);
+    // each thread sends back how it ended, so that errors are returned rather than panicking
+    let (send_result, results) = channel::unbounded();
+    rayon::scope(|spawner| {
+        for (src_context, dest_context) in contexts {
+            let (files, send_result) = (files.clone(), send_result.clone());
+            spawner.spawn(move |_| {
+                let contexts = (src_context, dest_context);
+                let sends = (progress_send, logs_send);
+                let result = verify_files(files, contexts, (id_map, options, source), sends);
+                // results is not dropped before the scope ends, so this can not fail
+                send_result.send(result).ok();
+            });
+        }
+    });
+    drop(send_result);
+    results.iter().collect()
+}
+
+/// helper for verify_synced; verify the queued files with one pair of contexts, until
+/// the queue is empty
+fn verify_files(
+    files: Receiver<(PathBuf, PathBuf)>,
+    contexts: (&ProtocolContext, &ProtocolContext),
+    (id_map, options, source): (&IdMap, SyncOptions, &Path),
+    sends: (&Sender<ProgressMessage>, &Sender<LogMessage>),
+) -> ForkliftResult<()> {
+    let send = |mess: ProgressMessage| match sends.0.send(mess) {
+        Ok(()) => Ok(()),
+        Err(_) => Err(ForkliftError::CrossbeamChannelError("Unable to send progress".to_string())),
+    };
+    for (src_path, dest_path) in files.iter() {
+        let paths = (src_path.as_path(), dest_path.as_path());
+        let mess = match verify_file(paths, contexts, (id_map, options), sends) {
+            Ok(()) => ProgressMessage::Verified(true),
+            Err((error, attempts)) => {
+                send(ProgressMessage::Verified(false))?;
+                match get_rel_path(&src_path, source) {
+                    Ok(path) => {
+                        let operation = Operation::Verify;
+                        ProgressMessage::Failed { path, operation, error, attempts }
+                    }
+                    Err(e) => ProgressMessage::SendError(e),
+                }
+            }
+        };
+        send(mess)?;
+    }
+    Ok(())
+}
+
+/// verify one file, syncing it again if it does not match.  Returns the last error,
+/// and the number of times the file was verified, if the destination does not end up
+/// matching the source
+fn verify_file(
+    paths: (&Path, &Path),
+    contexts: (&ProtocolContext, &ProtocolContext),
+    (id_map, options): (&IdMap, SyncOptions),
+    sends: (&Sender<ProgressMessage>, &Sender<LogMessage>),
+) -> Result<(), (ForkliftError, u32)> {
+    // the size and mtime of the files already match, so only their contents can tell
+    let options = SyncOptions { compare: ComparePolicy::Checksum, ..options };
+    let mut attempts = 1;
+    loop {
+        match same_checksums(paths, contexts, options.hash, sends.1) {
+            Ok(true) => return Ok(()),
+            Ok(false) if attempts > VERIFY_RETRIES => {
+                let error = ForkliftError::ChecksumError(format!(
+                    "{:?} still does not match the source after syncing it again",
+                    paths.1
+                ));
+                return Err((error, attempts));
+            }
+            Ok(false) => (),
+            Err(e) => return Err((e, attempts)),
+        }
+        debug!("Syncing {:?} again, attempt {}", paths.0, attempts);
+        attempts += 1;
+        if let Err(e) = sync_again(paths, contexts, (id_map, options), sends) {
+            return Err((e, attempts));
+        }
+    }
+}
+
+/// helper for verify_file; read both files, logging a ChecksumError if they differ
+fn same_checksums(
+    (src_path, dest_path): (&Path, &Path),
+    (src_context, dest_context): (&ProtocolContext, &ProtocolContext),
+    algorithm: HashAlgorithm,
+    logs_send: &Sender<LogMessage>,
+) -> ForkliftResult<bool> {
+    let src_err = format!("Could not open {:?} for reading", src_path);
+    let dest_err = format!("Could not open {:?} for reading", dest_path);
+    let src_file = open_file(src_path, src_context, OFlag::O_RDONLY, &src_err)?;
+    let dest_file = open_file(dest_path, dest_context, OFlag::O_RDONLY, &dest_err)?;
+    let (src_check, dest_check) =
+        (checksum(&src_file, algorithm)?, checksum(&dest_file, algorithm)?);
+    if src_check == dest_check {
+        trace!("Verified {:?}", dest_path);
+        return Ok(true);
+    }
+    let mess = LogMessage::Error(ForkliftError::ChecksumError(format!(
+        "Verifying {:?} failed, source checksum {:?}, destination checksum {:?}",
+        dest_path, src_check, dest_check
+    )));
+    send_mess(mess, logs_send)?;
+    Ok(false)
+}
+
+/// helper for verify_file; sync the file again, like an RsyncWorker
+fn sync_again(
+    (src_path, dest_path): (&Path, &Path),
+    (src_context, dest_context): (&ProtocolContext, &ProtocolContext),
+    (id_map, options): (&IdMap, SyncOptions),
+    (progress_send, logs_send): (&Sender<ProgressMessage>, &Sender<LogMessage>),
+) -> ForkliftResult<()> {
+    let (src, dest) = (Entry::new(src_path, src_context), Entry::new(dest_path, dest_context));
+    let sync = (id_map, options, None);
+    sync_entry(&src, &dest, src_context, dest_context, sync, progress_send, logs_send)?;
+    // with temp_files the contents were renamed over the destination as a new file
+    let dest = Entry::new(dest_path, dest_context);
+    copy_permissions(&src, &dest, src_context, dest_context, id_map, logs_send)?;
+    // rewriting the contents changed the destination mtime
+    copy_times(&src, dest_path, dest_context)?;
+    Ok(())
+}
+
+#[test]
+fn test_verify_file_syncs_mismatch() {
+    use crate::memory_filesystem::{MemoryContext, MemoryData};
+    let (src, dest) = (MemoryContext::new(1), MemoryContext::new(2));
+    let path = Path::new("/file");
+    src.add_file(path, b"source contents", 0o644).unwrap();
+    dest.add_file(path, b"corrupt content", 0o644).unwrap();
+    let (src_context, dest_context) =
+        (ProtocolContext::Memory(src), ProtocolContext::Memory(dest.clone()));
+    let (send_prog, _rec_prog) = channel::unbounded();
+    let (send_log, rec_log) = channel::unbounded();
+    let contexts = (&src_context, &dest_context);
+    let sends = (&send_prog, &send_log);
+    // synced again like the run, through a temporary file
+    let (id_map, options) = (IdMap::new(), SyncOptions { temp_files: true, ..Default::default() });
+    assert!(verify_file((path, path), contexts, (&id_map, options), sends).is_ok());
+    assert_eq!(dest.data(path), Some(MemoryData::File(b"source contents".to_vec())));
+    assert_eq!(dest.data(&temp_path(path)), None);
+    // the mismatch was reported before syncing again
+    match rec_log.try_recv() {
+        Ok(LogMessage::Error(ForkliftError::ChecksumError(_))) => (),
+        other => panic!("expected a checksum error, got {:?}", other),
+    }
+    assert!(verify_file((path, path), contexts, (&id_map, options), sends).is_ok());
+    assert!(rec_log.try_recv().is_err());
+    // a file that can not be read back fails on its first attempt
+    match verify_file((path, Path::new("/gone")), contexts, (&id_map, options), sends) {
+        Err((_, attempts)) => assert_eq!(attempts, 1),
+        Ok(()) => panic!("verified a missing file"),
+    }
+}
+
+#[test]
+fn test_verify_synced_returns_channel_errors() {
+    use crate::memory_filesystem::MemoryContext;
+    let (src, dest) = (MemoryContext::new(1), MemoryContext::new(2));
+    let path = Path::new("/file");
+    src.add_file(path, b"contents", 0o644).unwrap();
+    dest.add_file(path, b"contents", 0o644).unwrap();
+    let contexts = [(ProtocolContext::Memory(src), ProtocolContext::Memory(dest))];
+    let (send_prog, rec_prog) = channel::unbounded();
+    let (send_log, _rec_log) = channel::unbounded();
+    let (id_map, synced) = (IdMap::new(), vec![(path.to_path_buf(), path.to_path_buf())]);
+    let settings = (&id_map, SyncOptions::default(), Path::new("/"));
+    verify_synced(synced.clone(), &contexts, settings, &send_prog, &send_log).unwrap();
+    assert_eq!(rec_prog.try_iter().count(), 1);
+    // nobody is listening for progress anymore, which is an error rather than a panic
+    drop(rec_prog);
+    match verify_synced(synced, &contexts, settings, &send_prog, &send_log) {
+        Err(ForkliftError::CrossbeamChannelError(_)) => (),
+        other => panic!("expected a channel error, got {:?}", other),
+    }
+}
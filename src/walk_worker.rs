@@ -1,18 +1,28 @@
 use crate::error::*;
+use crate::failures::Operation;
 use crate::filesystem::*;
 use crate::filesystem_entry::Entry;
 use crate::filesystem_ops::*;
+use crate::filter::Filter;
+use crate::plan::{Action, Plan};
 use crate::progress_message::ProgressMessage;
 use crate::socket_node::*;
 
+use chrono::Utc;
 use crossbeam::channel::Sender;
 use lazy_static::*;
 use log::*;
 use rayon::*;
 use rendezvous_hash::{DefaultNodeHasher, RendezvousNodes};
+use std::cmp::Reverse;
+use std::mem;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 
+/// directory in the destination root that DeletePolicy::Quarantine moves entries into
+pub const QUARANTINE_DIR: &str = ".forklift-quarantine";
+
 lazy_static! {
     pub static ref THIS: PathBuf = Path::new(".").to_path_buf();
     pub static ref PARENT: PathBuf = Path::new("..").to_path_buf();
@@ -34,18 +44,30 @@ pub struct WalkWorker {
     entry_outputs: Vec<Sender<Option<Entry>>>,
     /// channel to send progress information
     progress_output: Sender<ProgressMessage>,
+    /// source directories walked, whose times are set once syncing is done
+    directories: Mutex<Vec<PathBuf>>,
+    /// destination entries not in the source, removed once syncing is done
+    pending: Mutex<Vec<PathBuf>>,
+    /// whether walking a directory failed during the run
+    walk_failed: AtomicBool,
+    /// settings of the sync
+    options: SyncOptions,
+    /// removals planned by a dry run
+    plan: Plan,
+    /// include/exclude rules of the entries walked
+    filter: Arc<Filter>,
 }
 
 impl WalkWorker {
     /// create a new WalkWorker
     pub fn new(
-        source: &Path,
-        destination: &Path,
+        (source, destination): (&Path, &Path),
         contexts: Vec<(ProtocolContext, ProtocolContext)>,
         node: SocketNode,
         nodes: Arc<Mutex<RendezvousNodes<SocketNode, DefaultNodeHasher>>>,
         entry_outputs: Vec<Sender<Option<Entry>>>,
         progress_output: Sender<ProgressMessage>,
+        (options, plan, filter): (SyncOptions, Plan, Arc<Filter>),
     ) -> WalkWorker {
         WalkWorker {
             entry_outputs,
@@ -55,6 +77,12 @@ impl WalkWorker {
             destination: destination.to_path_buf(),
             nodes,
             node,
+            directories: Mutex::new(vec![]),
+            pending: Mutex::new(vec![]),
+            walk_failed: AtomicBool::new(false),
+            options,
+            plan,
+            filter,
         }
     }
 
@@ -75,6 +103,8 @@ impl WalkWorker {
     // Find the sender with the smallest length of channel
     // Send the path over to that to be sync'd
     // Assuming they are all unbounded
+    // Hard linked files always go to the same sender, so that all of their
+    // names are synced in order by the same thread
     pub fn do_work(&self, entry: Option<Entry>) -> ForkliftResult<()> {
         let sender = match self.entry_outputs.get(0) {
             Some(s) => s,
@@ -93,6 +123,11 @@ impl WalkWorker {
                 index = i;
             }
         }
+        if let Some(Some((dev, ino))) =
+            entry.as_ref().and_then(|e| e.metadata()).map(|stat| hard_link_key(&stat))
+        {
+            index = ((dev ^ ino) % self.entry_outputs.len() as u64) as usize;
+        }
         let sender = match self.entry_outputs.get(index) {
             Some(s) => s,
             None => {
@@ -122,24 +157,34 @@ impl WalkWorker {
                 }
             };
             let mut check_paths: Vec<PathBuf> = vec![];
+            self.add_directory(path)?;
             let check_path = self.get_check_path(&path)?;
             let check = exist(&check_path, dest_context);
-            let dir = src_context.opendir(&path)?;
+            let dir = match self.options.retry.run(path, || src_context.opendir(path)) {
+                Ok(dir) => dir,
+                Err((e, attempts)) => {
+                    return self.walk_failure((path, Operation::OpenSourceDir), e, attempts);
+                }
+            };
             for entrytype in dir {
                 let entry = match entrytype {
                     Ok(f) => f,
-                    Err(e) => {
-                        return Err(e);
-                    }
+                    // the rest of the directory is unknown, so nothing is removed from it
+                    Err(e) => return self.walk_failure((path, Operation::OpenSourceDir), e, 1),
                 };
                 let file_path = entry.path();
                 if file_path != THIS.as_path() && file_path != PARENT.as_path() {
                     let newpath = path.join(&file_path);
+                    let directory = entry.filetype() == GenericFileType::Directory;
+                    if self.is_excluded(&newpath, &self.source, directory)? {
+                        continue;
+                    }
                     self.send_file(&newpath, src_context)?;
                     if let Some(true) = is_dir(&newpath, &entry) {
                         spawner.spawn(|_| {
                             let newpath = newpath;
                             if let Err(e) = self.t_walk(&newpath, &pool) {
+                                self.walk_failed.store(true, Ordering::SeqCst);
                                 let mess = ProgressMessage::SendError(ForkliftError::FSError(
                                     format!("Error {:?}, Unable to recursively call", e),
                                 ));
@@ -155,7 +200,11 @@ impl WalkWorker {
             }
             // check through dest files
             let check_path = self.get_check_path(&path)?;
-            self.check_and_remove((check, &mut check_paths), (&check_path, dest_context))?;
+            self.check_and_remove(
+                (check, &mut check_paths),
+                (path, src_context),
+                (&check_path, dest_context),
+            )?;
             Ok(())
         })?;
         Ok(())
@@ -178,19 +227,30 @@ impl WalkWorker {
         }
         Ok(false)
     }
-    /// linear walking loop
+    /// linear walking loop.  Returns None if reading the directory failed part way,
+    /// after recording the failure
     fn walk_loop(
         &self,
         (path, stack): (&Path, &mut Vec<PathBuf>),
         (check, check_path, check_paths): (bool, &Path, &mut Vec<PathBuf>),
         (dir, src_context): (DirectoryType, &ProtocolContext),
-    ) -> ForkliftResult<u64> {
+    ) -> ForkliftResult<Option<u64>> {
         let mut total_files = 0;
         for entrytype in dir {
-            let entry = entrytype?;
+            let entry = match entrytype {
+                Ok(entry) => entry,
+                Err(e) => {
+                    self.walk_failure((path, Operation::OpenSourceDir), e, 1)?;
+                    return Ok(None);
+                }
+            };
             let file_path = entry.path();
             if file_path != THIS.as_path() && file_path != PARENT.as_path() {
                 let newpath = path.join(&file_path);
+                let directory = entry.filetype() == GenericFileType::Directory;
+                if self.is_excluded(&newpath, &self.source, directory)? {
+                    continue;
+                }
                 //file exists?
                 if self.send_file(&newpath, src_context)? {
                     total_files += 1;
@@ -204,11 +264,16 @@ impl WalkWorker {
                 }
             }
         }
-        Ok(total_files)
+        Ok(Some(total_files))
     }
 
     /// Linear filesystem walker
     pub fn s_walk(&self) -> ForkliftResult<()> {
+        self.walk_from(vec![self.source.clone()])
+    }
+
+    /// walk the source directories in stack, and all the directories under them
+    fn walk_from(&self, mut stack: Vec<PathBuf>) -> ForkliftResult<()> {
         let (src_context, dest_context) = match self.contexts.get(0) {
             Some((src, dest)) => (src, dest),
             None => {
@@ -216,22 +281,36 @@ impl WalkWorker {
             }
         };
         let mut num_files = 0;
-        let mut stack: Vec<PathBuf> = vec![self.source.clone()];
         loop {
             let check: bool;
             let mut check_paths: Vec<PathBuf> = vec![];
             match stack.pop() {
                 Some(path) => {
+                    self.add_directory(&path)?;
                     let check_path = self.get_check_path(&path)?;
                     check = exist(&check_path, dest_context);
-                    let dir = src_context.opendir(&path)?;
-                    num_files += self.walk_loop(
+                    let dir = match self.options.retry.run(&path, || src_context.opendir(&path)) {
+                        Ok(dir) => dir,
+                        Err((e, attempts)) => {
+                            self.walk_failure((&path, Operation::OpenSourceDir), e, attempts)?;
+                            continue;
+                        }
+                    };
+                    num_files += match self.walk_loop(
                         (&path, &mut stack),
                         (check, &check_path, &mut check_paths),
                         (dir, src_context),
-                    )?;
+                    )? {
+                        Some(num) => num,
+                        // the rest of the directory is unknown, so nothing is removed from it
+                        None => continue,
+                    };
                     // check through dest files
-                    self.check_and_remove((check, &mut check_paths), (&check_path, dest_context))?;
+                    self.check_and_remove(
+                        (check, &mut check_paths),
+                        (&path, src_context),
+                        (&check_path, dest_context),
+                    )?;
                 }
                 None => {
                     debug!("Total number of files sent {:?}", num_files);
@@ -241,6 +320,127 @@ impl WalkWorker {
         }
         Ok(())
     }
+    /// send only the entries at paths, relative to the source, without walking the tree
+    /// or removing anything from the destination.  A listed directory is synced itself,
+    /// but not the entries in it.  The directories in dirs, also relative to the source,
+    /// are walked again as a whole
+    pub fn list_walk(&self, (paths, dirs): (&[PathBuf], &[PathBuf])) -> ForkliftResult<()> {
+        let src_context = match self.contexts.get(0) {
+            Some((src, _)) => src,
+            None => {
+                return Err(ForkliftError::FSError("Unable to retrieve contexts".to_string()));
+            }
+        };
+        let mut num_files = 0;
+        for path in paths {
+            let path = self.source.join(path);
+            let is_dir = match Entry::new(&path, src_context).is_dir() {
+                Some(is_dir) => is_dir,
+                None => {
+                    // every node reads the list, so only the node it hashes to reports it
+                    if self.node_for(&path.to_string_lossy())? == self.node {
+                        let e = ForkliftError::FSError(format!("{:?} is not in the source", path));
+                        self.walk_failure((&path, Operation::Sync), e, 1)?;
+                    }
+                    continue;
+                }
+            };
+            if self.is_excluded(&path, &self.source, is_dir)? {
+                continue;
+            }
+            if is_dir {
+                self.add_directory(&path)?;
+            }
+            if self.send_file(&path, src_context)? {
+                num_files += 1;
+            }
+        }
+        debug!("Total number of listed files sent {:?}", num_files);
+        self.walk_from(dirs.iter().map(|dir| self.source.join(dir)).collect())
+    }
+
+    /// record an entry that failed while walking, such as a directory that could not be
+    /// opened, skipping everything in it.  Nothing is removed by DeletePolicy::After in a
+    /// run that skipped a directory
+    fn walk_failure(
+        &self,
+        (path, operation): (&Path, Operation),
+        error: ForkliftError,
+        attempts: u32,
+    ) -> ForkliftResult<()> {
+        self.walk_failed.store(true, Ordering::SeqCst);
+        let path = match operation {
+            Operation::OpenDestinationDir => get_rel_path(path, &self.destination)?,
+            _ => get_rel_path(path, &self.source)?,
+        };
+        let failed = ProgressMessage::Failed { path, operation, error, attempts };
+        if let Err(e) = self.progress_output.send(failed) {
+            return Err(ForkliftError::CrossbeamChannelError(format!(
+                "Error: {:?}, unable to send progress",
+                e
+            )));
+        };
+        Ok(())
+    }
+
+    /// record a walked source directory (a dry run sets no times, so it records none)
+    fn add_directory(&self, path: &Path) -> ForkliftResult<()> {
+        if self.options.dry_run {
+            return Ok(());
+        }
+        match self.directories.lock() {
+            Ok(mut dirs) => {
+                dirs.push(path.to_path_buf());
+                Ok(())
+            }
+            Err(_) => Err(ForkliftError::FSError("failed to lock".to_string())),
+        }
+    }
+
+    /// set the times of the destination directories walked to those of the source.
+    /// This must run after all entries are synced, since adding or removing
+    /// entries changes the modification time of a directory.
+    ///
+    /// @note every node walks every directory, so whichever node finishes last
+    ///       sets the times after all nodes have synced their entries
+    pub fn copy_dir_times(&self) -> ForkliftResult<()> {
+        let mut dirs = match self.directories.lock() {
+            Ok(mut dirs) => mem::take(&mut *dirs),
+            Err(_) => {
+                return Err(ForkliftError::FSError("failed to lock".to_string()));
+            }
+        };
+        let (src_context, dest_context) = match self.contexts.get(0) {
+            Some((src, dest)) => (src, dest),
+            None => {
+                return Err(ForkliftError::FSError("Unable to retrieve contexts".to_string()));
+            }
+        };
+        // deepest first, so setting a child's times does not change its parent's after
+        dirs.sort_by_key(|dir| Reverse(dir.components().count()));
+        for dir in dirs {
+            let dest_path = self.get_check_path(&dir)?;
+            if !exist(&dest_path, dest_context) {
+                continue;
+            }
+            let src_entry = Entry::new(&dir, src_context);
+            if let Err(e) = copy_times(&src_entry, &dest_path, dest_context) {
+                self.walk_failure((&dir, Operation::Sync), e, 1)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// true if the filter excludes the entry at path, under root (source or destination)
+    fn is_excluded(&self, path: &Path, root: &Path, is_dir: bool) -> ForkliftResult<bool> {
+        let rel_path = get_rel_path(path, root)?;
+        if self.filter.is_excluded(&rel_path, is_dir) {
+            trace!("skip excluded: {:?}", path);
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
     /// get the destination path to check against
     fn get_check_path(&self, source_path: &Path) -> ForkliftResult<PathBuf> {
         let rel_path = get_rel_path(&source_path, &self.source)?;
@@ -251,26 +451,56 @@ impl WalkWorker {
     fn check_and_remove(
         &self,
         (check, check_paths): (bool, &mut Vec<PathBuf>),
+        (path, src_context): (&Path, &ProtocolContext),
         (check_path, dest_context): (&Path, &ProtocolContext),
     ) -> ForkliftResult<()> {
         // check through dest files
         if check {
-            let dir = dest_context.opendir(&check_path)?;
+            let opened = self.options.retry.run(check_path, || dest_context.opendir(check_path));
+            let dir = match opened {
+                Ok(dir) => dir,
+                Err((e, attempts)) => {
+                    let check_path = (check_path, Operation::OpenDestinationDir);
+                    return self.walk_failure(check_path, e, attempts);
+                }
+            };
             for entrytype in dir {
                 let entry = entrytype?;
                 let file_path = entry.path();
                 if file_path != THIS.as_path() && file_path != PARENT.as_path() {
                     let newpath = check_path.join(file_path);
                     if !contains_and_remove(check_paths, &newpath) {
-                        match entry.filetype() {
-                            GenericFileType::Directory => {
-                                trace!("call remove_dir: {:?}", &newpath);
-                                remove_dir(&newpath, dest_context)?;
-                            }
-                            _ => {
-                                debug!("remove: {:?}", &newpath);
-                                dest_context.unlink(&newpath)?;
-                            }
+                        if self.keep_temp(&newpath, (path, src_context), dest_context) {
+                            trace!("skip temporary file: {:?}", &newpath);
+                            continue;
+                        }
+                        if newpath == self.destination.join(QUARANTINE_DIR) {
+                            continue;
+                        }
+                        // excluded entries are not synced, so they are never in check_paths
+                        let is_dir = entry.filetype() == GenericFileType::Directory;
+                        if self.is_protected(&newpath, is_dir)? {
+                            continue;
+                        }
+                        // every node walks every directory, so only one of them handles
+                        // each extraneous entry
+                        if self.node_for(&newpath.to_string_lossy())? != self.node {
+                            continue;
+                        }
+                        // temporary files left by earlier runs are removed whatever the policy
+                        let policy = match temp_target(&newpath) {
+                            Some(_) => DeletePolicy::During,
+                            None => self.options.delete,
+                        };
+                        if self.options.dry_run {
+                            self.plan_remove(&newpath, (is_dir, policy), dest_context)?;
+                            continue;
+                        }
+                        match policy {
+                            DeletePolicy::Never => trace!("keep {:?}, not in source", &newpath),
+                            DeletePolicy::During => self.remove(&newpath, is_dir, dest_context)?,
+                            DeletePolicy::After => self.add_pending(&newpath)?,
+                            DeletePolicy::Quarantine => self.quarantine(&newpath, dest_context)?,
                         }
                     }
                 }
@@ -279,40 +509,288 @@ impl WalkWorker {
         Ok(())
     }
 
+    /// add what the delete policy would do to a destination entry that is not in the
+    /// source to the plan.  Removing a directory also removes everything in it
+    fn plan_remove(
+        &self,
+        path: &Path,
+        (is_dir, policy): (bool, DeletePolicy),
+        dest_context: &ProtocolContext,
+    ) -> ForkliftResult<()> {
+        let size = Entry::new(path, dest_context).metadata().map_or(0, |s| s.size());
+        match (policy, is_dir) {
+            (DeletePolicy::Never, _) => Ok(()),
+            (DeletePolicy::Quarantine, true) => self.plan_quarantine_dir(path, dest_context),
+            (DeletePolicy::Quarantine, false) => self.plan.add(path, Action::Quarantined, size),
+            (_, true) => self.plan_remove_dir(path, dest_context).map(|_| ()),
+            (_, false) => self.plan.add(path, Action::Removed, size),
+        }
+    }
+
+    /// add the removal of a directory in destination that is not in source, and of
+    /// everything in it, to the plan of a dry run.  Protected entries are kept, along with
+    /// the directories holding them; returns true if the directory would be removed
+    fn plan_remove_dir(&self, path: &Path, dest_context: &ProtocolContext) -> ForkliftResult<bool> {
+        let mut emptied = true;
+        for (newpath, is_dir) in dir_entries(path, dest_context)? {
+            if self.is_protected(&newpath, is_dir)? {
+                emptied = false;
+            } else if is_dir {
+                emptied &= self.plan_remove_dir(&newpath, dest_context)?;
+            } else {
+                let stat = Entry::new(&newpath, dest_context).metadata();
+                self.plan.add(&newpath, Action::Removed, stat.map_or(0, |s| s.size()))?;
+            }
+        }
+        if emptied {
+            self.plan.add(path, Action::Removed, 0)?;
+        }
+        Ok(emptied)
+    }
+
+    /// add the quarantine of a directory in destination that is not in source to the plan
+    /// of a dry run.  A directory holding protected entries stays, and only the rest of
+    /// its entries would be quarantined
+    fn plan_quarantine_dir(
+        &self,
+        path: &Path,
+        dest_context: &ProtocolContext,
+    ) -> ForkliftResult<()> {
+        if !self.holds_protected(path, dest_context)? {
+            return self.plan.add(path, Action::Quarantined, 0);
+        }
+        for (newpath, is_dir) in dir_entries(path, dest_context)? {
+            if self.is_protected(&newpath, is_dir)? {
+                continue;
+            }
+            if is_dir {
+                self.plan_quarantine_dir(&newpath, dest_context)?;
+            } else {
+                let stat = Entry::new(&newpath, dest_context).metadata();
+                self.plan.add(&newpath, Action::Quarantined, stat.map_or(0, |s| s.size()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// true if the destination entry at path is excluded by the filter, and so is kept
+    /// whatever the delete policy, unless delete_excluded is set
+    fn is_protected(&self, path: &Path, is_dir: bool) -> ForkliftResult<bool> {
+        Ok(!self.options.delete_excluded && self.is_excluded(path, &self.destination, is_dir)?)
+    }
+
+    /// true if anything under the destination directory at path is protected
+    fn holds_protected(&self, path: &Path, dest_context: &ProtocolContext) -> ForkliftResult<bool> {
+        if self.options.delete_excluded {
+            return Ok(false);
+        }
+        for (newpath, is_dir) in dir_entries(path, dest_context)? {
+            if self.is_protected(&newpath, is_dir)?
+                || (is_dir && self.holds_protected(&newpath, dest_context)?)
+            {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// remove a destination entry that is not in the source, and everything in it
+    fn remove(
+        &self,
+        path: &Path,
+        is_dir: bool,
+        dest_context: &ProtocolContext,
+    ) -> ForkliftResult<()> {
+        if is_dir {
+            trace!("call remove_dir: {:?}", path);
+            self.remove_dir(path, dest_context)?;
+            return Ok(());
+        }
+        debug!("remove: {:?}", path);
+        dest_context.unlink(path)?;
+        self.report(path, SyncOutcome::Removed)
+    }
+
+    /// recursively remove a directory in destination that is not in source.  Protected
+    /// entries are kept, along with the directories holding them; returns true if the
+    /// directory was removed
+    fn remove_dir(&self, path: &Path, dest_context: &ProtocolContext) -> ForkliftResult<bool> {
+        let mut emptied = true;
+        for (newpath, is_dir) in dir_entries(path, dest_context)? {
+            if self.is_protected(&newpath, is_dir)? {
+                trace!("keep protected: {:?}", &newpath);
+                emptied = false;
+            } else if is_dir {
+                emptied &= self.remove_dir(&newpath, dest_context)?;
+            } else {
+                debug!("remove: {:?}", &newpath);
+                dest_context.unlink(&newpath)?;
+                self.report(&newpath, SyncOutcome::Removed)?;
+            }
+        }
+        if emptied {
+            dest_context.rmdir(path)?;
+            self.report(path, SyncOutcome::Removed)?;
+        }
+        Ok(emptied)
+    }
+
+    /// remember a destination entry that is not in the source, to remove it once the run
+    /// is done
+    fn add_pending(&self, path: &Path) -> ForkliftResult<()> {
+        match self.pending.lock() {
+            Ok(mut pending) => {
+                pending.push(path.to_path_buf());
+                Ok(())
+            }
+            Err(_) => Err(ForkliftError::FSError("failed to lock".to_string())),
+        }
+    }
+
+    /// remove the destination entries that were not in the source, found while walking
+    /// with DeletePolicy::After.  If the run failed they are kept, and found again by the
+    /// next run
+    pub fn remove_pending(&self, sync_failed: bool) -> ForkliftResult<()> {
+        let pending = match self.pending.lock() {
+            Ok(mut pending) => mem::take(&mut *pending),
+            Err(_) => {
+                return Err(ForkliftError::FSError("failed to lock".to_string()));
+            }
+        };
+        if sync_failed || self.walk_failed.swap(false, Ordering::SeqCst) {
+            if !pending.is_empty() {
+                warn!("Sync failed, keeping {} entries not in the source", pending.len());
+            }
+            return Ok(());
+        }
+        let dest_context = match self.contexts.get(0) {
+            Some((_, dest)) => dest,
+            None => {
+                return Err(ForkliftError::FSError("Unable to retrieve contexts".to_string()));
+            }
+        };
+        for path in pending {
+            match Entry::new(&path, dest_context).is_dir() {
+                Some(is_dir) => self.remove(&path, is_dir, dest_context)?,
+                None => trace!("{:?} was already removed", path),
+            }
+        }
+        Ok(())
+    }
+
+    /// move a destination entry that is not in the source into the quarantine directory
+    /// of the day (in UTC), at the same path relative to the destination root.  An entry
+    /// with the same path quarantined earlier that day is kept, the new one gets a suffix
+    fn quarantine(&self, path: &Path, dest_context: &ProtocolContext) -> ForkliftResult<()> {
+        // a directory holding protected entries stays, and the rest of its entries are moved
+        if Entry::new(path, dest_context).is_dir() == Some(true)
+            && self.holds_protected(path, dest_context)?
+        {
+            for (newpath, is_dir) in dir_entries(path, dest_context)? {
+                if !self.is_protected(&newpath, is_dir)? {
+                    self.quarantine(&newpath, dest_context)?;
+                }
+            }
+            return Ok(());
+        }
+        let rel_path = get_rel_path(path, &self.destination)?;
+        let date = Utc::now().format("%Y-%m-%d").to_string();
+        let target = self.destination.join(QUARANTINE_DIR).join(date).join(rel_path);
+        if let Some(parent) = target.parent() {
+            create_dirs(parent, &self.destination, dest_context)?;
+        }
+        let mut quarantined = target.clone();
+        let mut count = 0;
+        while exist(&quarantined, dest_context) {
+            count += 1;
+            let mut name = target.clone().into_os_string();
+            name.push(format!(".{}", count));
+            quarantined = PathBuf::from(name);
+        }
+        debug!("quarantine: {:?} to {:?}", path, &quarantined);
+        dest_context.rename(path, &quarantined)?;
+        self.report(path, SyncOutcome::Quarantined)
+    }
+
+    /// log what was done to a destination entry that is not in the source, and count it
+    fn report(&self, path: &Path, outcome: SyncOutcome) -> ForkliftResult<()> {
+        info!("{:?}: {:?}", outcome, path);
+        if let Err(e) = self.progress_output.send(ProgressMessage::DoneSyncing(outcome)) {
+            return Err(ForkliftError::CrossbeamChannelError(format!(
+                "Error: {:?}, unable to send progress",
+                e
+            )));
+        };
+        Ok(())
+    }
+
+    /// true if dest_path is a temporary file (or checkpoint) that should be left alone: it
+    /// may still be written by another thread/node, or, if its file is still in the source,
+    /// the file's sync replaces it or continues the copy into it
+    fn keep_temp(
+        &self,
+        dest_path: &Path,
+        (path, src_context): (&Path, &ProtocolContext),
+        dest_context: &ProtocolContext,
+    ) -> bool {
+        let target = match temp_target(dest_path) {
+            Some(target) => target,
+            None => return false,
+        };
+        let src_path = path.join(target.file_name().unwrap_or_default());
+        (self.options.temp_files && exist(&src_path, src_context))
+            || !is_stale_temp(dest_path, dest_context)
+    }
+
     /// process a file, determining whether to send it to rsync_worker to be synced or skipped
     fn process_file(
         &self,
         entry: &Path,
         src_context: &ProtocolContext,
     ) -> ForkliftResult<Option<Stat>> {
-        let node = match self.nodes.lock() {
-            Ok(e) => {
-                let mut list = e;
-                trace!("{:?}", list.calc_candidates(&entry.to_string_lossy()).collect::<Vec<_>>());
-                match list.calc_candidates(&entry.to_string_lossy()).nth(0) {
-                    Some(p) => *p,
-                    None => {
-                        return Err(ForkliftError::FSError("calc candidates failed".to_string()));
-                    }
-                }
-            }
-            Err(_) => {
-                return Err(ForkliftError::FSError("failed to lock".to_string()));
+        let src_entry = Entry::new(entry, src_context);
+        let metadata = match src_entry.metadata() {
+            Some(stat) => stat,
+            None => {
+                return Ok(None);
             }
         };
-        if node == self.node {
-            let src_entry = Entry::new(entry, src_context);
-            let metadata = match src_entry.metadata() {
-                Some(stat) => stat,
-                None => {
-                    return Ok(None);
+        // all names of a hard linked file are hashed the same, so they go to the same node.
+        // The device id depends on the mount of each node, so only the inode is hashed
+        let key = match hard_link_key(&metadata) {
+            Some((_, ino)) => format!("inode:{}", ino),
+            None => entry.to_string_lossy().into_owned(),
+        };
+        if self.node_for(&key)? == self.node {
+            if !self.filter.is_selected(&metadata) {
+                trace!("skip unselected: {:?}", entry);
+                if let Err(e) = self.progress_output.send(ProgressMessage::FilteredOut) {
+                    return Err(ForkliftError::CrossbeamChannelError(format!(
+                        "Error: {:?}, unable to send progress",
+                        e
+                    )));
                 }
-            };
+                return Ok(None);
+            }
             self.do_work(Some(src_entry))?;
             return Ok(Some(metadata));
         }
         Ok(None)
     }
+
+    /// get the node that processes the entry with the key
+    fn node_for(&self, key: &str) -> ForkliftResult<SocketNode> {
+        match self.nodes.lock() {
+            Ok(list) => {
+                trace!("{:?}", list.calc_candidates(&key).collect::<Vec<_>>());
+                match list.calc_candidates(&key).nth(0) {
+                    Some(p) => Ok(*p),
+                    None => Err(ForkliftError::FSError("calc candidates failed".to_string())),
+                }
+            }
+            Err(_) => Err(ForkliftError::FSError("failed to lock".to_string())),
+        }
+    }
 }
 
 /// check if directory entry is a directory
@@ -330,6 +808,13 @@ fn is_dir(path: &Path, entry: &DirEntryType) -> Option<bool> {
             debug!("link: {:?}", path);
             Some(false)
         }
+        GenericFileType::Fifo
+        | GenericFileType::CharDevice
+        | GenericFileType::BlockDevice
+        | GenericFileType::Socket => {
+            debug!("special file: {:?}", path);
+            Some(false)
+        }
         GenericFileType::Other => None,
     }
 }
@@ -345,44 +830,40 @@ fn contains_and_remove(check_paths: &mut Vec<PathBuf>, check_path: &Path) -> boo
     false
 }
 
-/// recursively remove a directory in destination that is not in source
-fn remove_dir(path: &Path, dest_context: &ProtocolContext) -> ForkliftResult<()> {
-    let mut stack: Vec<PathBuf> = vec![(*path).to_path_buf()];
-    let mut remove_stack: Vec<PathBuf> = vec![(*path).to_path_buf()];
-    while let Some(p) = stack.pop() {
-        let dir = dest_context.opendir(&p)?;
-        for entrytype in dir {
-            let entry = match entrytype {
-                Ok(e) => e,
-                Err(e) => {
-                    return Err(e);
-                }
-            };
-            let file_path = entry.path();
-            if file_path != THIS.as_path() && file_path != PARENT.as_path() {
-                let newpath = p.join(&file_path);
-                debug!("remove: {:?}", &newpath);
-                match is_dir(&newpath, &entry) {
-                    Some(true) => {
-                        stack.push(newpath.clone());
-                        remove_stack.push(newpath);
-                    }
-                    Some(false) => {
-                        dest_context.unlink(&newpath)?;
-                    }
-                    None => {}
-                }
+/// the entries of the directory at path (without . and ..), and whether each is a directory
+fn dir_entries(path: &Path, context: &ProtocolContext) -> ForkliftResult<Vec<(PathBuf, bool)>> {
+    let mut entries = vec![];
+    for entrytype in context.opendir(path)? {
+        let entry = entrytype?;
+        let file_path = entry.path();
+        if file_path != THIS.as_path() && file_path != PARENT.as_path() {
+            let newpath = path.join(file_path);
+            if let Some(is_dir) = is_dir(&newpath, &entry) {
+                entries.push((newpath, is_dir));
             }
         }
     }
-    while !remove_stack.is_empty() {
-        let dir = match remove_stack.pop() {
-            Some(e) => e,
-            None => {
-                return Err(ForkliftError::FSError("remove stack should not be empty!".to_string()));
+    Ok(entries)
+}
+
+/// create the directory at path, and its missing parents below root
+fn create_dirs(path: &Path, root: &Path, context: &ProtocolContext) -> ForkliftResult<()> {
+    let mut missing: Vec<&Path> = vec![];
+    let mut dir = path;
+    while dir.starts_with(root) && dir != root && !exist(dir, context) {
+        missing.push(dir);
+        match dir.parent() {
+            Some(parent) => dir = parent,
+            None => break,
+        }
+    }
+    for dir in missing.iter().rev() {
+        // another thread/node may have just created it
+        if let Err(e) = context.mkdir(dir) {
+            if !exist(dir, context) {
+                return Err(e);
             }
-        };
-        dest_context.rmdir(&dir)?;
+        }
     }
     Ok(())
 }